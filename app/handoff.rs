@@ -0,0 +1,97 @@
+//! Reader for the handoff block blethrs writes just before booting the user firmware.
+//!
+//! Copy this file into the user firmware as a module. It only needs `core`, so works in
+//! `no_std` firmware. The firmware's RAM must start after the block, at 0x2000_001C as in
+//! the bootloader's memory.x, so the block isn't zeroed at startup before it is read.
+
+/// Address of the handoff block.
+pub const HANDOFF_ADDRESS: u32 = 0x2000_0008;
+/// Marks a valid handoff block.
+pub const HANDOFF_MAGIC: u32 = 0xB1E7_4A0F;
+/// Number of words in the handoff block.
+pub const HANDOFF_WORDS: usize = 5;
+
+/// Address and value of the bootload flag in a default build of blethrs, used by
+/// `request_bootloader` if there is no handoff block.
+pub const DEFAULT_FLAG_ADDRESS: u32 = 0x2000_0000;
+pub const DEFAULT_FLAG_VALUE: u32 = 0xB001_10AD;
+
+/// What the bootloader told the user firmware when booting it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Handoff {
+    /// Version of the block's layout, which only ever gains words.
+    pub version: u32,
+    /// Address the user firmware was booted from.
+    pub base: u32,
+    /// Address to write `flag_value` to before a reset to request the bootloader.
+    pub flag_address: u32,
+    pub flag_value: u32,
+}
+
+impl Handoff {
+    /// Decode the words of a handoff block, returning None if it isn't valid.
+    pub fn from_words(words: &[u32; HANDOFF_WORDS]) -> Option<Handoff> {
+        if words[0] != HANDOFF_MAGIC || words[1] == 0 {
+            return None;
+        }
+        Some(Handoff { version: words[1], base: words[2], flag_address: words[3],
+                       flag_value: words[4] })
+    }
+}
+
+/// Read the handoff block.
+///
+/// Returns None if there isn't one, for example when the firmware was started by a
+/// debugger or an older bootloader.
+pub fn read_handoff() -> Option<Handoff> {
+    let mut words = [0u32; HANDOFF_WORDS];
+    for (idx, word) in words.iter_mut().enumerate() {
+        *word = unsafe { core::ptr::read_volatile((HANDOFF_ADDRESS as *const u32).add(idx)) };
+    }
+    Handoff::from_words(&words)
+}
+
+/// Reset into the bootloader, using the bootload flag from the handoff block, or the
+/// default flag if there is none.
+pub fn request_bootloader() -> ! {
+    let (address, value) = match read_handoff() {
+        Some(handoff) => (handoff.flag_address, handoff.flag_value),
+        None => (DEFAULT_FLAG_ADDRESS, DEFAULT_FLAG_VALUE),
+    };
+    unsafe {
+        core::ptr::write_volatile(address as *mut u32, value);
+        // Request a system reset through the SCB's AIRCR
+        core::ptr::write_volatile(0xE000_ED0C as *mut u32, (0x5FA << 16) | (1 << 2));
+    }
+    loop {
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Built as part of the bootloader's host tests, to check this file matches it.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::config;
+
+    #[test]
+    fn matches_bootloader() {
+        assert_eq!(HANDOFF_ADDRESS, config::HANDOFF_ADDRESS);
+        assert_eq!(HANDOFF_MAGIC, config::HANDOFF_MAGIC);
+        assert_eq!(HANDOFF_WORDS, config::HANDOFF_WORDS as usize);
+
+        // As written by bootload::write_handoff
+        let mut words = [config::HANDOFF_MAGIC, config::HANDOFF_VERSION, config::FLASH_USER,
+                         config::BOOTLOAD_FLAG_ADDRESS, config::BOOTLOAD_FLAG_VALUE];
+        assert_eq!(Handoff::from_words(&words), Some(Handoff {
+            version: config::HANDOFF_VERSION,
+            base: config::FLASH_USER,
+            flag_address: config::BOOTLOAD_FLAG_ADDRESS,
+            flag_value: config::BOOTLOAD_FLAG_VALUE,
+        }));
+
+        words[0] ^= 1;
+        assert_eq!(Handoff::from_words(&words), None);
+        assert_eq!(Handoff::from_words(&[HANDOFF_MAGIC, 0, 0, 0, 0]), None);
+    }
+}
@@ -1,26 +1,58 @@
 #!/usr/bin/env python3
 
+import csv
+import sys
+import json
 import time
 import struct
 import socket
+import logging
+import hmac
+import hashlib
 import argparse
+import subprocess
 import crcmod
+from concurrent.futures import ThreadPoolExecutor
 
 try:
     from tqdm import tqdm
+    HAVE_TQDM = True
 except ImportError:
     print("Notice: tqdm not installed, install for progress bars.")
+    HAVE_TQDM = False
 
     def tqdm(x, *args, **kwargs):
         return x
 
 
+logger = logging.getLogger("blethrs")
+
+
 commands = {
     "info": 0,
     "read": 1,
     "erase": 2,
     "write": 3,
     "boot": 4,
+    "layout": 5,
+    "trial_boot": 6,
+    "read_mem": 7,
+    "write_mem": 8,
+    "self_test": 9,
+    "status": 10,
+    "write_compressed": 11,
+    "set_mac": 12,
+    "get_log": 13,
+    "set_config": 14,
+    "diagnostics": 15,
+    "mem_info": 16,
+    "first_word": 17,
+    "echo": 18,
+    "get_config": 19,
+    "reboot": 20,
+    "unlock": 21,
+    "auth_nonce": 22,
+    "set_key": 23,
 }
 
 
@@ -35,30 +67,281 @@ errors = {
     7: "Flash Error",
     8: "Network Error",
     9: "Internal Error",
+    10: "Unknown Command",
+    11: "Decompression Error",
+    12: "Write Protected",
+    13: "Flashing Locked",
+    14: "Unauthorized",
 }
 
 
+WRITE_PROTECT_HELP = (
+    "Flash sectors are write protected by the option bytes. Clear the nWRP "
+    "bits with a debug probe, for example using STM32CubeProgrammer, "
+    "then retry.")
+
+# Sector index sent in error details when no sector applies.
+SECTOR_NONE = 0xFFFFFFFF
+
+
 class BootloaderError(Exception):
-    def __init__(self, errno):
+    def __init__(self, errno, address=None, sector=None, sr=None, nwrp=None):
         self.errno = errno
+        self.address = address
+        self.sector = sector
+        self.sr = sr
+        self.nwrp = nwrp
+
+    def protected_sectors(self):
+        """Sectors write protected by the nWRP option bits, if reported."""
+        if self.nwrp is None:
+            return None
+        return [idx for idx in range(12) if not self.nwrp & (1 << idx)]
 
     def __str__(self):
         if self.errno in errors:
-            return "{}".format(errors[self.errno])
+            msg = "{}".format(errors[self.errno])
         else:
-            return "Unknown error {}".format(self.errno)
+            msg = "Unknown error {}".format(self.errno)
+        if self.address is not None:
+            msg += " at address {:08X}".format(self.address)
+        if self.sector is not None:
+            msg += " in sector {}".format(self.sector)
+        if self.sr:
+            msg += " (FLASH_SR {:08X})".format(self.sr)
+        if self.errno == 12 and self.protected_sectors():
+            msg += ", protected sectors: {}".format(
+                ", ".join(str(s) for s in self.protected_sectors()))
+        if self.errno == 10:
+            msg += " (device firmware does not support this command," \
+                   " check the bootloader version)"
+        return msg
+
+
+CONFIG_MAGIC = 0x67797870
+
+# Configuration sector address used when the device doesn't report its layout.
+DEFAULT_CONFIG_ADDRESS = 0x0800C000
+
+
+def config_crc(data):
+    """
+    Compute the checksum of configuration data, or any other whole words,
+    as the device's CRC peripheral does, which consumes each little-endian
+    word MSB first.
+    """
+    crc32 = crcmod.predefined.mkCrcFun('crc-32-mpeg')
+    n_words = len(data) // 4
+    u32 = struct.unpack(">{}I".format(n_words), data)
+    return crc32(struct.pack("<{}I".format(n_words), *u32))
+
+
+# Bits of ConfigFields.flags.
+CONFIG_FLAG_LOCKED = 1 << 0
+
+
+class ConfigFields:
+    """
+    Device configuration, as stored in the configuration sector:
+    magic, MAC address, IP address, gateway, prefix length,
+    a byte of CONFIG_FLAG_ bits, and a CRC32 of the preceeding bytes.
+    """
+    def __init__(self, mac, ip, gateway, prefix, flags=0):
+        self.mac = bytes(mac)
+        self.ip = bytes(ip)
+        self.gateway = bytes(gateway)
+        self.prefix = prefix
+        self.flags = flags
+
+    @classmethod
+    def parse(cls, mac, ip, gateway, prefix):
+        """Create from the textual forms used on the command line."""
+        return cls([int(x, 16) for x in mac.split(":")],
+                   [int(x) for x in ip.split(".")],
+                   [int(x) for x in gateway.split(".")],
+                   prefix)
+
+    def to_bytes(self):
+        data = struct.pack("<I6s4s4sBB", CONFIG_MAGIC, self.mac, self.ip,
+                           self.gateway, self.prefix, self.flags)
+        return data + struct.pack("<I", config_crc(data))
+
+    @classmethod
+    def from_bytes(cls, data):
+        """Decode configuration bytes, raising ValueError if invalid."""
+        if len(data) < 24:
+            raise ValueError("configuration too short")
+        magic, mac, ip, gateway, prefix, flags, crc = struct.unpack(
+            "<I6s4s4sBBI", data[:24])
+        if magic != CONFIG_MAGIC:
+            raise ValueError("bad magic {:08X}".format(magic))
+        if crc != config_crc(data[:20]):
+            raise ValueError("bad checksum {:08X}".format(crc))
+        return cls(mac, ip, gateway, prefix, flags)
+
+    def __str__(self):
+        return "MAC {} IP {}/{} gateway {}{}".format(
+            ":".join("{:02X}".format(x) for x in self.mac),
+            ".".join(str(x) for x in self.ip), self.prefix,
+            ".".join(str(x) for x in self.gateway),
+            ", flashing locked" if self.flags & CONFIG_FLAG_LOCKED else "")
 
 
 class MismatchError(Exception):
-    def __init__(self, addr, tx, rx):
+    """
+    Readback mismatch at addr. If given, context is a tuple of the start
+    address and the expected and actual bytes around the mismatch.
+    """
+    def __init__(self, addr, tx, rx, context=None):
         self.addr = addr
         self.tx = tx
         self.rx = rx
+        self.context = context
 
     def __str__(self):
         return "Mismatch at address {:08X}: {:02X}!={:02X}".format(
             self.addr, self.tx, self.rx)
 
+    def hexdump(self):
+        """Render the context as a hexdump, or return None if there is none."""
+        if self.context is None:
+            return None
+        return hexdump_diff(*self.context)
+
+
+def hexdump_diff(base, expected, actual):
+    """
+    Render expected and actual bytes side by side, 16 per row, with ^^
+    under each actual byte which differs from the expected byte.
+    """
+    lines = ["{:8}  {:47}  {}".format("", "expected", "actual")]
+    for offset in range(0, len(expected), 16):
+        exp = expected[offset:offset+16]
+        act = actual[offset:offset+16]
+        lines.append("{:08X}  {:47}  {}".format(
+            base + offset, " ".join("{:02X}".format(x) for x in exp),
+            " ".join("{:02X}".format(x) for x in act)))
+        if exp != act:
+            marks = " ".join("^^" if x != y else "  "
+                             for x, y in zip(exp, act))
+            lines.append("{:8}  {:47}  {}".format("", "", marks).rstrip())
+    return "\n".join(lines)
+
+
+class ConfigMismatchError(Exception):
+    def __init__(self, field, expected, actual):
+        self.field = field
+        self.expected = expected
+        self.actual = actual
+
+    def __str__(self):
+        def fmt(value):
+            if isinstance(value, bytes):
+                return ":".join("{:02X}".format(x) for x in value)
+            return "{:X}".format(value)
+        return "configuration {} is {}, expected {}".format(
+            self.field, fmt(self.actual), fmt(self.expected))
+
+
+class VersionMismatch(Exception):
+    def __init__(self, expected, version, git_version):
+        self.expected = expected
+        self.version = version
+        self.git_version = git_version
+
+    def __str__(self):
+        return "expected firmware {}, device has {} ({})".format(
+            self.expected, self.version, self.git_version)
+
+
+class WrongDevice(Exception):
+    """The device reports a different MCU ID than the one expected."""
+    def __init__(self, expected, found):
+        self.expected = expected
+        self.found = found
+
+    def __str__(self):
+        return "expected MCU ID {}, device has {}".format(
+            self.expected, self.found)
+
+
+class ImageError(Exception):
+    pass
+
+
+class FollowError(Exception):
+    """The device could not be found again at its new address."""
+    pass
+
+
+# Ranges of RAM an initial stack pointer may point into: SRAM and CCM RAM.
+RAM_RANGES = [(0x20000000, 0x20020000), (0x10000000, 0x10010000)]
+
+# Names of the vectors checked by analyze_image, after the initial SP.
+VECTOR_NAMES = ["Reset", "NMI", "HardFault", "MemManage", "BusFault",
+                "UsageFault"]
+
+
+class ImageReport:
+    """Result of analyze_image: a list of problems found, empty if none."""
+    def __init__(self, base, problems):
+        self.base = base
+        self.problems = problems
+
+    def ok(self):
+        return not self.problems
+
+    def __str__(self):
+        if self.ok():
+            return "Vector table OK for base {:08X}".format(self.base)
+        return "Vector table problems for base {:08X}:\n  {}".format(
+            self.base, "\n  ".join(self.problems))
+
+
+def analyze_image(data, expected_base, bases=()):
+    """
+    Check the vector table at the start of an image is plausible for an
+    image loaded at expected_base: the initial stack pointer must be in RAM,
+    and the reset and fault vectors must be Thumb addresses inside the image.
+
+    If the vectors don't fit expected_base but would fit one of the other
+    candidate bases (for example the flash sector addresses), the report
+    says which base the image appears to have been linked for.
+    """
+    problems = []
+    n_vectors = min(len(data) // 4, len(VECTOR_NAMES) + 1)
+    if n_vectors < 2:
+        return ImageReport(expected_base, ["image too short"])
+    vectors = struct.unpack("<{}I".format(n_vectors), data[:4*n_vectors])
+
+    sp = vectors[0]
+    if not any(lo <= sp <= hi for lo, hi in RAM_RANGES):
+        problems.append("initial stack pointer {:08X} not in RAM".format(sp))
+
+    def outside(vector, base):
+        return not base <= (vector & ~1) < base + len(data)
+
+    for name, vector in zip(VECTOR_NAMES, vectors[1:]):
+        if name != "Reset" and vector == 0:
+            continue
+        if vector & 1 == 0:
+            problems.append("{} vector {:08X} missing Thumb bit".format(
+                name, vector))
+        if outside(vector, expected_base):
+            problems.append("{} vector {:08X} outside image".format(
+                name, vector))
+
+    used = [v for v in vectors[1:] if v != 0]
+    if any(outside(v, expected_base) for v in used):
+        for base in bases:
+            if base != expected_base and not any(
+                    outside(v, base) for v in used):
+                problems.append(
+                    "image appears to be linked for {:08X}".format(base))
+                break
+
+    return ImageReport(expected_base, problems)
+
 
 def boot_request(hostname, boot_req_port, bootloader_port, n_attempts=10):
     print("Sending UDP boot request to port {}...".format(boot_req_port))
@@ -83,20 +366,188 @@ def boot_request(hostname, boot_req_port, bootloader_port, n_attempts=10):
             break
 
 
+def summarise_bytes(data, limit=32):
+    """Hex dump data for logging, eliding the middle of long payloads."""
+    if len(data) <= limit:
+        return data.hex()
+    return "{}...{} ({} bytes)".format(
+        data[:limit - 8].hex(), data[-8:].hex(), len(data))
+
+
+class CaptureLog:
+    """
+    Records every exchange made by interact to a binary file for later
+    replay. Each record is a header of the time, outcome (0 for a response,
+    1 for a connection error), a flag set if payloads are hashed, the port,
+    and the lengths of the command and response, followed by the command
+    and response bytes. If hash_payloads is set, the bytes after the first
+    12 of each (enough for the command and its address and length, or the
+    status) are replaced by their SHA-256 digest.
+    """
+    MAGIC = b"BLETHRSCAP1\n"
+    HEADER = "<dBBHII"
+
+    def __init__(self, f, hash_payloads=False):
+        self.f = f
+        self.hash_payloads = hash_payloads
+        self.f.write(self.MAGIC)
+
+    def _payload(self, data):
+        if self.hash_payloads and len(data) > 12:
+            return data[:12] + hashlib.sha256(data[12:]).digest()
+        return data
+
+    def record(self, port, command, response, outcome=0):
+        command = self._payload(command)
+        response = self._payload(response)
+        self.f.write(struct.pack(self.HEADER, time.time(), outcome,
+                                 self.hash_payloads, port, len(command),
+                                 len(response)))
+        self.f.write(command + response)
+        self.f.flush()
+
+
+def read_capture(f):
+    """
+    Yield each record of a CaptureLog file as a tuple of
+    (time, outcome, hashed, port, command, response).
+    """
+    if f.read(len(CaptureLog.MAGIC)) != CaptureLog.MAGIC:
+        raise ValueError("not a capture file")
+    size = struct.calcsize(CaptureLog.HEADER)
+    while True:
+        header = f.read(size)
+        if len(header) < size:
+            return
+        t, outcome, hashed, port, ntx, nrx = struct.unpack(
+            CaptureLog.HEADER, header)
+        command = f.read(ntx)
+        response = f.read(nrx)
+        yield t, outcome, hashed, port, command, response
+
+
+# Set to a CaptureLog to record every exchange made by interact.
+capture = None
+
+# Set to the device's key, as bytes, to sign commands which change it, for
+# bootloaders built with the auth feature.
+auth_key = None
+
+# Commands which must be signed once a key is stored on the device.
+AUTH_COMMANDS = frozenset(commands[name] for name in (
+    "erase", "write", "boot", "trial_boot", "set_mac", "set_config",
+    "first_word", "reboot", "unlock", "set_key", "write_compressed",
+    "write_mem"))
+
+AUTH_KEY_LEN = 32
+
+
+class TcpTransport:
+    """
+    Sends each command to the bootloader over its own TCP connection.
+
+    To reach devices some other way, for example through a serial to
+    ethernet gateway which adds its own framing, set the module's transport
+    to an object with the same exchange method. It must send the command
+    bytes unchanged and return the response bytes, starting with the status
+    word, raising OSError if the exchange fails. All commands then use it.
+    """
+    def exchange(self, hostname, port, command, timeout):
+        s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        try:
+            s.settimeout(timeout)
+            s.connect((hostname, port))
+            s.sendall(command)
+            # The device closes the connection once it has sent the whole
+            # response, which may arrive in several segments
+            response = b""
+            while True:
+                data = s.recv(2048)
+                if not data:
+                    return response
+                response += data
+        finally:
+            s.close()
+
+
+transport = TcpTransport()
+
+
+def sign_command(hostname, port, command):
+    """
+    Append the HMAC-SHA-256 tag of a fresh nonce from the device followed by
+    the command, keyed with auth_key. Each nonce is only accepted once.
+    """
+    nonce = interact(hostname, port,
+                     struct.pack("<I", commands['auth_nonce']))
+    tag = hmac.new(auth_key, nonce + command, hashlib.sha256).digest()
+    return command + tag
+
+
 def interact(hostname, port, command, timeout=2):
-    s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-    s.settimeout(timeout)
-    s.connect((hostname, port))
-    s.sendall(command)
-    data = check_response(s.recv(2048))
-    s.close()
+    if auth_key is not None and \
+            struct.unpack("<I", command[:4])[0] in AUTH_COMMANDS:
+        command = sign_command(hostname, port, command)
+    try:
+        logger.debug("%s:%d tx %s", hostname, port, summarise_bytes(command))
+        data = transport.exchange(hostname, port, command, timeout)
+        logger.debug("%s:%d rx %s", hostname, port, summarise_bytes(data))
+    except OSError:
+        if capture is not None:
+            capture.record(port, command, b"", outcome=1)
+        raise
+    if capture is not None:
+        capture.record(port, command, data)
+    data = check_response(data)
     time.sleep(0.01)
     return data
 
 
+def replay(hostname, port, f):
+    """
+    Re-send each command from a capture file to the bootloader at
+    hostname:port, and report any whose response differs from the one
+    captured. Returns the number of differing responses. Hashed captures
+    can't be replayed, as their commands are incomplete.
+    """
+    differences = 0
+    for idx, (t, outcome, hashed, _, command, response) in \
+            enumerate(read_capture(f)):
+        if hashed:
+            raise ValueError("capture has hashed payloads, can't replay")
+        try:
+            data = transport.exchange(hostname, port, command, 20)
+            replayed = 0
+        except OSError:
+            data = b""
+            replayed = 1
+        time.sleep(0.01)
+        if (replayed, data) != (outcome, response):
+            differences += 1
+            print("Record {} differs: command {}".format(
+                idx, summarise_bytes(command)))
+            print("  captured {}".format(summarise_bytes(response)))
+            print("  replayed {}".format(summarise_bytes(data)))
+    return differences
+
+
 def check_response(data):
     errno = struct.unpack("<I", data[:4])[0]
     if errno != 0:
+        # Erase, write, flash and write protection errors may be followed by
+        # the failing address, its sector, the flash status register and the
+        # nWRP option bits. Older firmware sends fewer of these, or only the
+        # address for erases which failed to blank-check.
+        if errno in (5, 6, 7, 12) and len(data) >= 16:
+            address, sector, sr = struct.unpack("<III", data[4:16])
+            nwrp = None
+            if len(data) >= 20:
+                nwrp = struct.unpack("<I", data[16:20])[0]
+            if sector == SECTOR_NONE:
+                address, sector = None, None
+            raise BootloaderError(errno, address, sector, sr, nwrp)
+        if errno == 5 and len(data) >= 8:
+            raise BootloaderError(errno, struct.unpack("<I", data[4:8])[0])
         raise BootloaderError(errno)
     return data[4:]
 
@@ -106,9 +557,252 @@ def info_cmd(hostname, port):
     return interact(hostname, port, cmd)
 
 
-def erase_cmd(hostname, port, address, length):
+def parse_info(info):
+    """
+    Parse the text returned by info_cmd into a dict. The first line gives
+    "version" and "git_version", and each later "Key: value" line is added
+    under its key, lowercased with spaces replaced by underscores.
+    """
+    lines = info.decode(errors="replace").splitlines()
+    fields = {"version": None, "git_version": None}
+    if lines:
+        words = lines[0].split()
+        if len(words) >= 2:
+            fields["version"] = words[1]
+        if len(words) >= 3:
+            fields["git_version"] = words[2]
+    for line in lines[1:]:
+        key, sep, value = line.partition(": ")
+        if sep:
+            fields[key.lower().replace(" ", "_")] = value
+    return fields
+
+
+def check_version(info, expected):
+    """
+    Check the device reports the expected firmware version, which may be
+    either the package version or the git version, raising VersionMismatch
+    if not.
+    """
+    fields = parse_info(info)
+    if expected not in (fields["version"], fields["git_version"]):
+        raise VersionMismatch(expected, fields["version"],
+                              fields["git_version"])
+
+
+def normalize_mcu_id(mcu_id):
+    """Canonical form of a hex MCU ID: upper case, without separators."""
+    return "".join(c for c in mcu_id.upper() if c not in " :-")
+
+
+def check_mcu_id(info, expected):
+    """
+    Check the device reports the expected MCU ID, ignoring case and any
+    separators, raising WrongDevice if not. IP addresses don't identify a
+    device, as cables and boards get swapped.
+    """
+    found = parse_info(info).get("mcu_id")
+    if found is None or normalize_mcu_id(found) != normalize_mcu_id(expected):
+        raise WrongDevice(expected, found)
+
+
+def inventory_mcu_id(f, hostname):
+    """
+    Return the MCU ID recorded for hostname in an inventory written by
+    the inventory command in json format, or None if it isn't listed.
+    """
+    for entry in json.load(f):
+        if entry.get("host") == hostname:
+            return entry.get("mcu_id")
+    return None
+
+
+def layout_cmd(hostname, port):
+    cmd = struct.pack("<I", commands['layout'])
+    data = interact(hostname, port, cmd)
+    config, user, end, n_sectors = struct.unpack("<IIII", data[:16])
+    sectors = list(struct.iter_unpack("<III", data[16:16+12*n_sectors]))
+    # Older firmware doesn't report which image it boots, which was always
+    # the one at the user address.
+    active = data[16+12*n_sectors:20+12*n_sectors]
+    active = struct.unpack("<I", active)[0] if len(active) == 4 else user
+    return {"config": config, "user": user, "end": end, "sectors": sectors,
+            "active": active}
+
+
+def get_layout(hostname, port):
+    """
+    Read the device flash layout, or return None if the firmware is too old
+    to report it (in which case it closes the connection without replying).
+    """
+    try:
+        return layout_cmd(hostname, port)
+    except (BootloaderError, struct.error):
+        return None
+
+
+class PreflightReport:
+    """
+    Result of preflight_check: the bootloader information, the round trip
+    time of the info command, and the device layout (None if not reported).
+    """
+    SLOW_RTT_MS = 500
+
+    def __init__(self, info, rtt, layout):
+        self.info = info
+        self.rtt = rtt
+        self.layout = layout
+
+    @property
+    def rtt_ms(self):
+        return int(self.rtt * 1000)
+
+    @property
+    def timeout_multiplier(self):
+        """Suggested scale for timeouts, relative to a 10ms round trip."""
+        return max(1.0, self.rtt_ms / 10)
+
+    def slow(self):
+        return self.rtt_ms > self.SLOW_RTT_MS
+
+    def __str__(self):
+        lines = ["Round trip time {}ms".format(self.rtt_ms)]
+        if self.layout is None:
+            lines.append("Device did not report its layout")
+        else:
+            lines.append("Config at {:08X}, user firmware at {:08X}".format(
+                self.layout['config'], self.layout['user']))
+            lines.append("Will boot image at {:08X}".format(
+                self.layout['active']))
+        if self.slow():
+            lines.append(
+                "Warning: slow connection, operations may time out; "
+                "timeouts would need scaling by about {:.0f}x".format(
+                    self.timeout_multiplier))
+        return "\n".join(lines)
+
+
+def preflight_check(hostname, port, layout=True):
+    """
+    Read the bootloader information, timing the round trip, and if layout
+    is set also read the device layout, before starting a long operation.
+    """
+    start_time = time.monotonic()
+    info = info_cmd(hostname, port)
+    rtt = time.monotonic() - start_time
+    return PreflightReport(
+        info, rtt, get_layout(hostname, port) if layout else None)
+
+
+def touched_sectors(layout, address, length):
+    """
+    List the sectors in layout touched by the given range, as tuples of
+    (index, start, end, typical erase ms, maximum erase ms).
+    """
+    sectors = layout['sectors']
+    touched = []
+    for idx, (start, typ_ms, max_ms) in enumerate(sectors):
+        if idx + 1 < len(sectors):
+            end = sectors[idx + 1][0] - 1
+        else:
+            end = layout['end']
+        if address <= end and address + length >= start:
+            touched.append((idx, start, end, typ_ms, max_ms))
+    return touched
+
+
+class SectorUsage:
+    """
+    How much of one flash sector an image occupies: the sector index and
+    address range, the bytes of the image written to it, and the bytes
+    which are erased but left as 0xFF.
+    """
+    def __init__(self, index, start, end, used):
+        self.index = index
+        self.start = start
+        self.end = end
+        self.used = used
+
+    @property
+    def size(self):
+        return self.end + 1 - self.start
+
+    @property
+    def unused(self):
+        return self.size - self.used
+
+    def __str__(self):
+        return "{:>6} {:08X}-{:08X} {:>8} {:>8}".format(
+            self.index, self.start, self.end, self.used, self.unused)
+
+
+def image_layout(layout, address, length):
+    """
+    List a SectorUsage for each sector in layout an image of length bytes
+    at address touches, all of which are erased when it is programmed.
+    """
+    usage = []
+    if length == 0:
+        return usage
+    for idx, start, end, _, _ in touched_sectors(layout, address, length - 1):
+        used = min(end + 1, address + length) - max(start, address)
+        usage.append(SectorUsage(idx, start, end, used))
+    return usage
+
+
+def format_image_layout(usage):
+    """Format a list of SectorUsage as a table, with totals."""
+    lines = ["Sector Address range      Image   Unused"]
+    lines.extend(str(sector) for sector in usage)
+    lines.append("Total {} sectors, {} bytes erased, {} bytes of image".format(
+        len(usage), sum(s.size for s in usage), sum(s.used for s in usage)))
+    return "\n".join(lines)
+
+
+def erase_timeout(layout, address, length):
+    """
+    Compute a timeout for erasing the given range, from the maximum erase
+    time of each sector it touches plus a margin. Without a layout, fall
+    back to a timeout long enough for any erase.
+    """
+    if layout is None:
+        return 20.0
+    erase_ms = sum(s[4] for s in touched_sectors(layout, address, length))
+    return 1.5 * erase_ms / 1000 + 2.0
+
+
+def estimate_write_time(layout, address, length, chunk_size, rtt):
+    """
+    Estimate the time in seconds to erase, write and read back an image,
+    from the typical erase time of each sector, the typical 16us word
+    programming time, and one round trip per segment written and read.
+    """
+    erase_ms = sum(s[3] for s in touched_sectors(layout, address, length))
+    segments = (length + chunk_size - 1) // chunk_size
+    return erase_ms / 1000 + (length // 4) * 16e-6 + segments * 2 * rtt
+
+
+class EraseResult:
+    """Number of sectors erased by an erase command, and the time it took."""
+    def __init__(self, sectors, ms):
+        self.sectors = sectors
+        self.ms = ms
+
+    def __str__(self):
+        return "Erased {} sectors in {}ms.".format(self.sectors, self.ms)
+
+
+def erase_cmd(hostname, port, address, length, layout=None):
+    """
+    Erase the sectors covering the given range. Returns an EraseResult, or
+    None if the device is too old to report one.
+    """
     cmd = struct.pack("<III", commands['erase'], address, length)
-    interact(hostname, port, cmd, timeout=20.0)
+    timeout = erase_timeout(layout, address, length)
+    data = interact(hostname, port, cmd, timeout=timeout)
+    if len(data) < 8:
+        return None
+    return EraseResult(*struct.unpack("<II", data[:8]))
 
 
 def read_cmd(hostname, port, address, length):
@@ -122,79 +816,1060 @@ def write_cmd(hostname, port, address, data):
     interact(hostname, port, cmd)
 
 
+def defer_first_word_cmd(hostname, port):
+    """
+    Ask the device to hold back the first word of the user firmware from
+    later writes until committed, so an interrupted write leaves it erased
+    and the device stays in the bootloader. Returns the address of the word
+    held back, or None if the device doesn't support this.
+    """
+    cmd = struct.pack("<II", commands['first_word'], 0)
+    try:
+        data = interact(hostname, port, cmd)
+    except BootloaderError as e:
+        if e.errno != 10:
+            raise
+        return None
+    return struct.unpack("<I", data[:4])[0]
+
+
+def commit_first_word_cmd(hostname, port, word):
+    """Program the held back first word, which must equal word."""
+    cmd = struct.pack("<III", commands['first_word'], 1, word)
+    interact(hostname, port, cmd)
+
+
+def cancel_first_word_cmd(hostname, port):
+    """Stop holding back the first word of the user firmware."""
+    cmd = struct.pack("<II", commands['first_word'], 2)
+    interact(hostname, port, cmd)
+
+
+def echo_cmd(hostname, port, data):
+    """Send data to the device and return what it sends back."""
+    cmd = struct.pack("<II", commands['echo'], len(data))
+    return interact(hostname, port, cmd + data)
+
+
+# Chunk size used when it isn't given and the device can't be probed.
+DEFAULT_CHUNK_SIZE = 512
+
+# Payload sizes tried by probe_payload_size, largest first.
+PROBE_SIZES = (1024, 768, 512, 256, 128)
+
+
+def probe_payload_size(hostname, port, attempts=3):
+    """
+    Find the largest payload in PROBE_SIZES which reaches the device intact
+    in a single receive, as a write chunk must, on every one of attempts
+    tries. On links with a small path MTU, such as VPNs, larger payloads
+    are split and fail. The echoed response is read in full however it is
+    split, so only delivery of the request is measured. Returns None if the
+    device has no echo command or no size works.
+    """
+    for size in PROBE_SIZES:
+        data = bytes((idx * 7) & 0xFF for idx in range(size))
+        try:
+            if all(echo_cmd(hostname, port, data) == data
+                   for _ in range(attempts)):
+                return size
+        except BootloaderError as e:
+            if e.errno == 10:
+                return None
+            if e.errno != 4:
+                raise
+    return None
+
+
+def safe_chunk_size(hostname, port):
+    """
+    Choose a write chunk size for the path to the device: half the largest
+    payload which probes intact, leaving margin for changing conditions, or
+    DEFAULT_CHUNK_SIZE if the device can't be probed.
+    """
+    size = probe_payload_size(hostname, port)
+    if size is None:
+        return DEFAULT_CHUNK_SIZE
+    return size // 2
+
+
+def lz4_compress(data):
+    """
+    Compress data as a single LZ4 block, using a simple greedy matcher.
+    The output follows the LZ4 end of block rules, so any LZ4 block
+    decoder can decompress it.
+    """
+    out = bytearray()
+    table = {}
+    anchor = pos = 0
+    # Matches may not start in the last 12 bytes, or end in the last 5.
+    while pos < len(data) - 12:
+        key = data[pos:pos+4]
+        candidate = table.get(key)
+        table[key] = pos
+        if candidate is None or pos - candidate > 0xFFFF:
+            pos += 1
+            continue
+        length = 4
+        while (pos + length < len(data) - 5 and
+               data[candidate + length] == data[pos + length]):
+            length += 1
+        _lz4_sequence(out, data[anchor:pos], pos - candidate, length)
+        pos += length
+        anchor = pos
+    _lz4_sequence(out, data[anchor:])
+    return bytes(out)
+
+
+def _lz4_sequence(out, literals, offset=None, length=4):
+    match = length - 4
+    token = min(len(literals), 15) << 4
+    if offset is not None:
+        token |= min(match, 15)
+    out.append(token)
+    if len(literals) >= 15:
+        _lz4_length(out, len(literals) - 15)
+    out += literals
+    if offset is not None:
+        out += struct.pack("<H", offset)
+        if match >= 15:
+            _lz4_length(out, match - 15)
+
+
+def _lz4_length(out, n):
+    while n >= 255:
+        out.append(255)
+        n -= 255
+    out.append(n)
+
+
+def write_compressed_cmd(hostname, port, address, data):
+    """
+    Write data to address, sent LZ4 compressed. Requires firmware built
+    with the compressed-write feature. Returns False without sending
+    anything if data doesn't compress, so it should be written normally.
+    """
+    compressed = lz4_compress(data)
+    if len(compressed) >= len(data):
+        return False
+    cmd = struct.pack("<IIIII", commands['write_compressed'], address,
+                      len(data), config_crc(data), len(compressed))
+    interact(hostname, port, cmd + compressed)
+    return True
+
+
+# Command codes from this value upwards are reserved for integrator
+# specific commands, handled by the firmware's CustomHandler.
+CUSTOM_COMMAND_BASE = 0x80000000
+
+
+def custom_cmd(hostname, port, command, payload=b"", timeout=2):
+    """
+    Send an integrator specific command, which must be at least
+    CUSTOM_COMMAND_BASE, followed by payload, and return the response data.
+    """
+    if command < CUSTOM_COMMAND_BASE:
+        raise ValueError("custom commands must be at least {:08X}".format(
+            CUSTOM_COMMAND_BASE))
+    cmd = struct.pack("<I", command) + payload
+    return interact(hostname, port, cmd, timeout=timeout)
+
+
+# Battery backed SRAM, accessible with read_mem and write_mem when the
+# firmware is built with the backup-sram feature.
+BACKUP_SRAM = (0x40024000, 4096)
+
+
+def check_mem_range(address, length):
+    """Check an access is word aligned, in chunks, and within BACKUP_SRAM."""
+    start, size = BACKUP_SRAM
+    if address % 4 != 0 or length % 4 != 0:
+        raise ValueError("memory accesses must be word aligned")
+    if length > 1024:
+        raise ValueError("memory accesses must be at most 1024 bytes")
+    if address < start or address + length > start + size:
+        raise ValueError("{} bytes at {:08X} not within backup SRAM".format(
+            length, address))
+
+
+def read_mem(hostname, port, address, length):
+    check_mem_range(address, length)
+    cmd = struct.pack("<III", commands['read_mem'], address, length)
+    return interact(hostname, port, cmd)
+
+
+def write_mem(hostname, port, address, data):
+    check_mem_range(address, len(data))
+    cmd = struct.pack("<III", commands['write_mem'], address, len(data))
+    interact(hostname, port, cmd + bytes(data))
+
+
 def boot_cmd(hostname, port):
     cmd = struct.pack("<I", commands['boot'])
     interact(hostname, port, cmd)
 
 
-def write_file(hostname, port, chunk_size, address, data):
-    # We need to write in multiples of 4 bytes (since writes are word-by-word),
-    # so add padding to the end of the data.
-    length = len(data)
-    if length % 4 != 0:
-        padding = 4 - length % 4
-        data += b"\xFF"*padding
-        length += padding
-    segments = length // chunk_size
-    if length % chunk_size != 0:
-        segments += 1
+def trial_boot_cmd(hostname, port):
+    cmd = struct.pack("<I", commands['trial_boot'])
+    interact(hostname, port, cmd)
 
-    print("Erasing (may take a few seconds)...")
-    erase_cmd(hostname, port, address, length)
 
-    print("Writing {:.02f}kB in {} segments...".format(length/1024, segments))
-    for sidx in tqdm(list(reversed(range(segments))),
-                     unit='kB', unit_scale=chunk_size/1024):
+def unlock_token(mcu_id):
+    """
+    Compute the token which unlocks flashing on the device with the given
+    MCU ID, as shown by info_cmd. This only guards against accidents: anyone
+    who can reach the device can read its MCU ID.
+    """
+    words = [int(mcu_id[idx:idx+8], 16) for idx in (16, 8, 0)]
+    return config_crc(struct.pack("<III", *words))
+
+
+def unlock_cmd(hostname, port):
+    """Allow flashing a locked device until it next resets."""
+    mcu_id = parse_info(info_cmd(hostname, port))["mcu_id"]
+    cmd = struct.pack("<II", commands['unlock'], unlock_token(mcu_id))
+    interact(hostname, port, cmd)
+
+
+def read_key_file(f):
+    """Read a key written as 64 hex digits, raising ValueError if invalid."""
+    key = bytes.fromhex(f.read().strip())
+    if len(key) != AUTH_KEY_LEN:
+        raise ValueError("key must be {} bytes".format(AUTH_KEY_LEN))
+    return key
+
+
+def set_key_cmd(hostname, port, key):
+    """
+    Store the key commands must then be signed with, or remove it if key is
+    None. Once a key is stored, changing or removing it must be signed with
+    the old one. The key is sent in the clear, so only set it over a network
+    you trust.
+    """
+    if key is None:
+        key = bytes(AUTH_KEY_LEN)
+    interact(hostname, port, struct.pack("<I", commands['set_key']) + key)
+
+
+def set_lock(hostname, port, locked):
+    """
+    Lock or unlock flashing in the stored configuration, so erases, writes
+    and configuration changes are refused with error 13 until unlock_cmd.
+    The device must already have a valid configuration.
+    """
+    state, fields = get_config(hostname, port)
+    if fields is None:
+        raise ValueError("configuration is {}, configure the device "
+                         "first".format(state))
+    unlock_cmd(hostname, port)
+    if locked:
+        fields.flags |= CONFIG_FLAG_LOCKED
+    else:
+        fields.flags &= ~CONFIG_FLAG_LOCKED
+    set_config_cmd(hostname, port, fields.to_bytes())
+
+
+def reboot_cmd(hostname, port):
+    """Reset the device back into the bootloader."""
+    cmd = struct.pack("<I", commands['reboot'])
+    interact(hostname, port, cmd)
+
+
+# Bits of the self test failure mask, and what each check covers.
+SELF_TEST_CHECKS = [
+    (1 << 0, "config", "stored configuration is not corrupt"),
+    (1 << 1, "ram", "walking ones over scratch RAM"),
+    (1 << 2, "phy", "PHY responds over SMI"),
+    (1 << 3, "flash_size", "flash size matches the layout"),
+    (1 << 4, "uid", "unique ID is programmed"),
+]
+
+def self_test_cmd(hostname, port):
+    """
+    Run the device's non-destructive self test, returning a dict with the
+    failure mask, a pass/fail entry per check, the CRC of the installed
+    bootloader, the config state, the PHY ID and the flash size in kB.
+    """
+    cmd = struct.pack("<I", commands['self_test'])
+    data = interact(hostname, port, cmd, timeout=5)
+    failures, crc, config, phy_id, flash_kb = struct.unpack("<IIIII",
+                                                            data[:20])
+    states = [CONFIG_VALID, CONFIG_BLANK, CONFIG_BAD_MAGIC,
+              CONFIG_BAD_CHECKSUM]
+    result = {name: not failures & bit for (bit, name, _) in SELF_TEST_CHECKS}
+    result.update({
+        "failures": failures,
+        "bootloader_crc": crc,
+        "config_state": states[config] if config < len(states) else "unknown",
+        "phy_id": phy_id,
+        "flash_size_kb": flash_kb,
+    })
+    return result
+
+
+class DeviceStatus:
+    """
+    Snapshot of a device returned by status_cmd. Fields added in newer
+    structure versions than the device sends are None.
+    """
+    # (name, struct format) of each field, in order, as appended by version.
+    FIELDS = [
+        ("version", "16s"), ("uid", "12s"), ("reset_flags", "I"),
+        ("uptime_ms", "I"), ("config_state", "I"), ("mac_address", "6s"),
+        ("ip_prefix", "B"), ("_pad", "x"), ("ip_address", "4s"),
+        ("config", "I"), ("user", "I"), ("flash_end", "I"),
+        ("cpu_percent", "I"), ("listen_failures", "I"),
+        ("entry_ms", "I"), ("clocks_up_ms", "I"), ("interface_ready_ms", "I"),
+        ("link_up_ms", "I"),
+    ]
+    # Startup milestone times, in ms from reset, not yet reached.
+    NOT_REACHED = 0xFFFFFFFF
+
+    def __init__(self, data):
+        self.struct_version, length = struct.unpack("<II", data[:8])
+        body = data[8:8+length]
+        offset = 0
+        for name, fmt in self.FIELDS:
+            size = struct.calcsize("<" + fmt)
+            if fmt == "x":
+                offset += size
+                continue
+            value = None
+            if offset + size <= len(body):
+                value = struct.unpack_from("<" + fmt, body, offset)[0]
+            setattr(self, name, value)
+            offset += size
+        if self.version is not None:
+            self.version = self.version.rstrip(b"\0").decode()
+        if self.uid is not None:
+            words = struct.unpack("<III", self.uid)
+            self.uid = "".join("{:08X}".format(w) for w in reversed(words))
+        if self.mac_address is not None:
+            self.mac_address = ":".join("{:02X}".format(b)
+                                        for b in self.mac_address)
+        if self.ip_address is not None:
+            self.ip_address = ".".join(str(b) for b in self.ip_address)
+
+    def __str__(self):
+        states = [CONFIG_VALID, CONFIG_BLANK, CONFIG_BAD_MAGIC,
+                  CONFIG_BAD_CHECKSUM]
+        lines = []
+        for name, fmt in self.FIELDS:
+            value = getattr(self, name, None)
+            if name.startswith("_") or value is None:
+                continue
+            if name == "config_state" and value < len(states):
+                value = states[value]
+            elif name.endswith("_ms") and value == self.NOT_REACHED:
+                value = "pending"
+            elif name in ("reset_flags", "config", "user", "flash_end"):
+                value = "{:08X}".format(value)
+            lines.append("{}: {}".format(name, value))
+        return "\n".join(lines)
+
+
+def status_cmd(hostname, port):
+    """Fetch a DeviceStatus snapshot in a single command."""
+    cmd = struct.pack("<I", commands['status'])
+    return DeviceStatus(interact(hostname, port, cmd))
+
+
+def set_mac_cmd(hostname, port, mac):
+    """
+    Store a new MAC address in the device configuration, keeping the
+    other settings. The device switches to it once this command completes,
+    so hosts may need to refresh their ARP entry for it.
+    """
+    mac = bytes(int(x, 16) for x in mac.split(":"))
+    if len(mac) != 6:
+        raise ValueError("MAC address must have 6 octets")
+    if mac[0] & 0x01 or not mac[0] & 0x02:
+        raise ValueError("MAC address must be unicast and locally "
+                         "administered, e.g. 02:xx:xx:xx:xx:xx")
+    cmd = struct.pack("<I6s", commands['set_mac'], mac)
+    interact(hostname, port, cmd, timeout=5)
+
+
+# Names of the counters sent by the diagnostics command, in order. Newer firmware
+# only ever appends counters.
+DIAGNOSTIC_COUNTERS = ["poll_errors", "dropped_frames", "dma_missed_frames",
+                       "flash_errors", "listen_failures"]
+
+
+def diagnostics_cmd(hostname, port, reset=False):
+    """
+    Fetch the device's error counters as a list of (name, value), clearing
+    them on the device if reset is set. Counters added by firmware newer
+    than this client are named counter_N.
+    """
+    cmd = struct.pack("<II", commands['diagnostics'], int(reset))
+    data = interact(hostname, port, cmd)
+    _, count = struct.unpack("<II", data[:8])
+    values = struct.unpack("<{}I".format(count), data[8:8+4*count])
+    names = DIAGNOSTIC_COUNTERS + ["counter_{}".format(idx) for idx in
+                                   range(len(DIAGNOSTIC_COUNTERS), count)]
+    return list(zip(names, values))
+
+
+# Names of the words sent by the mem info command, in order. Newer firmware only
+# ever appends words.
+MEM_INFO_FIELDS = ["statics_bytes", "stack_peak_bytes", "free_bytes",
+                   "ethernet_buffer_bytes", "log_buffer_bytes",
+                   "tcp_rx_capacity", "tcp_rx_queued", "tcp_tx_capacity",
+                   "tcp_tx_queued"]
+
+
+def mem_info_cmd(hostname, port):
+    """
+    Fetch the device's RAM use as a list of (name, value): the RAM taken by
+    statics, the deepest stack use since startup, the RAM never used
+    between them, the sizes of the main buffers and the TCP buffer use.
+    Words added by firmware newer than this client are named word_N.
+    """
+    cmd = struct.pack("<I", commands['mem_info'])
+    data = interact(hostname, port, cmd)
+    _, count = struct.unpack("<II", data[:8])
+    values = struct.unpack("<{}I".format(count), data[8:8+4*count])
+    names = MEM_INFO_FIELDS + ["word_{}".format(idx) for idx in
+                               range(len(MEM_INFO_FIELDS), count)]
+    return list(zip(names, values))
+
+
+def get_log_cmd(hostname, port):
+    """
+    Fetch the bootloader's recent log output, the same text it prints over
+    semihosting, as a string.
+    """
+    cmd = struct.pack("<I", commands['get_log'])
+    data = interact(hostname, port, cmd)
+    length = struct.unpack("<I", data[:4])[0]
+    return data[4:4+length].decode(errors="replace")
+
+
+def check_image_fits(layout, address, length, allow_config=False):
+    """
+    Check an image of the given length can be written at address,
+    according to the device layout, before anything is erased.
+
+    Images which would overwrite the configuration sector are refused
+    unless allow_config is set.
+    """
+    if address % 4 != 0 or address < layout['config']:
+        raise ImageError("invalid address {:08X}".format(address))
+    available = layout['end'] + 1 - address
+    if length > available:
+        raise ImageError(
+            "image of {} bytes too large for {} bytes available at {:08X}, "
+            "overflows by {} bytes".format(
+                length, available, address, length - available))
+    config = touched_sectors(layout, layout['config'], 0)[0]
+    if not allow_config and address <= config[2] and \
+            address + length > config[1]:
+        raise ImageError(
+            "image at {:08X} would overwrite the configuration sector at "
+            "{:08X}".format(address, config[1]))
+
+
+def pad_to_word(data):
+    """
+    We need to write in multiples of 4 bytes (since writes are word-by-word),
+    so add padding to the end of the data.
+    """
+    if len(data) % 4 != 0:
+        data += b"\xFF" * (4 - len(data) % 4)
+    return data
+
+
+# Alignments normalize_image can pad an image to.
+ALIGNMENTS = ("word", "sector")
+
+
+class Normalization:
+    """Bytes of 0xFF trimmed from the end of an image and padding added."""
+    def __init__(self, trimmed, padded):
+        self.trimmed = trimmed
+        self.padded = padded
+
+    def __str__(self):
+        return "Trimmed {} trailing 0xFF bytes, padded {} bytes.".format(
+            self.trimmed, self.padded)
+
+
+def normalize_image(data, align="word", trim_trailing_ff=False,
+                    address=None, layout=None):
+    """
+    Prepare an image at address for programming. Returns the adjusted image
+    and a Normalization.
+
+    With trim_trailing_ff, trailing 0xFF bytes are removed, as they match
+    erased flash and needn't be written, unless the image is entirely 0xFF.
+    Images from objcopy with --gap-fill 0xff can end in megabytes of them.
+
+    The result is then padded with 0xFF to a whole number of words, or with
+    align="sector" to the end of the sector in layout it ends in, for
+    placing images in separate slots.
+    """
+    if align not in ALIGNMENTS:
+        raise ValueError("unknown alignment {}".format(align))
+    original = len(data)
+    if trim_trailing_ff and data.rstrip(b"\xFF"):
+        data = data.rstrip(b"\xFF")
+    trimmed = original - len(data)
+    data = pad_to_word(data)
+    if align == "sector":
+        if layout is None or address is None:
+            raise ValueError("sector alignment needs the address and layout")
+        sectors = touched_sectors(layout, address + len(data) - 1, 0)
+        if not sectors:
+            raise ImageError("image ends outside the device's flash")
+        data += b"\xFF" * (sectors[0][2] + 1 - address - len(data))
+    padded = len(data) + trimmed - original
+    return data, Normalization(trimmed, padded)
+
+
+class EtaProgress:
+    """
+    Progress callback printing the amount written and time remaining, for
+    use when tqdm isn't available. The time remaining is extrapolated from
+    the initial estimate in seconds, if given, until a few chunks have been
+    written, and from the measured rate after that.
+    """
+    def __init__(self, estimate=None, measured_after=3):
+        self.estimate = estimate
+        self.measured_after = measured_after
+        self.calls = 0
+
+    def __call__(self, done, total, elapsed):
+        self.calls += 1
+        if self.calls >= self.measured_after:
+            remaining = elapsed / done * (total - done)
+        elif self.estimate is not None:
+            remaining = max(self.estimate - elapsed, 0)
+        else:
+            remaining = None
+        if remaining is None:
+            eta = "--:--"
+        else:
+            eta = "{:02d}:{:02d}".format(*divmod(int(remaining + 0.5), 60))
+        end = "\n" if done == total else ""
+        print("\r[{:.1f}%] Written {}KB / {}KB ETA {}".format(
+            100 * done / total, done // 1024, total // 1024, eta), end=end)
+
+
+WRITE_DIRECTIONS = ("reverse", "forward", "forward-first-last")
+
+
+def chunk_order(segments, direction="reverse"):
+    """
+    Order in which to write chunks, one of WRITE_DIRECTIONS:
+
+    reverse: last chunk first. The vector table in the first chunk is
+        written last, so an interrupted write never leaves a partially
+        written image which looks valid.
+    forward: first chunk first, the most intuitive order, but an
+        interrupted write can leave a valid vector table in front of
+        missing code.
+    forward-first-last: all but the first chunk in order, then the first.
+        Like reverse, the image only becomes valid once every other chunk
+        is written, while the rest of flash is programmed in address order.
+    """
+    if direction == "reverse":
+        return list(reversed(range(segments)))
+    elif direction == "forward":
+        return list(range(segments))
+    elif direction == "forward-first-last":
+        return list(range(1, segments)) + [0][:segments]
+    raise ValueError("unknown write direction {}".format(direction))
+
+
+class SectorFailures:
+    """
+    Sectors which failed to write or read back while salvaging an image
+    with continue_on_sector_error, as a dict of sector index to a tuple of
+    its start and end addresses and the first error seen in it.
+    """
+    def __init__(self, layout):
+        self.layout = layout
+        self.sectors = {}
+
+    def _sector(self, address):
+        return touched_sectors(self.layout, address, 0)[0]
+
+    def failed(self, address):
+        """Whether the sector containing address has already failed."""
+        return self._sector(address)[0] in self.sectors
+
+    def record(self, address, error):
+        idx, start, end = self._sector(address)[:3]
+        logger.warning("Skipping rest of sector %d after error: %s", idx, error)
+        self.sectors.setdefault(idx, (start, end, error))
+
+    def __len__(self):
+        return len(self.sectors)
+
+    def __str__(self):
+        return "\n".join("Sector {} ({:08X}-{:08X}) failed: {}".format(
+            idx, start, end, error) for idx, (start, end, error)
+            in sorted(self.sectors.items()))
+
+
+def write_chunks(hostname, port, chunk_size, address, data, progress=None,
+                 direction="reverse", compress=False, failures=None):
+    """
+    Write data to address in chunks of chunk_size, in the order given by
+    direction (see chunk_order), by default last chunk first.
+    If progress is given, it is called after each chunk with the number
+    of bytes written so far, the total, and the elapsed time in seconds,
+    and tqdm is not used.
+
+    If compress is set, chunks are sent compressed where that makes them
+    smaller, unless the device doesn't support compressed writes.
+
+    If failures is a SectorFailures, write errors are recorded in it and
+    the rest of the failing sector is skipped, instead of raising.
+    """
+    segments = (len(data) + chunk_size - 1) // chunk_size
+    order = chunk_order(segments, direction)
+    if progress is None:
+        order = tqdm(order, unit='kB', unit_scale=chunk_size/1024)
+    start_time = time.monotonic()
+    done = 0
+    for sidx in order:
         saddr = address + sidx*chunk_size
         sdata = data[sidx*chunk_size:(sidx+1)*chunk_size]
-        write_cmd(hostname, port, saddr, sdata)
+        if failures is not None and failures.failed(saddr):
+            done += len(sdata)
+            continue
+        written = False
+        if compress:
+            try:
+                written = write_compressed_cmd(hostname, port, saddr, sdata)
+            except BootloaderError as e:
+                if e.errno != 10:
+                    raise
+                logger.warning("Device doesn't support compressed writes, "
+                               "sending uncompressed")
+                compress = False
+        if not written:
+            try:
+                write_cmd(hostname, port, saddr, sdata)
+            except BootloaderError as e:
+                if failures is None or e.errno != 6:
+                    raise
+                failures.record(saddr, e)
+        done += len(sdata)
+        if progress is not None:
+            progress(done, len(data), time.monotonic() - start_time)
 
-    print("Writing completed successfully. Reading back...")
+
+def verify_chunks(hostname, port, chunk_size, address, data, failures=None):
+    """
+    Read back data from address in chunks of chunk_size and compare.
+    A mismatching chunk is read once more before reporting the mismatch,
+    so a transient read error isn't mistaken for a failed write.
+
+    If failures is a SectorFailures, sectors which already failed are
+    skipped, and mismatches are recorded in it instead of raised.
+    """
+    segments = (len(data) + chunk_size - 1) // chunk_size
     for sidx in tqdm(range(segments), unit='kB', unit_scale=chunk_size/1024):
         saddr = address + sidx*chunk_size
         sdata = data[sidx*chunk_size:(sidx+1)*chunk_size]
+        if failures is not None and failures.failed(saddr):
+            continue
         rdata = read_cmd(hostname, port, saddr, chunk_size)
         if sdata != rdata[:len(sdata)]:
+            rdata = read_cmd(hostname, port, saddr, chunk_size)
+            if sdata == rdata[:len(sdata)]:
+                logger.warning("Recovered from transient readback mismatch "
+                               "at %08X", saddr)
+                continue
             for idx in range(len(sdata)):
                 if sdata[idx] != rdata[idx]:
-                    raise MismatchError(saddr + idx, sdata[idx], rdata[idx])
+                    # Include up to 256 bytes of the chunk around the mismatch
+                    start = max(0, (idx - 128) & ~15)
+                    end = min(len(sdata), start + 256)
+                    context = (saddr + start, sdata[start:end],
+                               rdata[start:end])
+                    error = MismatchError(saddr + idx, sdata[idx], rdata[idx],
+                                          context)
+                    if failures is None:
+                        raise error
+                    failures.record(saddr, error)
+                    break
+
+
+def compare_image(hostname, port, chunk_size, address, data):
+    """
+    Read back flash over the range of data, padded to a word with 0xFF as
+    when programming, without modifying the device.
+
+    Returns None if the device matches, or a MismatchError giving the first
+    differing address and the surrounding bytes.
+    """
+    if not data:
+        raise ImageError("image is empty, nothing to compare")
+    try:
+        verify_chunks(hostname, port, min(chunk_size, 1024), address,
+                      pad_to_word(data))
+    except MismatchError as e:
+        return e
+    return None
+
+
+def write_file(hostname, port, chunk_size, address, data, layout=None,
+               rtt=None, check_vectors=False, allow_config=False,
+               direction="reverse", phases=None, compress=False,
+               continue_on_sector_error=False, defer_first_word=True,
+               trim_trailing_ff=True):
+    """
+    Check, erase, write and read back an image at address.
+
+    With trim_trailing_ff, trailing 0xFF bytes are erased but not written
+    or read back, instead relying on the bootloader checking the erase,
+    which it does unless built without the erase-verify feature.
+
+    If chunk_size is None, it is chosen by safe_chunk_size.
+
+    With defer_first_word, if the image covers the start of the user
+    firmware and the device supports it, the first word is only programmed
+    once everything else has been written and read back, so an interrupted
+    write never leaves an image which looks bootable.
+
+    With continue_on_sector_error, sectors which fail to write or read back
+    are skipped rather than aborting, and a SectorFailures listing them is
+    returned. This is only for salvaging or diagnosing marginal hardware:
+    the image left on the device is incomplete and must not be booted.
+    Otherwise None is returned, and any failure raises.
+    """
+    if phases is not None:
+        phases.start("check")
+    if not data:
+        raise ImageError("image is empty, nothing to program")
+    if check_vectors:
+        bases = [sector[0] for sector in layout['sectors']] if layout else []
+        report = analyze_image(data, address, bases)
+        print(report)
+        if not report.ok():
+            raise ImageError("refusing to program image with bad vectors")
+
+    erase_length = len(pad_to_word(data))
+    data, normalization = normalize_image(data, "word", trim_trailing_ff)
+    if normalization.trimmed >= 4:
+        print(normalization)
+    length = len(data)
+    if chunk_size is None:
+        chunk_size = safe_chunk_size(hostname, port)
+        print("Using {} byte chunks.".format(chunk_size))
+    segments = (length + chunk_size - 1) // chunk_size
+
+    write_estimate = None
+    if layout is not None:
+        check_image_fits(layout, address, erase_length, allow_config)
+        if rtt is not None:
+            estimate = estimate_write_time(layout, address, length,
+                                           chunk_size, rtt)
+            print("Estimated time: {:.1f}s".format(estimate))
+            write_estimate = (length // 4) * 16e-6 + segments * rtt
+    else:
+        print("Notice: device did not report its layout, "
+              "image size not checked.")
+    failures = None
+    if continue_on_sector_error:
+        if layout is None:
+            raise ImageError("device layout needed to skip failed sectors")
+        failures = SectorFailures(layout)
+    start_time = time.monotonic()
+
+    if phases is not None:
+        phases.start("erase")
+    print("Erasing (may take a few seconds)...")
+    erased = erase_cmd(hostname, port, address, erase_length, layout)
+    if erased is not None:
+        print(erased)
+        if layout is not None:
+            # Erases stop short of a sector starting just after the range
+            expected = len(touched_sectors(layout, address,
+                                           erase_length - 1))
+            if erased.sectors != expected:
+                logger.warning("Expected to erase %d sectors but device "
+                               "erased %d", expected, erased.sectors)
+
+    print("Writing {:.02f}kB in {} segments...".format(length/1024, segments))
+    if phases is not None:
+        phases.start("write")
+    deferred = None
+    if defer_first_word:
+        base = defer_first_word_cmd(hostname, port)
+        if base is not None and address <= base < address + length:
+            deferred = base - address
+        elif base is not None:
+            cancel_first_word_cmd(hostname, port)
+    progress = None if HAVE_TQDM else EtaProgress(write_estimate)
+    write_chunks(hostname, port, chunk_size, address, data, progress,
+                 direction, compress, failures)
+
+    if phases is not None:
+        phases.start("verify")
+    if failures:
+        print("Writing completed with failed sectors. Reading back...")
+    else:
+        print("Writing completed successfully. Reading back...")
+    expected = data
+    if deferred is not None:
+        # The held back word still reads as erased
+        expected = data[:deferred] + b"\xFF"*4 + data[deferred+4:]
+    verify_chunks(hostname, port, chunk_size, address, expected, failures)
+    if deferred is not None and not failures:
+        word = data[deferred:deferred+4]
+        commit_first_word_cmd(hostname, port, struct.unpack("<I", word)[0])
+        verify_chunks(hostname, port, 4, address + deferred, word)
+    if failures:
+        print("Readback finished with {} failed sectors in {:.1f}s.".format(
+            len(failures), time.monotonic() - start_time))
+    else:
+        print("Readback successful, completed in {:.1f}s.".format(
+            time.monotonic() - start_time))
+    return failures
+
+
+class ProgramReport:
+    """
+    Result of program_safely: the phase that failed (None on success),
+    the exception it failed with, the time taken by each phase, and any
+    sectors skipped with continue_on_sector_error.
+    """
+    def __init__(self):
+        self.failed_sectors = None
+        self.phase = None
+        self.error = None
+        self.times = []
+        self._started = None
+
+    def start(self, phase):
+        self._finish_phase()
+        self.phase = phase
+        self._started = time.monotonic()
+
+    def _finish_phase(self):
+        if self.phase is not None:
+            self.times.append((self.phase, time.monotonic() - self._started))
+
+    def finish(self, error=None):
+        self.error = error
+        if error is None:
+            self._finish_phase()
+            self.phase = None
+
+    def ok(self):
+        return self.error is None
+
+    def __str__(self):
+        lines = ["{}: {:.1f}s".format(phase, t) for (phase, t) in self.times]
+        if self.ok():
+            lines.append("Programming successful")
+        else:
+            lines.append("Failed during {}: {}".format(self.phase, self.error))
+            if self.failed_sectors:
+                lines.append(str(self.failed_sectors))
+            lines.append("Device left in bootloader mode")
+        return "\n".join(lines)
+
+
+def program_safely(hostname, port, chunk_size, address, data, layout=None,
+                   rtt=None, boot=True, trial=False, expected_mcu_id=None,
+                   **kwargs):
+    """
+    Check, erase, write and read back an image, then boot it only if every
+    step succeeded. With trial=True the image is booted on trial, so the
+    bootloader runs again unless the new firmware confirms itself.
+    With expected_mcu_id, nothing is done unless the device reports that
+    MCU ID, so the wrong board is never programmed.
+
+    Returns a ProgramReport instead of raising for device, connection and
+    image errors; on failure the device is never told to boot, so it stays
+    in bootloader mode. Other keyword arguments are passed to write_file.
+    """
+    report = ProgramReport()
+    try:
+        if expected_mcu_id is not None:
+            report.start("identify")
+            check_mcu_id(info_cmd(hostname, port), expected_mcu_id)
+        failures = write_file(hostname, port, chunk_size, address, data,
+                              layout, rtt, phases=report, **kwargs)
+        if failures:
+            # The image is incomplete, so must never be booted
+            report.failed_sectors = failures
+            raise ImageError("{} sectors failed, image is incomplete".format(
+                len(failures)))
+        if boot:
+            report.start("boot")
+            if trial:
+                print("Sending trial reboot command...")
+                trial_boot_cmd(hostname, port)
+            else:
+                print("Sending reboot command...")
+                boot_cmd(hostname, port)
+    except (OSError, BootloaderError, MismatchError, ImageError,
+            ValueError, WrongDevice) as e:
+        report.finish(e)
+    else:
+        report.finish()
+    return report
+
+
+def write_segments(hostname, port, chunk_size, segments, layout=None,
+                   allow_config=False, direction="reverse"):
+    """
+    Write an image made up of separate (address, data) segments, such as
+    from a HEX or ELF file, erasing and writing only the sectors covered by
+    each segment instead of filling the gaps between them with 0xFF.
+
+    All segments are erased before any are written, so segments may share
+    a sector, but they must not overlap.
+    """
+    segments = sorted((address, pad_to_word(data))
+                      for address, data in segments if data)
+    if not segments:
+        raise ImageError("image is empty, nothing to program")
+    for (addr1, data1), (addr2, _) in zip(segments, segments[1:]):
+        if addr1 + len(data1) > addr2:
+            raise ImageError("segments at {:08X} and {:08X} overlap".format(
+                addr1, addr2))
+    if layout is not None:
+        for address, data in segments:
+            check_image_fits(layout, address, len(data), allow_config)
+
+    print("Erasing (may take a few seconds)...")
+    for address, data in segments:
+        erase_cmd(hostname, port, address, len(data), layout)
+
+    for address, data in segments:
+        print("Writing {:.02f}kB at {:08X}...".format(len(data)/1024, address))
+        write_chunks(hostname, port, chunk_size, address, data,
+                     direction=direction)
+
+    print("Writing completed successfully. Reading back...")
+    for address, data in segments:
+        verify_chunks(hostname, port, chunk_size, address, data)
     print("Readback successful.")
 
 
-def write_config(hostname, port, address, mac, ip, gw, prefix):
-    magic_bytes = struct.pack("<I", 0x67797870)
+def read_bootloader(hostname, port, layout=None, chunk_size=1024):
+    """
+    Read back the bootloader's own flash, from the start of flash up to the
+    configuration sector, so the installed image can be compared
+    byte-for-byte against a known build.
+    """
+    if layout is not None:
+        start, end = layout['sectors'][0][0], layout['config']
+    else:
+        start, end = 0x08000000, 0x0800C000
+    data = b""
+    for saddr in tqdm(range(start, end, chunk_size),
+                      unit='kB', unit_scale=chunk_size/1024):
+        data += read_cmd(hostname, port, saddr, min(chunk_size, end - saddr))
+    return data
 
-    mac_bytes = [int(x, 16) for x in mac.split(":")]
-    mac_bytes = struct.pack("BBBBBB", *mac_bytes)
 
-    ip_bytes = [int(x) for x in ip.split(".")]
-    ip_bytes = struct.pack("BBBB", *ip_bytes)
+def config_address(layout, address=None, force=False):
+    """
+    Return the address of the device's configuration sector, from its
+    layout if it reported one. If an address is given it is returned
+    instead, but only if it matches or force is set, as a configuration
+    stored anywhere else would never be read.
+    """
+    expected = DEFAULT_CONFIG_ADDRESS if layout is None else layout['config']
+    if address is None:
+        return expected
+    if address != expected and not force:
+        raise ValueError(
+            "{:08X} is not the configuration sector at {:08X}, so the "
+            "device would never read a configuration stored there; use "
+            "--force to write it anyway".format(address, expected))
+    return address
 
-    gw_bytes = [int(x) for x in gw.split(".")]
-    gw_bytes = struct.pack("BBBB", *gw_bytes)
 
-    prefix_bytes = struct.pack("B", prefix)
+def set_config_cmd(hostname, port, config_bytes):
+    """
+    Store a configuration in the device's configuration sector, wherever it
+    is. The device sets the magic and checksum, so the final checksum word
+    of config_bytes isn't sent.
+    """
+    cmd = struct.pack("<I", commands['set_config']) + config_bytes[:-4]
+    interact(hostname, port, cmd, timeout=5)
 
-    padding_bytes = struct.pack("B", 0)
 
-    config_bytes = magic_bytes + mac_bytes + ip_bytes + gw_bytes + prefix_bytes
-    config_bytes += padding_bytes
+# Wait after erasing the configuration before connecting again, and how many
+# times to retry a connection refused while the device recovers from it.
+CONFIG_SETTLE = 0.2
+CONFIG_RETRIES = 5
+
+
+def retry_refused(step, func, *args):
+    """
+    Call func(*args), retrying up to CONFIG_RETRIES times, CONFIG_SETTLE
+    seconds apart, if the connection is refused or reset. Some devices
+    briefly stop accepting connections after their configuration sector is
+    erased.
+    """
+    for attempt in range(CONFIG_RETRIES + 1):
+        try:
+            return func(*args)
+        except (ConnectionRefusedError, ConnectionResetError) as e:
+            if attempt == CONFIG_RETRIES:
+                raise
+            print("{} failed: {}, retrying in {:.1f}s ({} of {})...".format(
+                step, e, CONFIG_SETTLE, attempt + 1, CONFIG_RETRIES))
+            time.sleep(CONFIG_SETTLE)
 
-    crc32 = crcmod.predefined.mkCrcFun('crc-32-mpeg')
-    u32 = struct.unpack(">5I", config_bytes)
-    raw = struct.pack("<5I", *u32)
-    crc = crc32(raw)
-    crc_bytes = struct.pack("<I", crc)
-    config_bytes += crc_bytes
 
-    print("Erasing old configuration...")
-    erase_cmd(hostname, port, address, len(config_bytes))
+def write_config_raw(hostname, port, address, blob, layout=None,
+                     verify=True, force=False):
+    """
+    Write a raw configuration blob, checking only its magic and replacing
+    its final word with a freshly computed checksum of the preceeding bytes.
+    This allows configurations with a newer layout than ConfigFields
+    understands to be restored.
 
-    print("Writing new configuration...")
-    write_cmd(hostname, port, address, config_bytes)
+    address may be None to use the device's configuration sector, and
+    any other address is refused unless force is set.
+    If verify is set, the blob is read back and compared byte-for-byte.
+
+    A blob of the current layout is stored with the set config command where
+    the device has it, as a device with a key for signed commands refuses to
+    erase the configuration sector directly, which would remove the key.
+    """
+    address = config_address(layout, address, force)
+    if len(blob) < 8 or len(blob) % 4 != 0:
+        raise ValueError("configuration length must be a multiple of 4")
+    if struct.unpack("<I", blob[:4])[0] != CONFIG_MAGIC:
+        raise ValueError("configuration has bad magic")
+    config_bytes = blob[:-4] + struct.pack("<I", config_crc(blob[:-4]))
+
+    stored = False
+    if address == config_address(layout) and len(config_bytes) == 24:
+        try:
+            print("Storing new configuration...")
+            set_config_cmd(hostname, port, config_bytes)
+            stored = True
+        except BootloaderError as e:
+            if e.errno != 10:
+                raise
+    if not stored:
+        print("Erasing old configuration...")
+        erase_cmd(hostname, port, address, len(config_bytes), layout)
+
+        time.sleep(CONFIG_SETTLE)
+        print("Writing new configuration...")
+        retry_refused("Write", write_cmd, hostname, port, address,
+                      config_bytes)
+    if not verify:
+        return
 
     print("Reading back new configuration...")
-    rdata = read_cmd(hostname, port, address, len(config_bytes))
+    rdata = retry_refused("Readback", read_cmd, hostname, port, address,
+                          len(config_bytes))
 
     if config_bytes != rdata:
         for idx in range(len(config_bytes)):
@@ -205,34 +1880,354 @@ def write_config(hostname, port, address, mac, ip, gw, prefix):
     print("Readback successful.")
 
 
+CONFIG_VALID = "valid"
+CONFIG_BLANK = "blank"
+CONFIG_BAD_MAGIC = "corrupt (bad magic)"
+CONFIG_BAD_CHECKSUM = "corrupt (bad checksum)"
+
+
+def config_state(blob):
+    """
+    Classify a raw configuration blob. A blank (erased) configuration means
+    the device was never configured or a write was interrupted after erasing.
+    """
+    if blob == b"\xFF" * len(blob):
+        return CONFIG_BLANK
+    if struct.unpack("<I", blob[:4])[0] != CONFIG_MAGIC:
+        return CONFIG_BAD_MAGIC
+    if struct.unpack("<I", blob[-4:])[0] != config_crc(blob[:-4]):
+        return CONFIG_BAD_CHECKSUM
+    return CONFIG_VALID
+
+
+def get_config_cmd(hostname, port):
+    """
+    Read the stored configuration blob from the device's configuration
+    sector, whatever its length. Returns None if the device is too old to
+    support this, so the blob must be read from its address instead.
+    """
+    cmd = struct.pack("<I", commands['get_config'])
+    try:
+        data = interact(hostname, port, cmd)
+    except BootloaderError as e:
+        if e.errno != 10:
+            raise
+        return None
+    length = struct.unpack("<I", data[:4])[0]
+    return data[4:4+length]
+
+
+def read_config_raw(hostname, port, address=None, length=None):
+    """
+    Read a raw configuration blob of the given length from address, or by
+    default the stored configuration from the configuration sector.
+    Returns the blob and its state, one of the CONFIG_ constants.
+    """
+    blob = None
+    if address is None and length is None:
+        blob = get_config_cmd(hostname, port)
+    if blob is None:
+        if address is None:
+            address = config_address(get_layout(hostname, port))
+        blob = read_cmd(hostname, port, address, length or 24)
+    return blob, config_state(blob)
+
+
+def get_config(hostname, port, address=None):
+    """
+    Read the device configuration.
+    Returns its state, one of the CONFIG_ constants, and the ConfigFields
+    if the state is CONFIG_VALID or None otherwise.
+    """
+    blob, state = read_config_raw(hostname, port, address)
+    if state != CONFIG_VALID:
+        return state, None
+    return state, ConfigFields.from_bytes(blob)
+
+
+def compare_config(expected, actual):
+    """
+    Compare two configuration blobs field by field.
+    Raises ConfigMismatchError naming the first field which differs.
+    """
+    names = ("magic", "MAC address", "IP address", "gateway", "prefix",
+             "flags", "checksum")
+    expected = struct.unpack("<I6s4s4sBBI", expected[:24])
+    actual = struct.unpack("<I6s4s4sBBI", actual[:24])
+    for name, exp, act in zip(names, expected, actual):
+        if exp != act:
+            raise ConfigMismatchError(name, exp, act)
+
+
+def device_inventory(hostname, port):
+    """
+    Gather the identity of one device: its MCU ID, firmware version and
+    configuration. Errors are recorded in the "error" field.
+    """
+    entry = {"host": hostname, "mcu_id": None, "version": None,
+             "git_version": None, "config_state": None, "config": None,
+             "error": None}
+    try:
+        info = parse_info(info_cmd(hostname, port))
+        entry["mcu_id"] = info.get("mcu_id")
+        entry["version"] = info["version"]
+        entry["git_version"] = info["git_version"]
+        state, fields = get_config(hostname, port)
+        entry["config_state"] = state
+        entry["config"] = str(fields) if fields is not None else None
+    except (OSError, BootloaderError, ValueError) as e:
+        entry["error"] = str(e)
+    return entry
+
+
+def take_inventory(hostnames, port, workers=8):
+    """Gather device_inventory for each host concurrently, in order."""
+    with ThreadPoolExecutor(max_workers=workers) as pool:
+        return list(pool.map(lambda h: device_inventory(h, port), hostnames))
+
+
+def write_config(hostname, port, address, fields, layout=None, force=False,
+                 verify=True):
+    """
+    Write configuration fields, then if verify is set, check them by reading
+    back the stored configuration and comparing each field, so padding bytes
+    are ignored.
+
+    address may be None to use the device's configuration sector, and
+    any other address is refused unless force is set.
+    """
+    address = config_address(layout, address, force)
+    config_bytes = fields.to_bytes()
+    stored = False
+    if address == config_address(layout):
+        try:
+            print("Storing new configuration...")
+            set_config_cmd(hostname, port, config_bytes)
+            stored = True
+        except BootloaderError as e:
+            if e.errno != 10:
+                raise
+    if not stored:
+        write_config_raw(hostname, port, address, config_bytes, layout,
+                         verify=False, force=force)
+    if not verify:
+        return
+
+    print("Reading back new configuration...")
+    rdata = retry_refused("Readback", read_cmd, hostname, port, address,
+                          len(config_bytes))
+    compare_config(config_bytes, rdata)
+    print("Readback successful.")
+
+
+def arp_cache_mac(ip):
+    """
+    Return the MAC address the kernel's ARP cache holds for ip, or None.
+    Only available on Linux, through /proc/net/arp.
+    """
+    try:
+        with open("/proc/net/arp") as f:
+            lines = f.readlines()[1:]
+    except OSError:
+        return None
+    for line in lines:
+        words = line.split()
+        # Incomplete entries, with flags 0x0, are for hosts which didn't reply
+        if len(words) >= 4 and words[0] == ip and int(words[2], 16) & 0x2:
+            return words[3].upper()
+    return None
+
+
+def probe_ip(ip, port, timeout=1.0):
+    """
+    Check whether something already answers at ip, before giving that
+    address to a device. Returns a description of what answered, or None.
+
+    A TCP connection to port counts whether it is accepted or refused, then
+    the system ping command is tried. On Linux, the kernel's ARP cache is
+    checked afterwards, which finds hosts on the local subnet ignoring both
+    without the privileges needed to send ARP requests directly.
+    """
+    answer = None
+    try:
+        socket.create_connection((ip, port), timeout).close()
+        answer = "accepted a connection on port {}".format(port)
+    except ConnectionRefusedError:
+        answer = "refused a connection on port {}".format(port)
+    except OSError:
+        pass
+    if answer is None:
+        try:
+            ping = subprocess.run(
+                ["ping", "-c", "1", "-W", str(max(1, int(timeout))), ip],
+                stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+            if ping.returncode == 0:
+                answer = "answered a ping"
+        except OSError:
+            pass
+    mac = arp_cache_mac(ip)
+    if mac is not None:
+        answer = "{} from MAC {}".format(answer or "answered ARP", mac)
+    return answer
+
+
+def reconfigure_and_follow(hostname, port, fields, layout=None, timeout=30,
+                           verify=True):
+    """
+    Write configuration fields, reset the device back into the bootloader
+    so it uses them, and wait for it to answer at its new IP address.
+    The device must answer with the same MCU ID, so another device already
+    using that address isn't mistaken for it.
+
+    verify is passed on to write_config.
+
+    Returns the new address. Raises FollowError if this host has no route
+    to the new address, or the device doesn't answer there within timeout
+    seconds, which usually means the new subnet isn't reachable from here.
+    """
+    mcu_id = parse_info(info_cmd(hostname, port)).get("mcu_id")
+    new_hostname = ".".join(str(x) for x in fields.ip)
+    try:
+        probe = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        probe.connect((new_hostname, port))
+        probe.close()
+    except OSError as e:
+        raise FollowError("no route from this host to {}: {}".format(
+            new_hostname, e))
+
+    write_config(hostname, port, None, fields, layout, verify=verify)
+    print("Rebooting into bootloader...")
+    reboot_cmd(hostname, port)
+
+    deadline = time.monotonic() + timeout
+    while time.monotonic() < deadline:
+        try:
+            info = parse_info(info_cmd(new_hostname, port))
+        except (OSError, BootloaderError):
+            time.sleep(0.5)
+            continue
+        if info.get("mcu_id") != mcu_id:
+            raise FollowError("a different device, MCU ID {}, answered at "
+                              "{}".format(info.get("mcu_id"), new_hostname))
+        print("Device is now at", new_hostname)
+        return new_hostname
+    raise FollowError(
+        "device didn't answer at {} within {}s; check this host can reach "
+        "{}/{} through gateway {}".format(
+            new_hostname, timeout, new_hostname, fields.prefix,
+            ".".join(str(x) for x in fields.gateway)))
+
+
 def main():
     parser = argparse.ArgumentParser()
     parser.add_argument("hostname", help="IP address/hostname of bootloader")
     parser.add_argument("--port", type=int, default=7777,
                         help="bootloader port, default 7777")
+    parser.add_argument("--emergency-port", type=int, default=7778,
+                        help="bootloader emergency port, tried when the main "
+                             "port fails for info, configure, config and "
+                             "boot, default 7778")
+    parser.add_argument("--expect-version",
+                        help="refuse to continue unless the bootloader "
+                             "reports this version or git version")
+    parser.add_argument("--expect-id",
+                        help="refuse to continue unless the bootloader "
+                             "reports this MCU ID")
+    parser.add_argument("--inventory", type=argparse.FileType('r'),
+                        help="json inventory to take --expect-id from, "
+                             "using the entry for this hostname")
+    parser.add_argument("--key-file", type=argparse.FileType('r'),
+                        help="file holding the device's key as 64 hex "
+                             "digits, to sign commands which change it")
     parser.add_argument("--boot-req", action='store_true',
                         help="send an initial boot request to user firmware")
     parser.add_argument("--boot-req-port", type=int, default=1735,
                         help="UDP port for boot request, default 1735")
     parser.add_argument("--no-reboot", action='store_true',
                         help="don't send a reboot request after completion")
-    parser.add_argument("--chunk-size", type=int, default=512,
-                        help="Size of chunks to write to flash, default 512")
+    parser.add_argument("--chunk-size", type=int, default=None,
+                        help="Size of chunks to write to flash, default "
+                             "chosen by probing the link, or 512")
+    parser.add_argument("--trial", action='store_true',
+                        help="boot user firmware on trial, returning to the "
+                             "bootloader on the next reset unless it "
+                             "confirms it is healthy")
+    parser.add_argument("--capture", type=argparse.FileType('wb'),
+                        help="record every exchange with the bootloader "
+                             "to this file, for later replay")
+    parser.add_argument("--capture-hash", action='store_true',
+                        help="store hashes of payloads in the capture file "
+                             "instead of the data itself")
+    parser.add_argument("--trace", action='store_true',
+                        help="log the bytes of every command and response")
     subparsers = parser.add_subparsers(dest="command")
     subparsers.required = True
     subparsers.add_parser(
         "info", help="Just read bootloader information without rebooting")
     parser_program = subparsers.add_parser(
         "program", help="Bootload new firmware image")
-    parser_program.add_argument("--lma", default=0x08010000,
+    parser_program.add_argument("--lma", type=lambda x: int(x, 0),
+                                default=0x08010000,
                                 help="address to load to, default 0x08010000")
+    parser_program.add_argument("--check-vectors", action='store_true',
+                                help="refuse to program an image whose "
+                                     "vector table doesn't match --lma")
+    parser_program.add_argument("--allow-config-overlap", action='store_true',
+                                help="allow the image to overwrite the "
+                                     "configuration sector")
+    parser_program.add_argument("--write-direction", choices=WRITE_DIRECTIONS,
+                                default="reverse",
+                                help="order to write chunks in, "
+                                     "default reverse")
+    parser_program.add_argument("--compress", action='store_true',
+                                help="send chunks LZ4 compressed, if the "
+                                     "device supports it")
+    parser_program.add_argument("--continue-on-sector-error",
+                                action='store_true',
+                                help="for salvaging marginal hardware only: "
+                                     "skip sectors which fail to write and "
+                                     "list them, leaving an incomplete "
+                                     "image which is never booted")
+    parser_program.add_argument("--no-defer-first-word", action='store_true',
+                                help="program the first word of the image "
+                                     "with the rest, instead of only once "
+                                     "it has all been read back")
     parser_program.add_argument("binfile", type=argparse.FileType('rb'),
                                 help="raw binary file to program")
+    parser_compare = subparsers.add_parser(
+        "compare", help="Check the device flash matches an image, "
+                        "without reprogramming")
+    parser_compare.add_argument("--lma", type=lambda x: int(x, 0),
+                                default=0x08010000,
+                                help="address image is loaded at, "
+                                     "default 0x08010000")
+    parser_compare.add_argument("binfile", type=argparse.FileType('rb'),
+                                help="raw binary file to compare with")
+    parser_usage = subparsers.add_parser(
+        "usage", help="Show which sectors an image would occupy, without "
+                      "programming it")
+    parser_usage.add_argument("--lma", type=lambda x: int(x, 0),
+                              default=0x08010000,
+                              help="address image is loaded at, "
+                                   "default 0x08010000")
+    parser_usage.add_argument("binfile", type=argparse.FileType('rb'),
+                              help="raw binary file to show")
     parser_configure = subparsers.add_parser(
         "configure", help="Load new configuration")
     parser_configure.add_argument(
-        "--lma", default=0x0800C000,
-        help="address to write to, default 0x0800C000")
+        "--lma", type=lambda x: int(x, 0),
+        help="address to write to, default the configuration sector")
+    parser_configure.add_argument(
+        "--force", action='store_true',
+        help="write to --lma even if it isn't the configuration sector")
+    parser_configure.add_argument(
+        "--follow", action='store_true',
+        help="reboot into the bootloader and wait for it at the new "
+             "IP address, instead of booting the user firmware")
+    parser_configure.add_argument(
+        "--no-ip-check", action='store_true',
+        help="don't check whether something already answers at the new "
+             "IP address")
     parser_configure.add_argument(
         "mac_address", help="MAC address, in format XX:XX:XX:XX:XX:XX")
     parser_configure.add_argument(
@@ -241,39 +2236,327 @@ def main():
         "gateway_address", help="Gateway address, in format XXX.XXX.XXX.XXX")
     parser_configure.add_argument(
         "prefix_length", type=int, help="Subnet prefix length")
+    parser_set_mac = subparsers.add_parser(
+        "set-mac", help="Change only the MAC address in the configuration")
+    parser_set_mac.add_argument(
+        "mac_address", help="MAC address, in format XX:XX:XX:XX:XX:XX")
+    subparsers.add_parser(
+        "lock", help="Refuse erases, writes and configuration changes "
+                     "until unlocked, to avoid accidental reflashing")
+    parser_set_key = subparsers.add_parser(
+        "set-key", help="Store the key which commands changing the device "
+                        "must then be signed with, for bootloaders built "
+                        "with the auth feature")
+    parser_set_key.add_argument(
+        "new_key_file", nargs="?", type=argparse.FileType('r'),
+        help="file holding the new key as 64 hex digits")
+    parser_set_key.add_argument(
+        "--clear", action='store_true',
+        help="remove the key, so commands needn't be signed")
+    parser_unlock = subparsers.add_parser(
+        "unlock", help="Allow flashing a locked device until it resets")
+    parser_unlock.add_argument(
+        "--permanent", action='store_true',
+        help="also remove the lock from the configuration")
+    parser_config = subparsers.add_parser(
+        "config", help="Export or import raw configuration")
+    config_subparsers = parser_config.add_subparsers(dest="config_command")
+    config_subparsers.required = True
+    parser_export = config_subparsers.add_parser(
+        "export", help="Save raw configuration to a file")
+    parser_export.add_argument(
+        "--lma", type=lambda x: int(x, 0),
+        help="address to read from, default the configuration sector")
+    parser_export.add_argument(
+        "--length", type=int,
+        help="length of configuration, default the stored length")
+    parser_export.add_argument("outfile", type=argparse.FileType('wb'),
+                               help="file to save configuration to")
+    parser_show = config_subparsers.add_parser(
+        "show", help="Show whether the configuration is valid, and its fields")
+    parser_show.add_argument(
+        "--lma", type=lambda x: int(x, 0),
+        help="address to read from, default the configuration sector")
+    parser_import = config_subparsers.add_parser(
+        "import", help="Load raw configuration from a file")
+    parser_import.add_argument(
+        "--lma", type=lambda x: int(x, 0),
+        help="address to write to, default the configuration sector")
+    parser_import.add_argument(
+        "--force", action='store_true',
+        help="write to --lma even if it isn't the configuration sector")
+    parser_import.add_argument("infile", type=argparse.FileType('rb'),
+                               help="file to load configuration from")
+    parser_dump = subparsers.add_parser(
+        "dump-bootloader", help="Save the installed bootloader to a file")
+    parser_dump.add_argument("outfile", type=argparse.FileType('wb'),
+                             help="file to save bootloader image to")
     subparsers.add_parser("boot", help="Send immediate reboot request")
+    subparsers.add_parser(
+        "selftest", help="Run the device's non-destructive self test")
+    subparsers.add_parser(
+        "status", help="Show a snapshot of the device status")
+    subparsers.add_parser(
+        "log", help="Show the bootloader's recent log output")
+    subparsers.add_parser(
+        "meminfo", help="Show the device's RAM and buffer use")
+    parser_diagnostics = subparsers.add_parser(
+        "diagnostics", help="Show the device's error counters")
+    parser_diagnostics.add_argument(
+        "--reset", action='store_true', help="clear the counters after reading")
+    parser_inventory = subparsers.add_parser(
+        "inventory", help="List the identity, firmware version and "
+                          "configuration of this and further devices")
+    parser_inventory.add_argument("--format", choices=("json", "csv"),
+                                  default="json",
+                                  help="output format, default json")
+    parser_inventory.add_argument("--output", type=argparse.FileType('w'),
+                                  default=sys.stdout,
+                                  help="file to write to, default stdout")
+    parser_inventory.add_argument("hosts", nargs="*",
+                                  help="further devices to include")
+    parser_replay = subparsers.add_parser(
+        "replay", help="Re-send a captured session and compare responses")
+    parser_replay.add_argument("capfile", type=argparse.FileType('rb'),
+                               help="capture file recorded with --capture")
     args = parser.parse_args()
     cmd = args.command
 
+    if args.trace:
+        logging.basicConfig(format="%(message)s", level=logging.DEBUG)
+
+    global capture
+    if args.capture is not None:
+        capture = CaptureLog(args.capture, args.capture_hash)
+
+    global auth_key
     try:
+        if args.key_file is not None:
+            auth_key = read_key_file(args.key_file)
+
         if args.boot_req:
             boot_request(args.hostname, args.boot_req_port, args.port)
 
+        if args.inventory is not None and args.expect_id is None:
+            args.expect_id = inventory_mcu_id(args.inventory, args.hostname)
+            if args.expect_id is None:
+                print("{} isn't in the inventory.".format(args.hostname))
+                sys.exit(1)
+
+        if cmd == "inventory":
+            entries = take_inventory([args.hostname] + args.hosts, args.port)
+            if args.format == "json":
+                json.dump(entries, args.output, indent=2)
+                args.output.write("\n")
+            else:
+                writer = csv.DictWriter(args.output, list(entries[0]))
+                writer.writeheader()
+                writer.writerows(entries)
+            return
+
+        if cmd == "replay":
+            differences = replay(args.hostname, args.port, args.capfile)
+            print("Replay complete, {} responses differed.".format(
+                differences))
+            return
+
+        modifies = cmd in ("program", "configure") or (
+            cmd == "config" and args.config_command == "import")
+
         print("Connecting to bootloader...")
-        info = info_cmd(args.hostname, args.port)
+        want_layout = modifies or cmd in ("dump-bootloader", "usage")
+        emergency = False
+        try:
+            report = preflight_check(args.hostname, args.port, want_layout)
+        except OSError as e:
+            # Firmware built with the emergency-port feature still accepts
+            # info, set config and reboot on a second port, enough to give
+            # a device a new configuration.
+            if cmd not in ("info", "configure"):
+                raise
+            print("Connection error: {}, trying emergency port {}...".format(
+                e, args.emergency_port))
+            args.port = args.emergency_port
+            emergency = True
+            report = preflight_check(args.hostname, args.port, False)
         print("Received bootloader information:")
-        print(info.decode())
+        print(report.info.decode())
+        conflict = parse_info(report.info).get("ip_conflict")
+        if conflict not in (None, "none"):
+            print("Warning: another host, MAC {}, is also using this IP "
+                  "address".format(conflict))
+        if args.expect_version is not None:
+            check_version(report.info, args.expect_version)
+        if args.expect_id is not None:
+            check_mcu_id(report.info, args.expect_id)
+        layout = report.layout
+        rtt = report.rtt
 
         if cmd == "program":
+            print(report)
             bindata = args.binfile.read()
-            write_file(args.hostname, args.port, args.chunk_size, args.lma,
-                       bindata)
+            result = program_safely(
+                args.hostname, args.port, args.chunk_size, args.lma, bindata,
+                layout, rtt, boot=not args.no_reboot, trial=args.trial,
+                check_vectors=args.check_vectors,
+                allow_config=args.allow_config_overlap,
+                direction=args.write_direction, compress=args.compress,
+                continue_on_sector_error=args.continue_on_sector_error,
+                defer_first_word=not args.no_defer_first_word)
+            print(result)
+            if isinstance(result.error, MismatchError):
+                print(result.error.hexdump())
+            if (isinstance(result.error, BootloaderError) and
+                    result.error.errno == 12):
+                print(WRITE_PROTECT_HELP)
+            if not result.ok():
+                sys.exit(1)
+        elif cmd == "usage":
+            if layout is None:
+                print("Device did not report its layout.")
+                sys.exit(1)
+            length = len(args.binfile.read())
+            check_image_fits(layout, args.lma, length, allow_config=True)
+            print(format_image_layout(image_layout(layout, args.lma, length)))
+        elif cmd == "compare":
+            mismatch = compare_image(args.hostname, args.port,
+                                     args.chunk_size or DEFAULT_CHUNK_SIZE,
+                                     args.lma,
+                                     args.binfile.read())
+            if mismatch is None:
+                print("Device flash matches image.")
+            else:
+                print("Device flash differs:", mismatch)
+                print(mismatch.hexdump())
+                sys.exit(1)
         elif cmd == "configure":
-            write_config(args.hostname, args.port, args.lma,
-                         args.mac_address, args.ip_address,
-                         args.gateway_address, args.prefix_length)
+            fields = ConfigFields.parse(
+                args.mac_address, args.ip_address, args.gateway_address,
+                args.prefix_length)
+            # Keep the device locked if it was. The emergency port can't
+            # read the configuration, nor read it back once stored.
+            current = None
+            if not emergency:
+                _, current = get_config(args.hostname, args.port)
+            if current is not None:
+                fields.flags = current.flags
+            new_ip = ".".join(str(x) for x in fields.ip)
+            # The device itself answers at its current address
+            moving = new_ip != socket.gethostbyname(args.hostname) and (
+                current is None or current.ip != fields.ip)
+            if moving and not args.no_ip_check:
+                print("Checking nothing else is using {}...".format(new_ip))
+                answer = probe_ip(new_ip, args.port)
+                if answer is not None:
+                    print("IP address {} is already in use: {}".format(
+                        new_ip, answer))
+                    print("Use --no-ip-check to configure it anyway.")
+                    sys.exit(1)
+            if args.follow:
+                reconfigure_and_follow(args.hostname, args.port, fields,
+                                       layout, verify=not emergency)
+                return
+            write_config(args.hostname, args.port, args.lma, fields, layout,
+                         args.force, verify=not emergency)
+        elif cmd == "lock":
+            set_lock(args.hostname, args.port, True)
+            print("Flashing locked.")
+        elif cmd == "set-key":
+            if args.clear == (args.new_key_file is not None):
+                print("Give either a new key file or --clear.")
+                sys.exit(1)
+            key = None if args.clear else read_key_file(args.new_key_file)
+            set_key_cmd(args.hostname, args.port, key)
+            print("Key removed." if key is None else "Key stored.")
+        elif cmd == "unlock":
+            if args.permanent:
+                set_lock(args.hostname, args.port, False)
+                print("Flashing unlocked.")
+            else:
+                unlock_cmd(args.hostname, args.port)
+                print("Flashing unlocked until the device resets.")
+        elif cmd == "set-mac":
+            set_mac_cmd(args.hostname, args.port, args.mac_address)
+            print("MAC address set to", args.mac_address.upper())
+        elif cmd == "config" and args.config_command == "export":
+            blob, state = read_config_raw(args.hostname, args.port, args.lma,
+                                          args.length)
+            if state != CONFIG_VALID:
+                print("Warning: configuration is", state)
+            args.outfile.write(blob)
+            print("Saved {} bytes of configuration.".format(len(blob)))
+        elif cmd == "status":
+            print(status_cmd(args.hostname, args.port))
+        elif cmd == "diagnostics":
+            for name, value in diagnostics_cmd(args.hostname, args.port,
+                                               args.reset):
+                print("{}: {}".format(name, value))
+        elif cmd == "meminfo":
+            for name, value in mem_info_cmd(args.hostname, args.port):
+                print("{}: {}".format(name, value))
+        elif cmd == "log":
+            print(get_log_cmd(args.hostname, args.port), end="")
+        elif cmd == "selftest":
+            result = self_test_cmd(args.hostname, args.port)
+            for (_, name, description) in SELF_TEST_CHECKS:
+                print("{:<10} {:<4}  {}".format(
+                    name, "ok" if result[name] else "FAIL", description))
+            print("Configuration is", result["config_state"])
+            print("PHY ID {:08X}, flash size {}kB, bootloader CRC {:08X}"
+                  .format(result["phy_id"], result["flash_size_kb"],
+                          result["bootloader_crc"]))
+            if result["failures"]:
+                sys.exit(1)
+        elif cmd == "dump-bootloader":
+            data = read_bootloader(args.hostname, args.port, layout)
+            args.outfile.write(data)
+            print("Saved {} bytes of bootloader.".format(len(data)))
+        elif cmd == "config" and args.config_command == "show":
+            state, fields = get_config(args.hostname, args.port, args.lma)
+            print("Configuration is", state)
+            if fields is not None:
+                print(fields)
+        elif cmd == "config" and args.config_command == "import":
+            write_config_raw(args.hostname, args.port, args.lma,
+                             args.infile.read(), layout, force=args.force)
 
-        if cmd == "boot" or (not args.no_reboot and cmd != "info"):
-            print("Sending reboot command...")
-            boot_cmd(args.hostname, args.port)
+        if cmd == "boot" or (cmd != "program" and not args.no_reboot
+                             and modifies):
+            if emergency:
+                # The emergency port can only reset into the bootloader,
+                # which is enough to use the new configuration
+                print("Rebooting into bootloader...")
+                reboot_cmd(args.hostname, args.port)
+            elif args.trial:
+                print("Sending trial reboot command...")
+                trial_boot_cmd(args.hostname, args.port)
+            else:
+                print("Sending reboot command...")
+                boot_cmd(args.hostname, args.port)
 
     except OSError as e:
         print("Connection error:", e)
         print("Check hostname is correct and device is in bootloader mode.")
     except BootloaderError as e:
         print("Bootloader error:", e)
+        if e.errno == 12:
+            print(WRITE_PROTECT_HELP)
     except MismatchError as e:
         print("Mismatch error:", e)
+        if e.context is not None:
+            print(e.hexdump())
+    except ConfigMismatchError as e:
+        print("Mismatch error:", e)
+    except ValueError as e:
+        print("Configuration error:", e)
+    except ImageError as e:
+        print("Image error:", e)
+    except VersionMismatch as e:
+        print("Version mismatch:", e)
+    except WrongDevice as e:
+        print("Wrong device:", e)
+    except FollowError as e:
+        print("Lost device:", e)
 
 
 if __name__ == "__main__":
@@ -1,5 +1,10 @@
 MEMORY
 {
-    FLASH : ORIGIN = 0x08000000, LENGTH = 48K
-    RAM :   ORIGIN = 0x20000008, LENGTH = 131064
+    /* The last 16 bytes before the configuration sector hold the auth key marker */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 48K - 16
+    RAM :   ORIGIN = 0x2000001C, LENGTH = 131044
 }
+
+/* The initial values of .data follow the code in flash, which the linker doesn't check */
+ASSERT(__sidata + SIZEOF(.data) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       "bootloader too large for FLASH, the .data initialisers overlap the config sector");
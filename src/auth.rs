@@ -0,0 +1,99 @@
+//! Authentication of commands which change the device, with the auth feature.
+//!
+//! Once a key has been stored, each such command must end with an HMAC-SHA-256 tag, keyed
+//! with it, over a nonce from the auth nonce command followed by the rest of the command.
+//! A nonce can only be used once, so recorded commands can't be replayed. This gives
+//! integrity and authenticity only, not confidentiality: commands are not encrypted,
+//! so anyone watching the network still sees them.
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use stm32f407;
+
+use ::flash::{self, KeyBlock, SoftwareCrc32};
+use ::sha256;
+
+/// Length in bytes of a nonce.
+pub const NONCE_LEN: usize = 16;
+
+/// Attempts to read each random word before giving up on the RNG.
+const RNG_ATTEMPTS: u32 = 1000;
+
+static RNG: Mutex<RefCell<Option<stm32f407::RNG>>> = Mutex::new(RefCell::new(None));
+
+/// The nonce most recently sent, until a command uses it.
+static NONCE: Mutex<Cell<Option<[u8; NONCE_LEN]>>> = Mutex::new(Cell::new(None));
+
+/// Start the random number generator, which needs the 48MHz PLL clock, and move it
+/// into this module.
+pub fn init(rcc: &mut stm32f407::RCC, rng: stm32f407::RNG) {
+    rcc.ahb2enr.modify(|_, w| w.rngen().enabled());
+    rng.cr.modify(|_, w| w.rngen().set_bit());
+    ::interrupt_free(|cs| *RNG.borrow(cs).borrow_mut() = Some(rng));
+}
+
+/// Returns true if a key is stored, so commands which change the device need a tag.
+///
+/// This is also true if the key marker says a key should be stored but it has been lost,
+/// for example to a power cut while the configuration sector was rewritten. Then no tag
+/// is accepted, so the device can only be recovered with a debugger.
+pub fn enabled() -> bool {
+    KeyBlock::get(&mut SoftwareCrc32).is_some() || flash::key_expected()
+}
+
+/// Make a new nonce, replacing any unused one, or None if the RNG has failed.
+pub fn new_nonce() -> Option<[u8; NONCE_LEN]> {
+    ::interrupt_free(|cs| {
+        let nonce = random_nonce(cs)?;
+        NONCE.borrow(cs).set(Some(nonce));
+        Some(nonce)
+    })
+}
+
+/// Read a nonce's worth of words from the RNG.
+#[cfg(not(test))]
+fn random_nonce(cs: &CriticalSection) -> Option<[u8; NONCE_LEN]> {
+    let rng = RNG.borrow(cs).borrow();
+    let rng = rng.as_ref()?;
+    let mut nonce = [0u8; NONCE_LEN];
+    for chunk in nonce.chunks_mut(4) {
+        chunk.copy_from_slice(&random_word(rng)?.to_le_bytes());
+    }
+    Some(nonce)
+}
+
+/// There is no RNG on the host, so host tests get a different made up nonce each time.
+#[cfg(test)]
+fn random_nonce(_cs: &CriticalSection) -> Option<[u8; NONCE_LEN]> {
+    use core::sync::atomic::{AtomicU8, Ordering};
+    static COUNT: AtomicU8 = AtomicU8::new(0);
+    let mut nonce = [0x4E; NONCE_LEN];
+    nonce[0] = COUNT.fetch_add(1, Ordering::Relaxed);
+    Some(nonce)
+}
+
+/// Read one word from the RNG, or None if it reports a seed or clock error.
+fn random_word(rng: &stm32f407::RNG) -> Option<u32> {
+    for _ in 0..RNG_ATTEMPTS {
+        let sr = rng.sr.read();
+        if sr.secs().bit_is_set() || sr.cecs().bit_is_set() {
+            return None;
+        }
+        if sr.drdy().bit_is_set() {
+            return Some(rng.dr.read().bits());
+        }
+    }
+    None
+}
+
+/// Check `tag` is the HMAC of the current nonce, the command word `cmd` and the rest
+/// of the command `body`. The nonce is used up either way.
+pub fn verify(cmd: u32, body: &[u8], tag: &[u8]) -> bool {
+    let nonce = ::interrupt_free(|cs| NONCE.borrow(cs).take());
+    match (nonce, KeyBlock::get(&mut SoftwareCrc32)) {
+        (Some(nonce), Some(block)) => {
+            let expected = sha256::hmac(&block.key, &[&nonce, &cmd.to_le_bytes(), body]);
+            sha256::tags_equal(&expected, tag)
+        },
+        _ => false,
+    }
+}
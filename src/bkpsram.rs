@@ -0,0 +1,57 @@
+//! Access to the battery backed SRAM, for storing data such as calibration
+//! which must survive resets and isn't suited to flash.
+use core;
+use stm32f407;
+
+use ::{Error, Result};
+use ::config::{BKPSRAM_ADDRESS, BKPSRAM_LENGTH};
+
+/// Enable the backup domain and backup SRAM so they can be read and written.
+pub fn init(rcc: &mut stm32f407::RCC, pwr: &mut stm32f407::PWR) {
+    rcc.apb1enr.modify(|_, w| w.pwren().enabled());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    rcc.ahb1enr.modify(|_, w| w.bkpsramen().enabled());
+}
+
+/// Check an access of `length` bytes at `address` is valid.
+///
+/// * `address` must be word aligned.
+/// * `length` must be a multiple of 4 and no more than 1024. Zero accesses nothing.
+/// * The range must lie within the backup SRAM.
+fn validate(address: u32, length: usize) -> Result<()> {
+    if length % 4 != 0 {
+        return Err(Error::LengthNotMultiple4);
+    }
+    if length > 1024 {
+        return Err(Error::LengthTooLong);
+    }
+    let offset = address.wrapping_sub(BKPSRAM_ADDRESS);
+    if address % 4 != 0 || address < BKPSRAM_ADDRESS ||
+       offset as usize > BKPSRAM_LENGTH - length {
+        return Err(Error::InvalidAddress);
+    }
+    Ok(())
+}
+
+/// Read from backup SRAM.
+pub fn read(address: u32, length: usize) -> Result<&'static [u8]> {
+    validate(address, length)?;
+    unsafe {
+        Ok(core::slice::from_raw_parts::<'static, u8>(address as *const _, length))
+    }
+}
+
+/// Write to backup SRAM.
+pub fn write(address: u32, length: usize, data: &[u8]) -> Result<()> {
+    validate(address, length)?;
+    if length != data.len() {
+        return Err(Error::DataLengthIncorrect);
+    }
+    for idx in 0..(length / 4) {
+        let offset = idx * 4;
+        let word = u32::from_le_bytes(
+            [data[offset], data[offset+1], data[offset+2], data[offset+3]]);
+        unsafe { core::ptr::write_volatile((address + offset as u32) as *mut u32, word) };
+    }
+    Ok(())
+}
@@ -4,6 +4,22 @@ use stm32f407;
 
 static mut USER_RESET: Option<extern "C" fn()> = None;
 use ::config::{BOOTLOAD_FLAG_VALUE, BOOTLOAD_FLAG_ADDRESS};
+use ::config::{TRIAL_FLAG_REQUESTED, TRIAL_FLAG_RUNNING, TRIAL_FLAG_ADDRESS};
+use ::config::{HANDOFF_ADDRESS, HANDOFF_MAGIC, HANDOFF_VERSION, HANDOFF_WORDS};
+#[cfg(feature = "rapid-reset")]
+use ::config::{RAPID_RESET_COUNT, RAPID_RESET_WINDOW_MS, RAPID_RESET_BKP_REGISTER};
+
+static mut RESET_FLAGS: u32 = 0;
+
+/// Record the reset flags in RCC_CSR, before anything clears them.
+pub fn save_reset_flags(rcc: &stm32f407::RCC) {
+    unsafe { RESET_FLAGS = rcc.csr.read().bits() };
+}
+
+/// Returns the RCC_CSR reset flags recorded by `save_reset_flags`.
+pub fn reset_flags() -> u32 {
+    unsafe { RESET_FLAGS }
+}
 
 /// Returns true if the most recent reset was due to a software request
 ///
@@ -18,7 +34,7 @@ pub fn was_software_reset(rcc: &mut stm32f407::RCC) -> bool {
 ///
 /// Clears the flag before returning, so this answer is only valid once.
 pub fn flag_set() -> bool {
-    cortex_m::interrupt::free(|_| unsafe {
+    ::interrupt_free(|_| unsafe {
         let flag = core::ptr::read_volatile(BOOTLOAD_FLAG_ADDRESS as *const u32);
         clear_flag();
         flag == BOOTLOAD_FLAG_VALUE
@@ -26,11 +42,71 @@ pub fn flag_set() -> bool {
 }
 
 fn clear_flag() {
-    cortex_m::interrupt::free(|_| unsafe {
+    ::interrupt_free(|_| unsafe {
         core::ptr::write_volatile(BOOTLOAD_FLAG_ADDRESS as *mut u32, 0);
     });
 }
 
+/// Returns true if user code was booted on trial and has not cleared the trial flag since.
+///
+/// If a trial boot has just been requested, marks the trial as running and returns false,
+/// so the user code is booted once. The user code must then write 0 to the trial flag
+/// once it is healthy, or the next reset will return to the bootloader.
+pub fn trial_boot_failed() -> bool {
+    ::interrupt_free(|_| unsafe {
+        match core::ptr::read_volatile(TRIAL_FLAG_ADDRESS as *const u32) {
+            TRIAL_FLAG_REQUESTED => {
+                core::ptr::write_volatile(TRIAL_FLAG_ADDRESS as *mut u32, TRIAL_FLAG_RUNNING);
+                false
+            },
+            TRIAL_FLAG_RUNNING => {
+                core::ptr::write_volatile(TRIAL_FLAG_ADDRESS as *mut u32, 0);
+                true
+            },
+            _ => false,
+        }
+    })
+}
+
+/// Request that the user code is booted on trial after the next reset
+pub fn set_trial_flag() {
+    ::interrupt_free(|_| unsafe {
+        core::ptr::write_volatile(TRIAL_FLAG_ADDRESS as *mut u32, TRIAL_FLAG_REQUESTED);
+    });
+}
+
+/// Marks a valid reset count in the upper half of the RTC backup register.
+#[cfg(feature = "rapid-reset")]
+const RAPID_RESET_TAG: u32 = 0xB1E7_0000;
+
+/// Returns true if this is the RAPID_RESET_COUNTth reset in quick succession.
+///
+/// Each call counts one reset in an RTC backup register, then waits RAPID_RESET_WINDOW_MS
+/// before clearing the count, so only resets during that wait add up. This delays every
+/// boot by the window, and must be called before the clocks are configured, as the wait
+/// assumes the 16MHz HSI.
+#[cfg(feature = "rapid-reset")]
+pub fn rapid_resets(rcc: &mut stm32f407::RCC, pwr: &mut stm32f407::PWR,
+                    rtc: &mut stm32f407::RTC) -> bool
+{
+    rcc.apb1enr.modify(|_, w| w.pwren().enabled());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+    let bkpr = &rtc.bkpr[RAPID_RESET_BKP_REGISTER];
+    let value = bkpr.read().bits();
+    let count = if value & 0xFFFF_0000 == RAPID_RESET_TAG { (value & 0xFFFF) + 1 } else { 1 };
+    let triggered = count >= RAPID_RESET_COUNT;
+    if !triggered {
+        unsafe { bkpr.write(|w| w.bits(RAPID_RESET_TAG | count)) };
+        cortex_m::asm::delay(16_000 * RAPID_RESET_WINDOW_MS);
+    }
+    unsafe { bkpr.write(|w| w.bits(0)) };
+
+    pwr.cr.modify(|_, w| w.dbp().clear_bit());
+    rcc.apb1enr.modify(|_, w| w.pwren().disabled());
+    triggered
+}
+
 /// Trigger a reset that will cause us to bootload the user application next go around
 pub fn reset_bootload() {
     clear_flag();
@@ -41,16 +117,47 @@ pub fn reset_bootload() {
     unsafe { *aircr = (0x5FA<<16) | (1<<2) };
 }
 
+/// Trigger a reset that will cause us to enter the bootloader next go around
+pub fn reset_to_bootloader() -> ! {
+    ::interrupt_free(|_| unsafe {
+        core::ptr::write_volatile(BOOTLOAD_FLAG_ADDRESS as *mut u32, BOOTLOAD_FLAG_VALUE);
+    });
+    let aircr = 0xE000ED0C as *mut u32;
+    unsafe { *aircr = (0x5FA<<16) | (1<<2) };
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+/// Returns true if user code has jumped back into the bootloader without a reset.
+///
+/// VTOR is always 0 after reset, but `bootload` points it at the user code.
+pub fn reentered() -> bool {
+    let vtor = 0xE000ED08 as *const u32;
+    unsafe { core::ptr::read_volatile(vtor) != 0 }
+}
+
+/// Write the handoff block, telling user code at `address` where it was booted from and
+/// how to request the bootloader.
+fn write_handoff(address: u32) {
+    let block: [u32; HANDOFF_WORDS as usize] = [HANDOFF_MAGIC, HANDOFF_VERSION, address,
+                                                BOOTLOAD_FLAG_ADDRESS, BOOTLOAD_FLAG_VALUE];
+    for (idx, &word) in block.iter().enumerate() {
+        unsafe { core::ptr::write_volatile((HANDOFF_ADDRESS as *mut u32).add(idx), word) };
+    }
+}
+
 /// Jump to user code at the given address.
 ///
 /// Doesn't disable interrupts so only call this right at boot,
 /// when no interrupt sources will be enabled.
 pub fn bootload(scb: &mut cortex_m::peripheral::SCB, address: u32) {
+    write_handoff(address);
     unsafe {
         let sp = *(address as *const u32);
         let rv = *((address + 4) as *const u32);
 
-        USER_RESET = Some(core::mem::transmute(rv));
+        USER_RESET = Some(core::mem::transmute(rv as usize));
         scb.vtor.write(address);
         cortex_m::register::msp::write(sp);
         (USER_RESET.unwrap())();
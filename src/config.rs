@@ -1,29 +1,226 @@
 //! Chip and board specific configuration settings go here.
 use stm32f407;
 use ::bootload;
+use ::indicator::{Indicator, EnterCause};
+use ::network::CustomHandler;
 
 /// TCP port to listen on
 pub const TCP_PORT: u16 = 7777;
 
+/// TCP port for the emergency listener, which only accepts the info, layout and boot commands,
+/// and reads, erases and writes of the configuration sector.
+#[cfg(feature = "emergency-port")]
+pub const EMERGENCY_TCP_PORT: u16 = 7778;
+
+/// Number of bytes of flash blank-checked per network poll after an erase.
+///
+/// Larger values finish the check in fewer polls but hold off the TCP stack for longer each time.
+pub const ERASE_VERIFY_CHUNK: usize = 4096;
+
+/// Number of entries in the smoltcp neighbor (ARP) cache.
+///
+/// Each entry costs 24 bytes of RAM, and as the cache storage is initialised data,
+/// the same again in flash. The cache is filled from incoming frames, so a client on
+/// the local subnet is learned from its first packet without an ARP round trip;
+/// a larger cache only helps if many hosts on a busy subnet talk to the bootloader.
+pub const NEIGHBOR_CACHE_SIZE: usize = 16;
+
+/// Size in bytes of the RAM copy of the bootloader's printed output, returned by the
+/// get log command. Once full, the oldest output is overwritten.
+pub const LOG_BUFFER_SIZE: usize = 1024;
+
+/// Number of consecutive network polls a socket may fail to listen on before the bootloader
+/// rebuilds the network stack. Polls happen every millisecond.
+pub const LISTEN_RETRY_LIMIT: u32 = 100;
+
+/// Interval in ms between checks of the PHY for link-up, until the link is first established.
+pub const LINK_POLL_MS: u32 = 10;
+
+/// Interval in ms between log messages while the link has still not come up.
+/// Must be a multiple of LINK_POLL_MS.
+pub const LINK_WAIT_LOG_MS: u32 = 5000;
+
+/// Number of ARP probes for the bootloader's own IP address sent once the link is up,
+/// with the ip-conflict feature.
+#[cfg(feature = "ip-conflict")]
+pub const ARP_PROBE_COUNT: u32 = 3;
+/// Interval in ms between those ARP probes.
+#[cfg(feature = "ip-conflict")]
+pub const ARP_PROBE_INTERVAL_MS: u32 = 1000;
+
 /// PHY address
 pub const ETH_PHY_ADDR: u8 = 0;
 
+/// First and last address of the RAM the ethernet DMA can access.
+///
+/// The descriptor rings and their buffers must lie in this range, which on the F4
+/// excludes the CCM RAM at 0x1000_0000. This is checked when the EthernetDevice is created.
+pub const ETH_DMA_RAM: (u32, u32) = (0x2000_0000, 0x2001_FFFF);
+
+/// Address of the 96 bit device unique ID, which differs between STM32 families.
+/// 0x1FFF_7A10 on the F4, 0x1FFF_F7E8 on the F1 and 0x1FF1_E800 on the F7.
+pub const UID_ADDRESS: u32 = 0x1FFF_7A10;
+
+/// Lowest and highest initial stack pointer accepted in a user firmware vector table.
+///
+/// The stack grows down, so the highest value is the address just past the end of RAM.
+pub const USER_STACK_RAM: (u32, u32) = (0x2000_0000, 0x2002_0000);
+
+/// Address and length of the battery backed SRAM, accessible with the ReadMem and WriteMem
+/// commands when the backup-sram feature is enabled.
+#[cfg(feature = "backup-sram")]
+pub const BKPSRAM_ADDRESS: u32 = 0x4002_4000;
+#[cfg(feature = "backup-sram")]
+pub const BKPSRAM_LENGTH: usize = 4096;
+
 /// Start address of each sector in flash
 pub const FLASH_SECTOR_ADDRESSES: [u32; 12] =
     [0x0800_0000, 0x0800_4000, 0x0800_8000, 0x0800_C000,
      0x0801_0000, 0x0802_0000, 0x0804_0000, 0x0806_0000,
      0x0808_0000, 0x080A_0000, 0x080C_0000, 0x080E_0000];
+/// Typical and maximum erase time in milliseconds of each sector in flash,
+/// from the datasheet figures for 32 bit parallelism.
+pub const FLASH_SECTOR_ERASE_MS: [(u32, u32); 12] =
+    [(250, 500), (250, 500), (250, 500), (250, 500),
+     (550, 1100), (1000, 2000), (1000, 2000), (1000, 2000),
+     (1000, 2000), (1000, 2000), (1000, 2000), (1000, 2000)];
 /// Final valid address in flash
 pub const FLASH_END: u32 = 0x080F_FFFF;
 /// Address of configuration sector. Must be one of the start addresses in FLASH_SECTOR_ADDRESSES.
 pub const FLASH_CONFIG: u32 = FLASH_SECTOR_ADDRESSES[3];
 /// Address of user firmware sector. Must be one of the start addresses in FLASH_SECTOR_ADDRESSES.
 pub const FLASH_USER: u32   = FLASH_SECTOR_ADDRESSES[4];
+/// Address of the key for authenticating commands, with the auth feature. It is kept in
+/// the configuration sector after the configuration, and can't be read with the read command.
+#[cfg(feature = "auth")]
+pub const AUTH_KEY_ADDRESS: u32 = FLASH_CONFIG + 0x100;
+/// Address of the key marker, with the auth feature, which records whether a key should be
+/// stored so that losing it to an interrupted erase of the configuration sector refuses
+/// commands rather than turning authentication off. It is the last AUTH_MARKER_WORDS words
+/// of the bootloader's own flash, which memory.x keeps out of the bootloader image.
+#[cfg(feature = "auth")]
+pub const AUTH_MARKER_ADDRESS: u32 = FLASH_CONFIG - 4 * AUTH_MARKER_WORDS as u32;
+/// Number of words in the key marker. Each key stored after none, or removed, uses one bit.
+#[cfg(feature = "auth")]
+pub const AUTH_MARKER_WORDS: usize = 4;
+
+/// Returns true if user firmware starting at `user` can't overlap the bootloader or the
+/// configuration sector at `config`.
+///
+/// Both must be sector starts, with `user` inside flash and after `config`. Sectors are
+/// contiguous, so the user firmware then starts at or after the sector following `config`,
+/// and the bootloader occupies the sectors before `config`.
+pub const fn user_region_valid(config: u32, user: u32) -> bool {
+    is_sector_start(config) && is_sector_start(user) && user > config && user <= FLASH_END
+}
+
+/// Returns true if `address` is the start address of one of the flash sectors.
+const fn is_sector_start(address: u32) -> bool {
+    let mut idx = 0;
+    while idx < FLASH_SECTOR_ADDRESSES.len() {
+        if FLASH_SECTOR_ADDRESSES[idx] == address {
+            return true;
+        }
+        idx += 1;
+    }
+    false
+}
+
+// Check the flash layout above is self-consistent at compile time,
+// so a port to a different sector layout can't silently overlap regions.
+const _: () = assert!(FLASH_CONFIG != 0 && FLASH_USER != 0);
+const _: () = assert!(is_sector_start(FLASH_CONFIG), "FLASH_CONFIG must be a sector start");
+const _: () = assert!(is_sector_start(FLASH_USER), "FLASH_USER must be a sector start");
+const _: () = assert!(FLASH_USER > FLASH_CONFIG, "FLASH_USER must be after FLASH_CONFIG");
+const _: () = assert!(FLASH_USER <= FLASH_END, "FLASH_USER must be inside flash");
+const _: () = assert!(user_region_valid(FLASH_CONFIG, FLASH_USER));
+#[cfg(feature = "auth")]
+const _: () = assert!(AUTH_KEY_ADDRESS % 4 == 0 && AUTH_KEY_ADDRESS + 40 <= FLASH_USER,
+                      "AUTH_KEY_ADDRESS must be a word in the configuration sector");
+#[cfg(feature = "auth")]
+const _: () = assert!(AUTH_MARKER_WORDS <= 4, "memory.x only reserves 16 bytes for the key marker");
+
+const _: () = assert!(LINK_WAIT_LOG_MS % LINK_POLL_MS == 0,
+                      "LINK_WAIT_LOG_MS must be a multiple of LINK_POLL_MS");
+
+/// Parse a decimal or 0x prefixed hexadecimal number at compile time.
+const fn parse_u32(s: &str) -> u32 {
+    let b = s.as_bytes();
+    let (mut idx, radix) = if b.len() > 2 && b[0] == b'0' && (b[1] == b'x' || b[1] == b'X') {
+        (2, 16)
+    } else {
+        (0, 10)
+    };
+    let mut value: u32 = 0;
+    while idx < b.len() {
+        let digit = match b[idx] {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'f' if radix == 16 => c - b'a' + 10,
+            c @ b'A'..=b'F' if radix == 16 => c - b'A' + 10,
+            b'_' => { idx += 1; continue; },
+            _ => panic!("invalid number"),
+        };
+        value = value * radix + digit as u32;
+        idx += 1;
+    }
+    value
+}
+
+/// Returns the parsed value of an environment variable read at compile time, or `default`.
+const fn env_or(value: Option<&str>, default: u32) -> u32 {
+    match value {
+        Some(s) => parse_u32(s),
+        None => default,
+    }
+}
 
 /// Magic value used in this module to check if bootloader should start.
-pub const BOOTLOAD_FLAG_VALUE: u32 = 0xB00110AD;
+/// Override by setting BLETHRS_BOOTLOAD_FLAG_VALUE when building.
+pub const BOOTLOAD_FLAG_VALUE: u32 =
+    env_or(option_env!("BLETHRS_BOOTLOAD_FLAG_VALUE"), 0xB00110AD);
 /// Address of magic value used in this module to check if bootloader should start.
-pub const BOOTLOAD_FLAG_ADDRESS: u32 = 0x2000_0000;
+/// Override by setting BLETHRS_BOOTLOAD_FLAG_ADDRESS when building.
+///
+/// This word must not be zeroed at startup by either the bootloader or the user firmware,
+/// so must be outside their `.bss` and `.data`, as arranged for 0x2000_0000 by memory.x.
+pub const BOOTLOAD_FLAG_ADDRESS: u32 =
+    env_or(option_env!("BLETHRS_BOOTLOAD_FLAG_ADDRESS"), 0x2000_0000);
+
+/// Magic value set by the trial boot command to boot user code once.
+pub const TRIAL_FLAG_REQUESTED: u32 = 0x7121A100;
+/// Magic value set while user code is being trialled, which user code must clear.
+pub const TRIAL_FLAG_RUNNING: u32 = 0x7121A1ED;
+/// Address of the trial boot flag.
+pub const TRIAL_FLAG_ADDRESS: u32 = 0x2000_0004;
+
+/// Address of the handoff block written just before booting the user firmware, which
+/// holds HANDOFF_MAGIC, HANDOFF_VERSION, the user firmware's base address and the
+/// bootload flag's address and value, one word each.
+pub const HANDOFF_ADDRESS: u32 = 0x2000_0008;
+/// Marks a valid handoff block.
+pub const HANDOFF_MAGIC: u32 = 0xB1E7_4A0F;
+/// Version of the handoff block's layout, increased when words are added to it.
+pub const HANDOFF_VERSION: u32 = 1;
+/// Number of words in the handoff block.
+pub const HANDOFF_WORDS: u32 = 5;
+
+const _: () = assert!(BOOTLOAD_FLAG_ADDRESS % 4 == 0, "BOOTLOAD_FLAG_ADDRESS must be word aligned");
+const _: () = assert!(BOOTLOAD_FLAG_ADDRESS != TRIAL_FLAG_ADDRESS,
+                      "BOOTLOAD_FLAG_ADDRESS must not be the trial boot flag");
+const _: () = assert!(BOOTLOAD_FLAG_ADDRESS < HANDOFF_ADDRESS
+                      || BOOTLOAD_FLAG_ADDRESS >= HANDOFF_ADDRESS + 4 * HANDOFF_WORDS,
+                      "BOOTLOAD_FLAG_ADDRESS must not be in the handoff block");
+
+/// Number of resets in quick succession which enter the bootloader, with the rapid-reset feature.
+#[cfg(feature = "rapid-reset")]
+pub const RAPID_RESET_COUNT: u32 = 3;
+/// Time in milliseconds after each reset during which a further reset counts as rapid.
+/// Every boot of the user firmware is delayed by this long.
+#[cfg(feature = "rapid-reset")]
+pub const RAPID_RESET_WINDOW_MS: u32 = 500;
+/// Index of the RTC backup register holding the reset count, which user firmware must not use.
+#[cfg(feature = "rapid-reset")]
+pub const RAPID_RESET_BKP_REGISTER: usize = 19;
 
 /// This function should return true if the bootloader should enter bootload mode,
 /// or false to immediately chainload the user firmware.
@@ -36,9 +233,13 @@ pub fn should_enter_bootloader(peripherals: &mut stm32f407::Peripherals) -> bool
     // Our plan is:
     // * If the reset was a software reset, and the magic flag is in the magic location,
     //   then the user firmware requested bootload, so enter bootload.
+    // * If user code was booted on trial and didn't confirm it was healthy, enter bootload.
+    // * With the rapid-reset feature, if the board was reset several times in quick
+    //   succession, enter bootload.
     // * Otherwise we check if PD2 is LOW for at least a full byte period of the UART,
     //   indicating someone has connected 3V to the external connector.
     let cond1 = bootload::was_software_reset(&mut peripherals.RCC) && bootload::flag_set();
+    let cond3 = bootload::trial_boot_failed();
 
     peripherals.RCC.ahb1enr.modify(|_, w| w.gpioden().enabled());
     peripherals.GPIOD.moder.modify(|_, w| w.moder2().input());
@@ -53,7 +254,14 @@ pub fn should_enter_bootloader(peripherals: &mut stm32f407::Peripherals) -> bool
     }
 
     peripherals.RCC.ahb1enr.modify(|_, w| w.gpioden().disabled());
-    cond1 || cond2
+
+    #[cfg(feature = "rapid-reset")]
+    let cond4 = bootload::rapid_resets(&mut peripherals.RCC, &mut peripherals.PWR,
+                                       &mut peripherals.RTC);
+    #[cfg(not(feature = "rapid-reset"))]
+    let cond4 = false;
+
+    cond1 || cond2 || cond3 || cond4
 }
 
 /// Set up GPIOs for ethernet.
@@ -66,9 +274,8 @@ pub fn configure_gpio(peripherals: &mut stm32f407::Peripherals) {
     let gpioc = &peripherals.GPIOC;
     let gpioe = &peripherals.GPIOE;
 
-    // Status LED
+    // Status LED, driven by StatusLed below
     gpioe.moder.modify(|_, w| w.moder7().output());
-    gpioe.odr.modify(|_, w| w.odr7().clear_bit());
 
     // Configure ethernet related GPIO:
     // GPIOA 1, 2, 7
@@ -112,3 +319,67 @@ pub fn configure_gpio(peripherals: &mut stm32f407::Peripherals) {
          .afrl4().af11()
          .afrl5().af11());
 }
+
+/// Handler for integrator specific commands, or None if there are none.
+///
+/// See `network::CustomHandler` for the reserved range of command codes it receives.
+pub static CUSTOM_HANDLER: Option<&'static (dyn CustomHandler + Sync)> = None;
+
+/// Board indications of bootloader activity.
+///
+/// Replace StatusLed with your own Indicator implementation to drive other outputs.
+pub static INDICATOR: StatusLed = StatusLed;
+
+/// Lights the status LED on PE7 while in bootloader mode.
+pub struct StatusLed;
+
+impl Indicator for StatusLed {
+    fn entered_bootloader(&self, _cause: EnterCause) {
+        let gpioe = unsafe { &*stm32f407::GPIOE::ptr() };
+        gpioe.odr.modify(|_, w| w.odr7().clear_bit());
+    }
+
+    fn link_up(&self) {
+        let gpioe = unsafe { &*stm32f407::GPIOE::ptr() };
+        gpioe.odr.modify(|_, w| w.odr7().clear_bit());
+    }
+
+    /// Blink the LED once a second until the link comes up.
+    fn link_waiting(&self, ms: u32) {
+        let gpioe = unsafe { &*stm32f407::GPIOE::ptr() };
+        gpioe.odr.modify(|_, w| w.odr7().bit(ms % 1000 >= 500));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Layouts where user firmware would overlap the configuration or bootloader,
+    /// or lie outside flash, are rejected.
+    #[test]
+    fn user_region_layouts() {
+        let sector = |idx: usize| FLASH_SECTOR_ADDRESSES[idx];
+        let table: &[(u32, u32, bool)] = &[
+            (sector(3), sector(4), true),
+            (sector(3), sector(5), true),
+            (sector(1), sector(2), true),
+            (sector(3), sector(11), true),
+            // User firmware in the configuration sector
+            (sector(3), sector(3), false),
+            (sector(3), sector(3) + 0x400, false),
+            // User firmware in the bootloader
+            (sector(3), sector(1), false),
+            (sector(3), sector(0), false),
+            // Not sector starts, or outside flash
+            (sector(3), sector(4) + 4, false),
+            (sector(3) + 4, sector(4), false),
+            (sector(3), FLASH_END + 1, false),
+            (sector(11), FLASH_END + 1, false),
+            (0, sector(4), false),
+        ];
+        for (row, &(config, user, expected)) in table.iter().enumerate() {
+            assert!(user_region_valid(config, user) == expected, "row {}", row);
+        }
+    }
+}
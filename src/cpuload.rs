@@ -0,0 +1,67 @@
+//! Estimate of CPU utilisation using the DWT cycle counter.
+//!
+//! All bootloader work happens in the SysTick handler, and the main loop otherwise sleeps
+//! in `wfi`, so cycles spent in the handler count as active and all others as idle.
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+
+/// Core clock in Hz, which the cycle counter runs at.
+pub const CORE_HZ: u32 = 168_000_000;
+
+/// Active cycles over the most recent measurement period of about one second.
+pub static ACTIVE_CYCLES_PER_SECOND: AtomicU32 = AtomicU32::new(0);
+/// Idle cycles over the most recent measurement period of about one second.
+pub static IDLE_CYCLES_PER_SECOND: AtomicU32 = AtomicU32::new(0);
+
+static PERIOD_START: AtomicU32 = AtomicU32::new(0);
+static PERIOD_ACTIVE: AtomicU32 = AtomicU32::new(0);
+
+/// Start measuring. The cycle counter must already have been started by `startup::init`.
+pub fn init() {
+    PERIOD_START.store(DWT::get_cycle_count(), Ordering::Relaxed);
+}
+
+/// Cycle count to pass to `handler_done` at the end of the handler.
+pub fn handler_start() -> u32 {
+    DWT::get_cycle_count()
+}
+
+/// Count the cycles since `start` as active, and publish the totals each second.
+///
+/// Must only be called from the SysTick handler, which cannot preempt itself.
+pub fn handler_done(start: u32) {
+    let now = DWT::get_cycle_count();
+    let active = PERIOD_ACTIVE.load(Ordering::Relaxed) + now.wrapping_sub(start);
+    let elapsed = now.wrapping_sub(PERIOD_START.load(Ordering::Relaxed));
+    if elapsed >= CORE_HZ {
+        ACTIVE_CYCLES_PER_SECOND.store(active, Ordering::Relaxed);
+        IDLE_CYCLES_PER_SECOND.store(elapsed.saturating_sub(active), Ordering::Relaxed);
+        PERIOD_START.store(now, Ordering::Relaxed);
+        PERIOD_ACTIVE.store(0, Ordering::Relaxed);
+    } else {
+        PERIOD_ACTIVE.store(active, Ordering::Relaxed);
+    }
+}
+
+/// Current value of the cycle counter, for timing work within the handler.
+#[cfg(not(test))]
+pub fn cycle_count() -> u32 {
+    DWT::get_cycle_count()
+}
+
+/// There is no cycle counter on the host, so everything takes no time.
+#[cfg(test)]
+pub fn cycle_count() -> u32 {
+    0
+}
+
+/// Percentage of cycles spent active over the last second, or 0 before the first second.
+pub fn utilisation_percent() -> u32 {
+    let active = ACTIVE_CYCLES_PER_SECOND.load(Ordering::Relaxed);
+    let total = active + IDLE_CYCLES_PER_SECOND.load(Ordering::Relaxed);
+    if total < 100 {
+        0
+    } else {
+        active / (total / 100)
+    }
+}
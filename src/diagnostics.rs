@@ -0,0 +1,38 @@
+//! Counters of errors seen since startup, reported and optionally cleared by the
+//! diagnostics command, to judge link and flash health before and after programming.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Errors returned by the smoltcp interface poll.
+pub static POLL_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// Received frames dropped as they didn't fit in a single receive buffer.
+pub static DROPPED_FRAMES: AtomicU32 = AtomicU32::new(0);
+/// Frames the ethernet DMA missed, because no receive descriptor or FIFO space was free.
+pub static DMA_MISSED_FRAMES: AtomicU32 = AtomicU32::new(0);
+/// Failed flash unlocks, erases and writes.
+pub static FLASH_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// Times a socket failed to go back to listening after a connection.
+pub static LISTEN_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Version of the counters structure. Counters are only ever appended, increasing the version.
+pub const VERSION: u32 = 1;
+
+/// All counters, in the order they are sent by the diagnostics command.
+pub static COUNTERS: [&AtomicU32; 5] = [
+    &POLL_ERRORS, &DROPPED_FRAMES, &DMA_MISSED_FRAMES, &FLASH_ERRORS, &LISTEN_FAILURES,
+];
+
+/// Add one to `counter`.
+pub fn count(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Read each counter in order with `f`, clearing them if `reset` is set.
+pub fn read<F: FnMut(u32)>(reset: bool, mut f: F) {
+    for counter in COUNTERS.iter() {
+        if reset {
+            f(counter.swap(0, Ordering::Relaxed));
+        } else {
+            f(counter.load(Ordering::Relaxed));
+        }
+    }
+}
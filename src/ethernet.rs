@@ -1,5 +1,5 @@
 use core;
-use cortex_m;
+use core::sync::atomic::Ordering;
 use stm32f407;
 
 use smoltcp::{self, phy::{self, DeviceCapabilities}, time::Instant, wire::EthernetAddress};
@@ -8,7 +8,8 @@ const ETH_BUF_SIZE: usize = 1536;
 const ETH_NUM_TD: usize = 4;
 const ETH_NUM_RD: usize = 4;
 
-use ::config::ETH_PHY_ADDR;
+use ::config::{ETH_PHY_ADDR, ETH_DMA_RAM};
+use ::diagnostics;
 
 /// Transmit Descriptor representation
 ///
@@ -72,6 +73,7 @@ struct TDesRing {
     tdidx: usize,
 }
 
+#[cfg_attr(feature = "eth-dma-section", link_section = ".eth_dma")]
 static mut TDESRING: TDesRing = TDesRing {
     td: [TDes { tdes0: 0, tdes1: 0, tdes2: 0, tdes3: 0 }; ETH_NUM_TD],
     tbuf: [[0; ETH_BUF_SIZE/4]; ETH_NUM_TD],
@@ -157,9 +159,28 @@ impl RDes {
         self.rdes0 |= 1<<31;
     }
 
-    /// Access the buffer pointed to by this descriptor
+    /// Return the length of the received frame
+    pub fn frame_length(&self) -> usize {
+        (self.rdes0 >> 16) as usize & 0x3FFF
+    }
+
+    /// Return true if this RDes holds a complete frame which fits in its buffer.
+    ///
+    /// Both first segment (FS) and last segment (LS) must be set, as frames
+    /// spread over multiple buffers are not supported.
+    pub fn valid(&self) -> bool {
+        self.rdes0 & (1<<9) != 0 && self.rdes0 & (1<<8) != 0 && self.frame_length() <= ETH_BUF_SIZE
+    }
+
+    /// Access the buffer pointed to by this descriptor.
+    ///
+    /// Returns an empty slice if the frame length is larger than the buffer.
     pub unsafe fn buf_as_slice_mut(&self) -> &mut [u8] {
-        core::slice::from_raw_parts_mut(self.rdes2 as *mut _, (self.rdes0 >> 16) as usize & 0x3FFF)
+        let mut length = self.frame_length();
+        if length > ETH_BUF_SIZE {
+            length = 0;
+        }
+        core::slice::from_raw_parts_mut(self.rdes2 as *mut _, length)
     }
 }
 
@@ -170,6 +191,7 @@ struct RDesRing {
     rdidx: usize,
 }
 
+#[cfg_attr(feature = "eth-dma-section", link_section = ".eth_dma")]
 static mut RDESRING: RDesRing = RDesRing {
     rd: [RDes { rdes0: 0, rdes1: 0, rdes2: 0, rdes3: 0 }; ETH_NUM_RD],
     rbuf: [[0; ETH_BUF_SIZE/4]; ETH_NUM_RD],
@@ -210,6 +232,10 @@ impl RDesRing {
     }
 }
 
+/// Bytes of RAM taken by the descriptor rings and their buffers.
+pub const BUFFER_BYTES: usize =
+    core::mem::size_of::<TDesRing>() + core::mem::size_of::<RDesRing>();
+
 /// Ethernet device driver
 pub struct EthernetDevice {
     rdring: &'static mut RDesRing,
@@ -220,20 +246,37 @@ pub struct EthernetDevice {
 
 static mut BUFFERS_USED: bool = false;
 
+/// Returns true if `length` bytes at `ptr` are word aligned and inside ETH_DMA_RAM,
+/// as required for the ETH_DMA engine to access them.
+fn dma_accessible<T>(ptr: *const T, length: usize) -> bool {
+    let start = ptr as u32;
+    let end = start + length as u32 - 1;
+    start % 4 == 0 && start >= ETH_DMA_RAM.0 && end <= ETH_DMA_RAM.1
+}
+
 impl EthernetDevice {
     /// Create a new uninitialised EthernetDevice.
     ///
     /// You must move in ETH_MAC, ETH_DMA, and they are then kept by the device.
     ///
     /// You may only call this function once; subsequent calls will panic.
+    /// Also panics if the descriptor rings have been placed outside ETH_DMA_RAM.
     pub fn new(eth_mac: stm32f407::ETHERNET_MAC, eth_dma: stm32f407::ETHERNET_DMA)
     -> EthernetDevice {
-        cortex_m::interrupt::free(|_| unsafe {
+        ::interrupt_free(|_| unsafe {
             if BUFFERS_USED {
                 panic!("EthernetDevice already created");
             }
+            if !dma_accessible(core::ptr::addr_of!(RDESRING), core::mem::size_of::<RDesRing>()) ||
+               !dma_accessible(core::ptr::addr_of!(TDESRING), core::mem::size_of::<TDesRing>()) {
+                panic!("Ethernet descriptor rings not in DMA accessible RAM");
+            }
             BUFFERS_USED = true;
-            EthernetDevice { rdring: &mut RDESRING, tdring: &mut TDESRING, eth_mac, eth_dma }
+            EthernetDevice {
+                rdring: &mut *core::ptr::addr_of_mut!(RDESRING),
+                tdring: &mut *core::ptr::addr_of_mut!(TDESRING),
+                eth_mac, eth_dma,
+            }
         })
     }
 
@@ -242,7 +285,7 @@ impl EthernetDevice {
     /// Sets up the descriptor structures, sets up the peripheral clocks and GPIO configuration,
     /// and configures the ETH MAC and DMA peripherals.
     ///
-    /// Brings up the PHY and then blocks waiting for a network link.
+    /// Brings up the PHY. The link is not up yet, so poll `link_established` for it.
     pub fn init(&mut self, rcc: &mut stm32f407::RCC, addr: EthernetAddress) {
         self.tdring.init();
         self.rdring.init();
@@ -257,10 +300,6 @@ impl EthernetDevice {
         return self.phy_poll_link()
     }
 
-    pub fn block_until_link(&mut self) {
-        while !self.link_established() {}
-    }
-
     /// Resume suspended TX DMA operation
     pub fn resume_tx_dma(&mut self) {
         if self.eth_dma.dmasr.read().tps().is_suspended() {
@@ -284,12 +323,7 @@ impl EthernetDevice {
         while self.eth_dma.dmabmr.read().sr().is_reset() {}
 
         // Set MAC address
-        let mac = mac.as_bytes();
-        self.eth_mac.maca0lr.write(|w| w.maca0l().bits(
-            (mac[0] as u32) << 0 | (mac[1] as u32) << 8 |
-            (mac[2] as u32) <<16 | (mac[3] as u32) <<24));
-        self.eth_mac.maca0hr.write(|w| w.maca0h().bits(
-            (mac[4] as u16) << 0 | (mac[5] as u16) << 8));
+        self.set_mac(mac);
 
         // Enable RX and TX. We'll set link speed and duplex at link-up.
         self.eth_mac.maccr.write(|w|
@@ -321,6 +355,29 @@ impl EthernetDevice {
         );
     }
 
+    /// Change the MAC address used to filter received frames.
+    pub fn set_mac(&mut self, mac: EthernetAddress) {
+        let mac = mac.as_bytes();
+        self.eth_mac.maca0lr.write(|w| w.maca0l().bits(
+            (mac[0] as u32) << 0 | (mac[1] as u32) << 8 |
+            (mac[2] as u32) <<16 | (mac[3] as u32) <<24));
+        self.eth_mac.maca0hr.write(|w| w.maca0h().bits(
+            (mac[4] as u16) << 0 | (mac[5] as u16) << 8));
+    }
+
+    /// Add the frames missed by the DMA since this was last called to the diagnostics
+    /// counter. The hardware counters are cleared by reading them.
+    pub fn count_missed_frames(&mut self) {
+        let counts = self.eth_dma.dmamfbocr.read();
+        let missed = counts.mfc().bits() as u32 + counts.mfa().bits() as u32;
+        diagnostics::DMA_MISSED_FRAMES.fetch_add(missed, Ordering::Relaxed);
+    }
+
+    /// Read the PHY identifier registers 2 and 3.
+    pub fn phy_id(&mut self) -> (u16, u16) {
+        (self.smi_read(0x02), self.smi_read(0x03))
+    }
+
     /// Read a register over SMI.
     fn smi_read(&mut self, reg: u8) -> u16 {
         // Use PHY address 00000, set register address, set clock to HCLK/102, start read.
@@ -423,6 +480,18 @@ impl phy::RxToken for RxToken {
         // the various RDes methods.
         unsafe {
             let rdes = (*self.0).rdring.next().unwrap();
+
+            // Drop frames which aren't complete in this single descriptor
+            if !rdes.valid() {
+                diagnostics::count(&diagnostics::DROPPED_FRAMES);
+                rdes.release();
+                (*self.0).resume_rx_dma();
+                return Err(smoltcp::Error::Truncated);
+            }
+
+            #[cfg(feature = "ip-conflict")]
+            ::ipconflict::inspect(rdes.buf_as_slice_mut());
+
             let result = f(rdes.buf_as_slice_mut());
             rdes.release();
             (*self.0).resume_rx_dma();
@@ -459,3 +528,22 @@ impl<'a> phy::Device<'a> for EthernetDevice {
         }
     }
 }
+
+// Also for a borrowed device, so the network module can keep the device while
+// rebuilding the interface which uses it
+impl<'a, 'b> phy::Device<'a> for &'b mut EthernetDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        phy::Device::capabilities(&**self)
+    }
+
+    fn receive(&mut self) -> Option<(RxToken, TxToken)> {
+        phy::Device::receive(&mut **self)
+    }
+
+    fn transmit(&mut self) -> Option<TxToken> {
+        phy::Device::transmit(&mut **self)
+    }
+}
@@ -1,4 +1,6 @@
 use core;
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
 use stm32f407;
 
 use ufmt::uwrite;
@@ -7,7 +9,14 @@ use ::{Error, Result};
 
 const CONFIG_MAGIC: u32 = 0x67797870;
 
-use ::config::{FLASH_SECTOR_ADDRESSES, FLASH_END, FLASH_CONFIG, FLASH_USER};
+use ::diagnostics;
+use ::config::{FLASH_SECTOR_ADDRESSES, FLASH_END, FLASH_CONFIG, FLASH_USER, USER_STACK_RAM, INDICATOR};
+use ::config::user_region_valid;
+#[cfg(feature = "auth")]
+use ::config::{AUTH_KEY_ADDRESS, AUTH_MARKER_ADDRESS, AUTH_MARKER_WORDS};
+use ::indicator::Indicator;
+#[cfg(feature = "compressed-write")]
+use ::lz4;
 
 
 static mut FLASH: Option<stm32f407::FLASH> = None;
@@ -17,6 +26,101 @@ pub fn init(flash: stm32f407::FLASH) {
     unsafe { FLASH = Some(flash) };
 }
 
+/// Context of a failed erase or write, sent after the error status.
+#[derive(Clone, Copy)]
+pub struct ErrorDetail {
+    /// Address being erased or written when the error occurred.
+    pub address: u32,
+    /// Index of the sector containing `address`, or SECTOR_NONE.
+    pub sector: u32,
+    /// FLASH_SR at the time of the error.
+    pub sr: u32,
+    /// Write protection of each sector, as returned by `write_protection`.
+    pub nwrp: u32,
+}
+
+/// Sector index used in ErrorDetail when no sector applies.
+pub const SECTOR_NONE: u32 = 0xFFFF_FFFF;
+
+static mut ERROR_DETAIL: Option<ErrorDetail> = None;
+
+/// Record the context of a failure to report with its error status.
+fn set_error_detail(address: u32, sr: u32) {
+    let sector = sector_index(address).map_or(SECTOR_NONE, |idx| idx as u32);
+    let nwrp = write_protection();
+    unsafe { ERROR_DETAIL = Some(ErrorDetail { address, sector, sr, nwrp }) };
+    diagnostics::count(&diagnostics::FLASH_ERRORS);
+}
+
+/// Returns the nWRP option bits, in which bit n is clear if sector n is write protected.
+#[cfg(not(test))]
+pub fn write_protection() -> u32 {
+    let flash = unsafe { &*stm32f407::FLASH::ptr() };
+    flash.optcr.read().n_wrp().bits() as u32
+}
+
+/// Nothing is write protected in the fake flash.
+#[cfg(test)]
+pub fn write_protection() -> u32 {
+    (1 << FLASH_SECTOR_ADDRESSES.len()) - 1
+}
+
+/// Returns and clears the context of the most recent erase or write failure, if any.
+pub fn take_error_detail() -> Option<ErrorDetail> {
+    unsafe {
+        let detail = ERROR_DETAIL;
+        ERROR_DETAIL = None;
+        detail
+    }
+}
+
+/// Set while writes should hold back the first word of the user firmware.
+static DEFER_FIRST_WORD: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// First word of the user firmware, held back from a write until committed.
+static DEFERRED_WORD: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Hold back the first word of the user firmware, its initial stack pointer, from
+/// subsequent writes until `commit_first_word` is called.
+///
+/// Until then the word stays erased, so `valid_user_code` fails and a board which
+/// loses power part way through programming stays in the bootloader. Erasing the
+/// user sector ends the deferral, so defer after erasing.
+pub fn defer_first_word() {
+    ::interrupt_free(|cs| {
+        DEFER_FIRST_WORD.borrow(cs).set(true);
+        DEFERRED_WORD.borrow(cs).set(None);
+    });
+}
+
+/// Stop holding back the first word, discarding it if already written.
+pub fn cancel_first_word() {
+    ::interrupt_free(|cs| {
+        DEFER_FIRST_WORD.borrow(cs).set(false);
+        DEFERRED_WORD.borrow(cs).set(None);
+    });
+}
+
+/// Program the held back first word of the user firmware, which must equal `word`.
+///
+/// Returns WriteError without writing anything if no word is held or it differs.
+pub fn commit_first_word(word: u32) -> Result<()> {
+    let deferred = ::interrupt_free(|cs| DEFERRED_WORD.borrow(cs).get());
+    if deferred != Some(word) {
+        return Err(Error::WriteError);
+    }
+    cancel_first_word();
+    write(FLASH_USER, 4, &word.to_le_bytes())
+}
+
+/// Returns the index of the sector containing `address`, if it is in flash.
+pub fn sector_index(address: u32) -> Option<usize> {
+    if address < FLASH_SECTOR_ADDRESSES[0] || address > FLASH_END {
+        return None;
+    }
+    FLASH_SECTOR_ADDRESSES.iter().rposition(|&start| start <= address)
+}
+
 /// User configuration. Must live in flash at FLASH_CONFIG, 0x0800_C000.
 /// `magic` must be set to 0x67797870. `checksum` must be the CRC32 of the preceeding bytes.
 #[derive(Copy,Clone)]
@@ -27,10 +131,18 @@ pub struct UserConfig {
     pub ip_address: [u8; 4],
     pub ip_gateway: [u8; 4],
     pub ip_prefix: u8,
-    _padding: [u8; 1],
+    /// Bitmask of CONFIG_FLAG_*.
+    pub flags: u8,
     checksum: u32,
 }
 
+/// Set in `UserConfig::flags` to refuse commands which modify flash until unlocked.
+pub const CONFIG_FLAG_LOCKED: u8 = 1 << 0;
+
+/// Size of a stored UserConfig in bytes, including its checksum.
+pub const CONFIG_SIZE: usize = 24;
+const _: () = assert!(CONFIG_SIZE == core::mem::size_of::<UserConfig>());
+
 impl UserConfig {
     pub fn write_to_semihosting(&self) {
         if unsafe { (*cortex_m::peripheral::DCB::ptr()).dhcsr.read() & 1 == 0 } { return; }
@@ -53,6 +165,9 @@ impl UserConfig {
         uwrite!(stdout, "  Gateway: {}.{}.{}.{}\n",
                self.ip_gateway[0], self.ip_gateway[1], self.ip_gateway[2],
                self.ip_gateway[3]).ok();
+        if self.flags & CONFIG_FLAG_LOCKED != 0 {
+            uwrite!(stdout, "  Flashing locked\n").ok();
+        }
         uwrite!(stdout, "  Checksum: {}\n", self.checksum as u32).ok();
     }
 }
@@ -90,60 +205,445 @@ pub static DEFAULT_CONFIG: UserConfig = UserConfig {
     ip_address: [10, 1, 1, 10],
     ip_gateway: [10, 1, 1, 1],
     ip_prefix: 24,
-    _padding: [0u8; 1],
+    flags: 0,
     checksum: 0,
 };
 
+/// Computes the CRC32 used to checksum the UserConfig.
+///
+/// This is the STM32 CRC peripheral's algorithm: polynomial 0x04C11DB7, initial value
+/// 0xFFFFFFFF, fed one whole word at a time MSB first, with no reflection or final XOR.
+pub trait CrcCompute {
+    fn compute(&mut self, data: &[u32]) -> u32;
+}
+
+/// Returns true if the CRC peripheral's clock is enabled, so it can be used.
+pub fn hardware_crc_available() -> bool {
+    unsafe { (*stm32f407::RCC::ptr()).ahb1enr.read().crcen().is_enabled() }
+}
+
+/// Uses the CRC peripheral, or SoftwareCrc32 if its clock isn't enabled,
+/// as an unclocked peripheral reads back zero rather than the CRC.
+impl CrcCompute for stm32f407::CRC {
+    fn compute(&mut self, data: &[u32]) -> u32 {
+        if !hardware_crc_available() {
+            return SoftwareCrc32.compute(data);
+        }
+        self.cr.write(|w| w.reset().reset());
+        for word in data {
+            self.dr.write(|w| w.dr().bits(*word));
+        }
+        self.dr.read().dr().bits()
+    }
+}
+
+/// CRC of `bytes` taken as little-endian words, as stored in flash.
+/// `bytes` must be a multiple of 4 long, and at most 64 bytes.
+fn crc_of_words(bytes: &[u8], crc: &mut impl CrcCompute) -> u32 {
+    let mut words = [0u32; 16];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    crc.compute(&words[..bytes.len() / 4])
+}
+
+/// Software implementation of CrcCompute, for when the CRC peripheral isn't available.
+pub struct SoftwareCrc32;
+
+impl CrcCompute for SoftwareCrc32 {
+    fn compute(&mut self, data: &[u32]) -> u32 {
+        let mut crc = 0xFFFF_FFFFu32;
+        for word in data {
+            crc ^= *word;
+            for _ in 0..32 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04C1_1DB7
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+}
+
+/// Reasons a valid UserConfig could not be read from flash.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The config is erased, so the device was never configured
+    /// or a config write was interrupted after the erase.
+    Blank,
+    /// The config is not erased but does not start with the magic value.
+    BadMagic,
+    /// The magic value is present but the checksum does not match.
+    BadChecksum,
+}
+
+impl ConfigError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigError::Blank => "blank",
+            ConfigError::BadMagic => "corrupt (bad magic)",
+            ConfigError::BadChecksum => "corrupt (bad checksum)",
+        }
+    }
+}
+
 impl UserConfig {
     /// Attempt to read the UserConfig from flash sector 3 at 0x0800_C000.
-    /// If a valid config cannot be read, the default one is returned instead.
-    pub fn get(crc: &mut stm32f407::CRC) -> Option<UserConfig> {
-        // Read config from flash
-        let adr = FLASH_CONFIG as *const u32;
-        let cfg = unsafe { *(FLASH_CONFIG as *const UserConfig) };
+    /// If a valid config cannot be read, returns the reason why.
+    pub fn get(crc: &mut impl CrcCompute) -> core::result::Result<UserConfig, ConfigError> {
+        let mut bytes = [0u8; CONFIG_SIZE];
+        bytes.copy_from_slice(read_config_bytes());
+        UserConfig::from_bytes(&bytes, crc)
+    }
+
+    /// Decode a UserConfig from its stored form, checking its magic and checksum.
+    /// If it is not valid, returns the reason why.
+    pub fn from_bytes(bytes: &[u8; CONFIG_SIZE], crc: &mut impl CrcCompute)
+        -> core::result::Result<UserConfig, ConfigError>
+    {
+        // Distinguish an erased config from a corrupt one
+        if bytes.iter().all(|&byte| byte == 0xFF) {
+            return Err(ConfigError::Blank);
+        }
 
         // First check magic is correct
-        if cfg.magic != CONFIG_MAGIC {
+        let word = |idx: usize| {
+            u32::from_le_bytes([bytes[idx], bytes[idx + 1], bytes[idx + 2], bytes[idx + 3]])
+        };
+        let magic = word(0);
+        if magic != CONFIG_MAGIC {
+            return Err(ConfigError::BadMagic);
+        }
+
+        // Validate checksum, which covers every word before it
+        let checksum = word(CONFIG_SIZE - 4);
+        if UserConfig::checksum(bytes, crc) != checksum {
+            return Err(ConfigError::BadChecksum);
+        }
+
+        let mut cfg = UserConfig {
+            magic,
+            mac_address: [0; 6],
+            ip_address: [0; 4],
+            ip_gateway: [0; 4],
+            ip_prefix: bytes[18],
+            flags: bytes[19],
+            checksum,
+        };
+        cfg.mac_address.copy_from_slice(&bytes[4..10]);
+        cfg.ip_address.copy_from_slice(&bytes[10..14]);
+        cfg.ip_gateway.copy_from_slice(&bytes[14..18]);
+        Ok(cfg)
+    }
+
+    /// Store this config in flash at FLASH_CONFIG with the magic value and a fresh checksum.
+    ///
+    /// The whole configuration sector is erased first. With the auth feature, any stored
+    /// key is written back afterwards, and the key marker set first so that losing the key
+    /// part way through refuses commands instead of turning authentication off.
+    pub fn store(&self, crc: &mut impl CrcCompute) -> Result<()> {
+        #[cfg(feature = "auth")]
+        let key = KeyBlock::get(crc);
+        #[cfg(feature = "auth")]
+        expect_key(key.is_some())?;
+
+        erase(FLASH_CONFIG, CONFIG_SIZE)?;
+        self.program(crc)?;
+
+        #[cfg(feature = "auth")]
+        {
+            if let Some(key) = key {
+                key.program(crc)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode this config in its stored form, the inverse of `from_bytes`.
+    ///
+    /// The magic value and checksum are copied as they are, so use `seal` to make
+    /// the result valid.
+    pub fn to_bytes(&self) -> [u8; CONFIG_SIZE] {
+        let mut bytes = [0u8; CONFIG_SIZE];
+        bytes[0..4].copy_from_slice(&{ self.magic }.to_le_bytes());
+        bytes[4..10].copy_from_slice(&self.mac_address);
+        bytes[10..14].copy_from_slice(&self.ip_address);
+        bytes[14..18].copy_from_slice(&self.ip_gateway);
+        bytes[18] = self.ip_prefix;
+        bytes[19] = self.flags;
+        bytes[20..24].copy_from_slice(&{ self.checksum }.to_le_bytes());
+        bytes
+    }
+
+    /// Set the magic value and a fresh checksum in a config in its stored form.
+    pub fn seal(bytes: &mut [u8; CONFIG_SIZE], crc: &mut impl CrcCompute) {
+        bytes[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+        let checksum = UserConfig::checksum(bytes, crc);
+        bytes[CONFIG_SIZE - 4..].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// CRC of every word of a stored config before its checksum.
+    fn checksum(bytes: &[u8; CONFIG_SIZE], crc: &mut impl CrcCompute) -> u32 {
+        crc_of_words(&bytes[..CONFIG_SIZE - 4], crc)
+    }
+
+    /// Write this config to the erased configuration sector, with the magic value
+    /// and a fresh checksum.
+    fn program(&self, crc: &mut impl CrcCompute) -> Result<()> {
+        let mut bytes = self.to_bytes();
+        UserConfig::seal(&mut bytes, crc);
+        write(FLASH_CONFIG, CONFIG_SIZE, &bytes)
+    }
+}
+
+#[cfg(feature = "auth")]
+const KEY_MAGIC: u32 = 0x6B65_7921;
+
+/// Length in bytes of the key for authenticating commands.
+#[cfg(feature = "auth")]
+pub const KEY_LEN: usize = 32;
+
+/// Key for authenticating commands, stored at AUTH_KEY_ADDRESS with the auth feature.
+///
+/// It is stored as the magic value KEY_MAGIC, the key, and the CRC32 of the preceeding words.
+#[cfg(feature = "auth")]
+#[derive(Clone, Copy)]
+pub struct KeyBlock {
+    pub key: [u8; KEY_LEN],
+}
+
+/// Size of a stored KeyBlock in bytes, including its magic value and checksum.
+#[cfg(feature = "auth")]
+pub const KEY_BLOCK_SIZE: usize = 4 + KEY_LEN + 4;
+
+#[cfg(feature = "auth")]
+impl KeyBlock {
+    /// Read the stored key, or None if there is none or it is corrupt.
+    pub fn get(crc: &mut impl CrcCompute) -> Option<KeyBlock> {
+        let mut bytes = [0u8; KEY_BLOCK_SIZE];
+        bytes.copy_from_slice(memory(AUTH_KEY_ADDRESS, KEY_BLOCK_SIZE));
+        KeyBlock::from_bytes(&bytes, crc)
+    }
+
+    /// Decode a KeyBlock from its stored form, or None if its magic or checksum is wrong.
+    pub fn from_bytes(bytes: &[u8; KEY_BLOCK_SIZE], crc: &mut impl CrcCompute)
+        -> Option<KeyBlock>
+    {
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let end = KEY_BLOCK_SIZE - 4;
+        let checksum = u32::from_le_bytes([bytes[end], bytes[end + 1], bytes[end + 2],
+                                           bytes[end + 3]]);
+        if magic != KEY_MAGIC || crc_of_words(&bytes[..end], crc) != checksum {
             return None;
         }
+        let mut block = KeyBlock { key: [0; KEY_LEN] };
+        block.key.copy_from_slice(&bytes[4..end]);
+        Some(block)
+    }
 
-        // Validate checksum
-        let len = core::mem::size_of::<UserConfig>() / 4;
-        crc.cr.write(|w| w.reset().reset());
-        for idx in 0..(len - 1) {
-            let val = unsafe { *(adr.offset(idx as isize)) };
-            crc.dr.write(|w| w.dr().bits(val));
+    /// Encode this key in its stored form, with the magic value and a fresh checksum.
+    pub fn to_bytes(&self, crc: &mut impl CrcCompute) -> [u8; KEY_BLOCK_SIZE] {
+        let mut bytes = [0u8; KEY_BLOCK_SIZE];
+        let end = KEY_BLOCK_SIZE - 4;
+        bytes[..4].copy_from_slice(&KEY_MAGIC.to_le_bytes());
+        bytes[4..end].copy_from_slice(&self.key);
+        let checksum = crc_of_words(&bytes[..end], crc);
+        bytes[end..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Store `key`, or remove the stored key if None, keeping any valid configuration.
+    ///
+    /// The whole configuration sector is erased first. The key marker only records that
+    /// no key is expected while both the old and new keys are absent, so an interrupted
+    /// change of key refuses commands, while an interrupted removal leaves none.
+    ///
+    /// Each key stored when there was none, and each removal, uses a bit of the marker.
+    /// Once they have all been used, storing a key returns WriteError without changing
+    /// anything.
+    pub fn store(key: Option<[u8; KEY_LEN]>, crc: &mut impl CrcCompute) -> Result<()> {
+        let cfg = UserConfig::get(crc).ok();
+        let had_key = KeyBlock::get(crc).is_some();
+        if key.is_some() && !had_key {
+            next_marker_word()?;
+        }
+        expect_key(key.is_some() && had_key)?;
+        erase(FLASH_CONFIG, CONFIG_SIZE)?;
+        if let Some(cfg) = cfg {
+            cfg.program(crc)?;
+        }
+        if let Some(key) = key {
+            KeyBlock { key }.program(crc)?;
         }
-        let crc_computed = crc.dr.read().dr().bits();
+        expect_key(key.is_some())
+    }
 
-        if crc_computed == cfg.checksum {
-            Some(cfg.clone())
-        } else {
-            None
+    /// Write this key to the erased configuration sector.
+    fn program(&self, crc: &mut impl CrcCompute) -> Result<()> {
+        write(AUTH_KEY_ADDRESS, KEY_BLOCK_SIZE, &self.to_bytes(crc))
+    }
+}
+
+/// Returns true if the key marker records that a key should be stored, in which case
+/// commands needing a tag are refused even if the key has been lost.
+///
+/// The marker is never erased. It records a key while an odd number of its bits are clear,
+/// and is toggled by clearing the next bit.
+#[cfg(feature = "auth")]
+pub fn key_expected() -> bool {
+    let mut cleared = 0;
+    for idx in 0..AUTH_MARKER_WORDS {
+        cleared += read_word(AUTH_MARKER_ADDRESS + 4 * idx as u32).count_zeros();
+    }
+    cleared % 2 == 1
+}
+
+/// Address of the first word of the key marker with a bit left to clear,
+/// or WriteError if every bit has been used.
+#[cfg(feature = "auth")]
+fn next_marker_word() -> Result<u32> {
+    for idx in 0..AUTH_MARKER_WORDS {
+        let address = AUTH_MARKER_ADDRESS + 4 * idx as u32;
+        if read_word(address) != 0 {
+            return Ok(address);
         }
     }
+    set_error_detail(AUTH_MARKER_ADDRESS, 0);
+    Err(Error::WriteError)
+}
+
+/// Toggle the key marker if it doesn't already record `expected`.
+#[cfg(feature = "auth")]
+fn expect_key(expected: bool) -> Result<()> {
+    if key_expected() == expected {
+        return Ok(());
+    }
+    let address = next_marker_word()?;
+    let word = read_word(address);
+    // Programming can only clear bits, so this needs no erase
+    program_words(address, &(word & (word - 1)).to_le_bytes())
 }
 
-/// Try to determine if there is valid code in the user flash at 0x0801_0000.
+/// Address of the user firmware the bootloader boots.
+///
+/// There is only one user firmware slot, at FLASH_USER, but everything that needs to
+/// know which image will boot asks here, so that selecting between slots only changes this.
+pub fn active_user_address() -> u32 {
+    FLASH_USER
+}
+
+/// Try to determine if there is valid code in the active user firmware slot.
 /// Returns Some(u32) with the address to jump to if so, and None if not.
 pub fn valid_user_code() -> Option<u32> {
-    let reset_vector: u32 = unsafe { *((FLASH_USER + 4) as *const u32) };
-    if reset_vector >= FLASH_USER && reset_vector <= FLASH_END {
-        Some(FLASH_USER)
+    valid_user_code_at(active_user_address())
+}
+
+/// Try to determine if there is a valid vector table at `base`, which must be in flash.
+/// Returns Some(base) if so, and None if not, including if user firmware at `base`
+/// would overlap the bootloader or configuration.
+pub fn valid_user_code_at(base: u32) -> Option<u32> {
+    if !user_region_valid(FLASH_CONFIG, base) {
+        return None;
+    }
+    let mut vectors = [0u32; 7];
+    for (idx, vector) in vectors.iter_mut().enumerate() {
+        *vector = read_word(base + 4 * idx as u32);
+    }
+    if vector_table_valid(&vectors, base) {
+        Some(base)
     } else {
         None
     }
 }
 
-/// Check if address+length is valid for read/write flash.
-fn check_address_valid(address: u32, length: usize) -> Result<()> {
-    if address < FLASH_CONFIG {
-        Err(Error::InvalidAddress)
-    } else if address > (FLASH_END - length as u32 + 1) {
-        Err(Error::InvalidAddress)
-    } else{
-        Ok(())
+/// Check the first 7 words of a vector table loaded at `base` look like real firmware.
+///
+/// * None of the words may be erased flash (0xFFFF_FFFF).
+/// * The initial stack pointer must be within USER_STACK_RAM.
+/// * The reset vector must have the Thumb bit set and point between `base` and FLASH_END.
+///
+/// This rejects erased or partly erased sectors, and images built for a different base
+/// address, which would otherwise hard fault when booted.
+pub fn vector_table_valid(vectors: &[u32; 7], base: u32) -> bool {
+    if vectors.iter().any(|&vector| vector == 0xFFFF_FFFF) {
+        return false;
     }
+    let stack_pointer = vectors[0];
+    if stack_pointer < USER_STACK_RAM.0 || stack_pointer > USER_STACK_RAM.1 {
+        return false;
+    }
+    let reset_vector = vectors[1];
+    reset_vector & 1 == 1 && reset_vector & !1 >= base && reset_vector & !1 <= FLASH_END
+}
+
+/// Returns the address just past the `length` bytes starting at `address`,
+/// or InvalidAddress if that range runs past the end of flash.
+///
+/// The sum is checked rather than compared against FLASH_END directly, so a range
+/// which would wrap past the top of the address space is also rejected.
+fn range_end(address: u32, length: usize) -> Result<u32> {
+    if length > u32::max_value() as usize {
+        return Err(Error::InvalidAddress);
+    }
+    match address.checked_add(length as u32) {
+        Some(end) if end <= FLASH_END + 1 => Ok(end),
+        _ => Err(Error::InvalidAddress),
+    }
+}
+
+/// Check a read of `length` bytes from `address` is valid.
+///
+/// * `length` must be a multiple of 4 and no more than 1024. Zero reads nothing.
+/// * The range must lie within flash, which includes the bootloader's own sectors.
+/// * With the auth feature, the range must not include the stored key.
+fn validate_read(address: u32, length: usize) -> Result<()> {
+    check_length_valid(length)?;
+    if address < FLASH_SECTOR_ADDRESSES[0] {
+        return Err(Error::InvalidAddress);
+    }
+    range_end(address, length)?;
+    // The key must stay secret, or anyone could authenticate commands
+    #[cfg(feature = "auth")]
+    {
+        if length > 0 && address < AUTH_KEY_ADDRESS + KEY_BLOCK_SIZE as u32
+                      && address + length as u32 > AUTH_KEY_ADDRESS {
+            return Err(Error::InvalidAddress);
+        }
+    }
+    Ok(())
+}
+
+/// Check a write of `length` bytes to `address` is valid.
+///
+/// * `address` must be word aligned.
+/// * `length` must be a multiple of 4 and no more than 1024. Zero writes nothing.
+/// * The range must lie within the configuration and user sectors.
+fn validate_write(address: u32, length: usize) -> Result<()> {
+    check_length_valid(length)?;
+    if address % 4 != 0 || address < FLASH_CONFIG {
+        return Err(Error::InvalidAddress);
+    }
+    range_end(address, length)?;
+    Ok(())
+}
+
+/// Check an erase of the sectors covering `length` bytes from `address` is valid.
+///
+/// * `address` must be word aligned.
+/// * `length` must be a multiple of 4, of any size. Zero erases nothing.
+/// * The range must lie within the configuration and user sectors.
+///
+/// Returns the address just past the end of the range.
+fn validate_erase(address: u32, length: usize) -> Result<u32> {
+    if length % 4 != 0 {
+        return Err(Error::LengthNotMultiple4);
+    }
+    if address % 4 != 0 || address < FLASH_CONFIG {
+        return Err(Error::InvalidAddress);
+    }
+    range_end(address, length)
 }
 
 /// Check length is a multiple of 4 and no greater than 1024
@@ -168,7 +668,7 @@ fn check_length_correct(length: usize, data: &[u8]) -> Result<()> {
 
 /// Try to get the FLASH peripheral
 fn get_flash_peripheral() -> Result<&'static mut stm32f407::FLASH> {
-    match unsafe { FLASH.as_mut() } {
+    match unsafe { (*core::ptr::addr_of_mut!(FLASH)).as_mut() } {
         Some(flash) => Ok(flash),
         None => Err(Error::InternalError),
     }
@@ -179,43 +679,130 @@ fn unlock(flash: &mut stm32f407::FLASH) -> Result<()> {
     // Wait for any ongoing operations
     while flash.sr.read().bsy().bit_is_set() {}
 
+    // Clear any error flags left by a previous failed operation
+    flash.sr.write(|w| w.operr().set_bit().wrperr().set_bit().pgaerr().set_bit()
+                        .pgperr().set_bit().pgserr().set_bit());
+
     // Attempt unlock
     flash.keyr.write(|w| w.key().bits(0x45670123));
     flash.keyr.write(|w| w.key().bits(0xCDEF89AB));
 
     // Verify success
     match flash.cr.read().lock().is_unlocked() {
-        true => Ok(()),
-        false => Err(Error::FlashError),
+        true => { INDICATOR.flash_busy(true); Ok(()) },
+        false => {
+            set_error_detail(0, flash.sr.read().bits());
+            Err(Error::FlashError)
+        },
     }
 }
 
 /// Lock flash
+///
+/// Also flushes the data cache, which may hold the old contents of erased or written flash,
+/// so that any later read returns what is now committed to flash.
 fn lock(flash: &mut stm32f407::FLASH) {
+    while flash.sr.read().bsy().bit_is_set() {}
     flash.cr.write(|w| w.lock().locked());
+
+    // The data cache can only be reset while it is disabled
+    flash.acr.modify(|_, w| w.dcen().clear_bit());
+    flash.acr.modify(|_, w| w.dcrst().reset());
+    flash.acr.modify(|_, w| w.dcrst().not_reset().dcen().set_bit());
+
+    INDICATOR.flash_busy(false);
 }
 
 /// Erase flash sectors that cover the given address and length.
-pub fn erase(address: u32, length: usize) -> Result<()> {
-    check_address_valid(address, length)?;
+///
+/// Returns the start address and end address (exclusive) of the erased sectors.
+pub fn erase(address: u32, length: usize) -> Result<(u32, u32)> {
+    let end = validate_erase(address, length)?;
+    if length == 0 {
+        return Ok((address, address));
+    }
+    // Both ends are inclusive, so a range ending exactly on a sector boundary
+    // doesn't also erase the following sector. The range is within flash, so the
+    // last address is at most FLASH_END.
     let address_start = address;
-    let address_end = address + length as u32;
+    let address_end = end - 1;
+    let mut erased: Option<(u32, u32)> = None;
     for (idx, sector_start) in FLASH_SECTOR_ADDRESSES.iter().enumerate() {
         let sector_start = *sector_start;
         let sector_end = match FLASH_SECTOR_ADDRESSES.get(idx + 1) {
             Some(adr) => *adr - 1,
             None => FLASH_END,
         };
-        if (address_start >= sector_start && address_start <= sector_end) ||
-           (address_end   >= sector_start && address_end   <= sector_end) ||
-           (address_start <= sector_start && address_end   >= sector_end) {
-               erase_sector(idx as u8)?;
+        if address_start <= sector_end && address_end >= sector_start {
+            erase_sector(idx as u8)?;
+            // Erasing the user sector ends any deferral, so a later write of the
+            // first word isn't held back without a fresh defer
+            if sector_start <= FLASH_USER && FLASH_USER <= sector_end {
+                cancel_first_word();
+            }
+            let first = erased.map_or(sector_start, |(first, _)| first);
+            erased = Some((first, sector_end));
+        }
+    }
+    match erased {
+        Some((first, last)) => Ok((first, last + 1)),
+        None => Err(Error::InternalError),
+    }
+}
+
+/// Check that flash reads back as erased (all 0xFF) over the given address and length.
+/// Returns the address of the first word which is not blank, or None if all are blank.
+/// length must be a multiple of 4.
+pub fn first_non_blank(address: u32, length: usize) -> Option<u32> {
+    for idx in 0..(length / 4) {
+        let word_address = address + (idx * 4) as u32;
+        if read_word(word_address) != 0xFFFF_FFFF {
+            return Some(word_address);
+        }
+    }
+    None
+}
+
+/// Decompress the LZ4 block `data` and write the result to flash, as for `write`.
+///
+/// The data must decompress to exactly `length` bytes, whose CRC must be `crc`,
+/// or DecompressionError is returned without writing anything.
+#[cfg(feature = "compressed-write")]
+pub fn write_compressed(address: u32, length: usize, crc: u32, data: &[u8]) -> Result<()> {
+    check_length_valid(length)?;
+    let mut words = [0u32; 256];
+    let bytes = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 1024) };
+    match lz4::decompress(data, &mut bytes[..length]) {
+        Some(n) if n == length => (),
+        _ => return Err(Error::DecompressionError),
+    }
+    if SoftwareCrc32.compute(&words[..length / 4]) != crc {
+        return Err(Error::DecompressionError);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, length) };
+    write(address, length, bytes)
+}
+
+/// Check each word of flash from `address` is erased or already holds the word in `data`.
+///
+/// Programming can only clear bits, so writing over any other value would store
+/// the bitwise AND of the old and new words.
+#[cfg(feature = "write-guard")]
+fn check_writable(address: u32, data: &[u8]) -> Result<()> {
+    for (idx, new) in data.chunks(4).enumerate() {
+        let word_address = address + (idx * 4) as u32;
+        let old = read_word(word_address);
+        let new = u32::from_le_bytes([new[0], new[1], new[2], new[3]]);
+        if old != 0xFFFF_FFFF && old != new {
+            set_error_detail(word_address, 0);
+            return Err(Error::WriteError);
         }
     }
     Ok(())
 }
 
 /// Erase specified sector
+#[cfg(not(test))]
 fn erase_sector(sector: u8) -> Result<()> {
     if (sector as usize) >= FLASH_SECTOR_ADDRESSES.len() {
         return Err(Error::InternalError);
@@ -243,31 +830,90 @@ fn erase_sector(sector: u8) -> Result<()> {
     lock(flash);
 
     if sr.wrperr().bit_is_set() {
+        set_error_detail(FLASH_SECTOR_ADDRESSES[sector as usize], sr.bits());
+        Err(Error::WriteProtected)
+    } else if sr.pgserr().bit_is_set() || sr.pgperr().bit_is_set() || sr.pgaerr().bit_is_set() {
+        set_error_detail(FLASH_SECTOR_ADDRESSES[sector as usize], sr.bits());
         Err(Error::EraseError)
     } else {
         Ok(())
     }
 }
 
+/// Erase specified sector of the fake flash.
+#[cfg(test)]
+fn erase_sector(sector: u8) -> Result<()> {
+    if (sector as usize) >= FLASH_SECTOR_ADDRESSES.len() {
+        return Err(Error::InternalError);
+    }
+    fake::erase_sector(sector as usize);
+    Ok(())
+}
+
+/// The `length` bytes of flash from `address`.
+#[cfg(not(test))]
+fn memory(address: u32, length: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(address as *const u8, length) }
+}
+
+/// The word of flash at `address`, which must be word aligned.
+#[cfg(not(test))]
+fn read_word(address: u32) -> u32 {
+    unsafe { core::ptr::read_volatile(address as *const u32) }
+}
+
+#[cfg(test)]
+use self::fake::{memory, read_word};
+
 /// Read from flash.
 /// Returns a &[u8] if the address and length are valid.
 /// length must be a multiple of 4.
 pub fn read(address: u32, length: usize) -> Result<&'static [u8]> {
-    check_address_valid(address, length)?;
-    check_length_valid(length)?;
-    let address = address as *const _;
-    unsafe {
-        Ok(core::slice::from_raw_parts::<'static, u8>(address, length))
-    }
+    validate_read(address, length)?;
+    Ok(memory(address, length))
+}
+
+/// Read the stored UserConfig as it is in flash, whether or not it is valid.
+pub fn read_config_bytes() -> &'static [u8] {
+    memory(FLASH_CONFIG, CONFIG_SIZE)
 }
 
 /// Write to flash.
 /// Returns () on success, None on failure.
 /// length must be a multiple of 4.
+///
+/// Only returns once every word has finished programming and flash is locked again,
+/// so on success the data is committed and will read back.
+///
+/// While `defer_first_word` is in effect, the word at FLASH_USER is held back and
+/// left erased rather than programmed.
+///
+/// With the write-guard feature, returns WriteError without writing anything if any
+/// target word is neither erased nor already holds its new value.
 pub fn write(address: u32, length: usize, data: &[u8]) -> Result<()> {
-    check_address_valid(address, length)?;
-    check_length_valid(length)?;
+    validate_write(address, length)?;
     check_length_correct(length, data)?;
+    #[cfg(feature = "write-guard")]
+    check_writable(address, data)?;
+    program_words(address, data)
+}
+
+/// Returns true if `word` is the first word of the user firmware and is to be held
+/// back rather than programmed, in which case it is kept until committed.
+fn hold_back(address: u32, word: u32) -> bool {
+    ::interrupt_free(|cs| {
+        if address == FLASH_USER && DEFER_FIRST_WORD.borrow(cs).get() {
+            DEFERRED_WORD.borrow(cs).set(Some(word));
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Program already validated `data` to flash from `address`.
+#[cfg(not(test))]
+fn program_words(address: u32, data: &[u8]) -> Result<()> {
     let flash = get_flash_peripheral()?;
     unlock(flash)?;
 
@@ -277,7 +923,7 @@ pub fn write(address: u32, length: usize, data: &[u8]) -> Result<()> {
                         .psize().psize32()
                         .pg().program());
 
-    for idx in 0..(length / 4) {
+    for idx in 0..(data.len() / 4) {
         let offset = idx * 4;
         let word: u32 =
               (data[offset]   as u32)
@@ -285,6 +931,9 @@ pub fn write(address: u32, length: usize, data: &[u8]) -> Result<()> {
             | (data[offset+2] as u32) << 16
             | (data[offset+3] as u32) << 24;
         let write_address = (address + offset as u32) as *mut u32;
+        if hold_back(write_address as u32, word) {
+            continue;
+        }
         unsafe { core::ptr::write_volatile(write_address, word) };
 
         // Wait for write
@@ -292,8 +941,13 @@ pub fn write(address: u32, length: usize, data: &[u8]) -> Result<()> {
 
         // Check for errors
         let sr = flash.sr.read();
-        if sr.pgserr().bit_is_set() || sr.pgperr().bit_is_set() ||
-           sr.pgaerr().bit_is_set() || sr.wrperr().bit_is_set() {
+        if sr.wrperr().bit_is_set() {
+            set_error_detail(write_address as u32, sr.bits());
+            lock(flash);
+            return Err(Error::WriteProtected);
+        }
+        if sr.pgserr().bit_is_set() || sr.pgperr().bit_is_set() || sr.pgaerr().bit_is_set() {
+            set_error_detail(write_address as u32, sr.bits());
             lock(flash);
             return Err(Error::WriteError);
         }
@@ -303,3 +957,496 @@ pub fn write(address: u32, length: usize, data: &[u8]) -> Result<()> {
 
     Ok(())
 }
+
+/// Program already validated `data` to the fake flash from `address`.
+#[cfg(test)]
+fn program_words(address: u32, data: &[u8]) -> Result<()> {
+    for (idx, chunk) in data.chunks(4).enumerate() {
+        let word_address = address + (idx * 4) as u32;
+        let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        if !hold_back(word_address, word) {
+            fake::program_word(word_address, word);
+        }
+    }
+    Ok(())
+}
+
+/// Flash for host tests, held in RAM and starting erased.
+///
+/// As in real flash, programming a word can only clear bits, so it stores the
+/// bitwise AND of the old and new values.
+#[cfg(test)]
+pub mod fake {
+    use std::sync::{Mutex, MutexGuard};
+    use ::config::{FLASH_SECTOR_ADDRESSES, FLASH_END};
+
+    const SIZE: usize = (FLASH_END + 1 - FLASH_SECTOR_ADDRESSES[0]) as usize;
+
+    static mut MEMORY: [u8; SIZE] = [0xFF; SIZE];
+
+    static DEVICE: Mutex<()> = Mutex::new(());
+
+    /// Take exclusive use of the fake device for a test, with the flash erased and
+    /// no write or error state left over from earlier tests.
+    pub fn lock() -> MutexGuard<'static, ()> {
+        let guard = DEVICE.lock().unwrap_or_else(|err| err.into_inner());
+        reset();
+        guard
+    }
+
+    /// Erase the flash and clear the write and error state, while holding the lock.
+    pub fn reset() {
+        contents().iter_mut().for_each(|byte| *byte = 0xFF);
+        super::cancel_first_word();
+        super::take_error_detail();
+    }
+
+    /// Only used while holding the lock.
+    fn contents() -> &'static mut [u8; SIZE] {
+        unsafe { &mut *core::ptr::addr_of_mut!(MEMORY) }
+    }
+
+    /// Offset in `MEMORY` of `length` bytes from `address`, which must all be in flash.
+    fn offset(address: u32, length: usize) -> usize {
+        assert!(address >= FLASH_SECTOR_ADDRESSES[0] &&
+                address as u64 + length as u64 <= FLASH_END as u64 + 1,
+                "0x{:08X} length {} is not in flash", address, length);
+        (address - FLASH_SECTOR_ADDRESSES[0]) as usize
+    }
+
+    pub fn memory(address: u32, length: usize) -> &'static [u8] {
+        let start = offset(address, length);
+        &contents()[start..start + length]
+    }
+
+    pub fn read_word(address: u32) -> u32 {
+        let bytes = memory(address, 4);
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub fn program_word(address: u32, word: u32) {
+        let start = offset(address, 4);
+        let bytes = (read_word(address) & word).to_le_bytes();
+        contents()[start..start + 4].copy_from_slice(&bytes);
+    }
+
+    pub fn erase_sector(sector: usize) {
+        let start = offset(FLASH_SECTOR_ADDRESSES[sector], 0);
+        let end = match FLASH_SECTOR_ADDRESSES.get(sector + 1) {
+            Some(&next) => offset(next, 0),
+            None => SIZE,
+        };
+        contents()[start..end].iter_mut().for_each(|byte| *byte = 0xFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Op { Read, Write, Erase }
+
+    fn validate(op: Op, address: u32, length: usize) -> Result<()> {
+        match op {
+            Op::Read => validate_read(address, length),
+            Op::Write => validate_write(address, length),
+            Op::Erase => validate_erase(address, length).map(|_| ()),
+        }
+    }
+
+    /// Every rule of each operation, with the addresses and lengths either side of it.
+    /// This is the reference for which reads, writes and erases the protocol allows.
+    #[test]
+    fn validation_table() {
+        use self::Op::*;
+        let last_sector = FLASH_SECTOR_ADDRESSES[FLASH_SECTOR_ADDRESSES.len() - 1];
+        let table: &[(Op, u32, usize, Result<()>)] = &[
+            // Alignment: only writes and erases need a word aligned address
+            (Read,  FLASH_USER + 1, 4, Ok(())),
+            (Write, FLASH_USER + 1, 4, Err(Error::InvalidAddress)),
+            (Write, FLASH_USER + 2, 4, Err(Error::InvalidAddress)),
+            (Erase, FLASH_USER + 2, 4, Err(Error::InvalidAddress)),
+            (Read,  FLASH_USER, 3, Err(Error::LengthNotMultiple4)),
+            (Write, FLASH_USER, 3, Err(Error::LengthNotMultiple4)),
+            (Erase, FLASH_USER, 3, Err(Error::LengthNotMultiple4)),
+
+            // Length limit: reads and writes carry at most 1024 bytes, erases any length
+            (Read,  FLASH_USER, 1024, Ok(())),
+            (Read,  FLASH_USER, 1028, Err(Error::LengthTooLong)),
+            (Write, FLASH_USER, 1024, Ok(())),
+            (Write, FLASH_USER, 1028, Err(Error::LengthTooLong)),
+            (Erase, FLASH_USER, 0x2_0000, Ok(())),
+
+            // Zero length does nothing, but the address must still be valid
+            (Read,  FLASH_USER, 0, Ok(())),
+            (Write, FLASH_USER, 0, Ok(())),
+            (Erase, FLASH_USER, 0, Ok(())),
+            (Read,  0, 0, Err(Error::InvalidAddress)),
+            (Write, FLASH_SECTOR_ADDRESSES[0], 0, Err(Error::InvalidAddress)),
+            (Erase, FLASH_SECTOR_ADDRESSES[0], 0, Err(Error::InvalidAddress)),
+
+            // Below flash
+            (Read,  FLASH_SECTOR_ADDRESSES[0] - 4, 4, Err(Error::InvalidAddress)),
+            (Write, FLASH_SECTOR_ADDRESSES[0] - 4, 4, Err(Error::InvalidAddress)),
+            (Erase, FLASH_SECTOR_ADDRESSES[0] - 4, 4, Err(Error::InvalidAddress)),
+
+            // Bootloader sectors: readable, but never written or erased
+            (Read,  FLASH_SECTOR_ADDRESSES[0], 4, Ok(())),
+            (Read,  FLASH_CONFIG - 4, 8, Ok(())),
+            (Write, FLASH_SECTOR_ADDRESSES[0], 4, Err(Error::InvalidAddress)),
+            (Write, FLASH_CONFIG - 4, 4, Err(Error::InvalidAddress)),
+            (Write, FLASH_CONFIG - 4, 8, Err(Error::InvalidAddress)),
+            (Erase, FLASH_SECTOR_ADDRESSES[0], 4, Err(Error::InvalidAddress)),
+            (Erase, FLASH_CONFIG - 4, 8, Err(Error::InvalidAddress)),
+
+            // Configuration sector: may be read, written and erased
+            (Read,  FLASH_CONFIG, 4, Ok(())),
+            (Write, FLASH_CONFIG, 4, Ok(())),
+            (Erase, FLASH_CONFIG, 4, Ok(())),
+            (Write, FLASH_USER - 4, 8, Ok(())),
+
+            // End of flash
+            (Read,  FLASH_END - 3, 4, Ok(())),
+            (Read,  FLASH_END - 3, 8, Err(Error::InvalidAddress)),
+            (Read,  FLASH_END + 1, 4, Err(Error::InvalidAddress)),
+            (Write, FLASH_END - 3, 4, Ok(())),
+            (Write, FLASH_END - 3, 8, Err(Error::InvalidAddress)),
+            (Write, FLASH_END + 1, 4, Err(Error::InvalidAddress)),
+            (Erase, last_sector, (FLASH_END + 1 - last_sector) as usize, Ok(())),
+            (Erase, last_sector, (FLASH_END + 5 - last_sector) as usize, Err(Error::InvalidAddress)),
+            (Erase, FLASH_END + 1, 4, Err(Error::InvalidAddress)),
+
+            // Ranges whose end overflows the address space
+            (Read,  0xFFFF_FFFC, 8, Err(Error::InvalidAddress)),
+            (Write, 0xFFFF_FFFC, 8, Err(Error::InvalidAddress)),
+            (Erase, 0xFFFF_FFFC, 8, Err(Error::InvalidAddress)),
+            (Erase, FLASH_USER, 0xFFFF_FFFC, Err(Error::InvalidAddress)),
+            (Erase, FLASH_USER, usize::max_value() - 3, Err(Error::InvalidAddress)),
+        ];
+        for (row, &(op, address, length, expected)) in table.iter().enumerate() {
+            assert!(validate(op, address, length) == expected, "row {}", row);
+        }
+    }
+
+    #[test]
+    fn erase_returns_range_end() {
+        assert!(validate_erase(FLASH_USER, 8) == Ok(FLASH_USER + 8));
+        assert!(validate_erase(FLASH_USER, 0) == Ok(FLASH_USER));
+        assert!(validate_erase(FLASH_CONFIG, (FLASH_END + 1 - FLASH_CONFIG) as usize)
+                == Ok(FLASH_END + 1));
+    }
+
+    /// An erase starting in the last sector is refused if it runs past the end of flash,
+    /// however far, before any sector is erased.
+    #[test]
+    fn erase_past_end_of_flash() {
+        let _device = fake::lock();
+        let last_sector = FLASH_SECTOR_ADDRESSES[FLASH_SECTOR_ADDRESSES.len() - 1];
+        let to_end = (FLASH_END + 1 - last_sector) as usize;
+        fake::program_word(last_sector, 0);
+        for &length in &[to_end + 4, to_end + 0x1_0000, u32::max_value() as usize - 3] {
+            assert!(erase(last_sector, length) == Err(Error::InvalidAddress), "{}", length);
+            assert!(erase(FLASH_END - 3, length) == Err(Error::InvalidAddress), "{}", length);
+            assert_eq!(fake::read_word(last_sector), 0);
+        }
+
+        assert!(erase(last_sector + 4, to_end - 4) == Ok((last_sector, FLASH_END + 1)));
+        assert_eq!(fake::read_word(last_sector), 0xFFFF_FFFF);
+    }
+
+    /// A plausible vector table for firmware at FLASH_USER.
+    const GOOD_VECTORS: [u32; 7] = [
+        0x2002_0000, FLASH_USER + 0x1C5, FLASH_USER + 0x201, FLASH_USER + 0x203,
+        FLASH_USER + 0x205, FLASH_USER + 0x207, FLASH_USER + 0x209,
+    ];
+
+    #[test]
+    fn vector_table_checks() {
+        assert!(vector_table_valid(&GOOD_VECTORS, FLASH_USER));
+        let with = |idx: usize, value: u32| {
+            let mut vectors = GOOD_VECTORS;
+            vectors[idx] = value;
+            vectors
+        };
+        let table: &[([u32; 7], bool)] = &[
+            // Stack pointer must be within RAM, inclusive of its end
+            (with(0, USER_STACK_RAM.0), true),
+            (with(0, USER_STACK_RAM.0 - 4), false),
+            (with(0, USER_STACK_RAM.1 + 4), false),
+            (with(0, 0), false),
+            // Reset vector must be Thumb, and between the base and the end of flash
+            (with(1, FLASH_USER + 0x1C4), false),
+            (with(1, FLASH_USER + 1), true),
+            (with(1, FLASH_USER - 0x3F), false),
+            (with(1, FLASH_END), true),
+            (with(1, FLASH_END + 2), false),
+            // No vector may be erased, such as from a partly erased image
+            (with(0, 0xFFFF_FFFF), false),
+            (with(1, 0xFFFF_FFFF), false),
+            (with(6, 0xFFFF_FFFF), false),
+        ];
+        for (row, &(vectors, expected)) in table.iter().enumerate() {
+            assert!(vector_table_valid(&vectors, FLASH_USER) == expected, "row {}", row);
+        }
+        assert!(!vector_table_valid(&[0xFFFF_FFFF; 7], FLASH_USER));
+        // An image built for the user address doesn't boot from a later base
+        assert!(!vector_table_valid(&GOOD_VECTORS, FLASH_SECTOR_ADDRESSES[5]));
+    }
+
+    #[test]
+    fn valid_user_code_reads_flash() {
+        let _device = fake::lock();
+        assert!(valid_user_code().is_none());
+        let bytes: Vec<u8> = GOOD_VECTORS.iter().flat_map(|word| word.to_le_bytes().to_vec())
+                                         .collect();
+        assert!(write(FLASH_USER, bytes.len(), &bytes) == Ok(()));
+        assert!(valid_user_code() == Some(FLASH_USER));
+        assert!(valid_user_code_at(FLASH_CONFIG).is_none());
+    }
+
+    fn vector_bytes() -> Vec<u8> {
+        GOOD_VECTORS.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect()
+    }
+
+    /// A deferred first word leaves the stack pointer erased, so the firmware isn't valid.
+    #[test]
+    fn deferred_first_word_stays_erased() {
+        let _device = fake::lock();
+        let bytes = vector_bytes();
+        defer_first_word();
+        assert!(write(FLASH_USER, bytes.len(), &bytes) == Ok(()));
+        assert_eq!(fake::read_word(FLASH_USER), 0xFFFF_FFFF);
+        assert_eq!(fake::memory(FLASH_USER + 4, bytes.len() - 4), &bytes[4..]);
+        assert!(valid_user_code().is_none());
+    }
+
+    /// Committing programs only the word which was held back.
+    #[test]
+    fn commit_first_word_checks_word() {
+        let _device = fake::lock();
+        let bytes = vector_bytes();
+        defer_first_word();
+        assert!(write(FLASH_USER, bytes.len(), &bytes) == Ok(()));
+        assert!(commit_first_word(GOOD_VECTORS[0] ^ 1) == Err(Error::WriteError));
+        assert_eq!(fake::read_word(FLASH_USER), 0xFFFF_FFFF);
+        assert!(commit_first_word(GOOD_VECTORS[0]) == Ok(()));
+        assert_eq!(fake::read_word(FLASH_USER), GOOD_VECTORS[0]);
+        assert!(valid_user_code() == Some(FLASH_USER));
+        // Nothing is held once committed
+        assert!(commit_first_word(GOOD_VECTORS[0]) == Err(Error::WriteError));
+    }
+
+    /// Erasing the user sector drops a held word and ends the deferral.
+    #[test]
+    fn erase_drops_deferred_word() {
+        let _device = fake::lock();
+        let bytes = vector_bytes();
+        defer_first_word();
+        assert!(write(FLASH_USER, bytes.len(), &bytes) == Ok(()));
+        assert!(erase(FLASH_USER, 4).is_ok());
+        assert!(commit_first_word(GOOD_VECTORS[0]) == Err(Error::WriteError));
+        assert!(write(FLASH_USER, bytes.len(), &bytes) == Ok(()));
+        assert_eq!(fake::read_word(FLASH_USER), GOOD_VECTORS[0]);
+        assert!(valid_user_code() == Some(FLASH_USER));
+    }
+
+    /// The default config as the client encodes it, with the magic value and checksum.
+    const STORED_DEFAULT: [u8; CONFIG_SIZE] = [
+        0x70, 0x78, 0x79, 0x67, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04, 0x0A, 0x01,
+        0x01, 0x0A, 0x0A, 0x01, 0x01, 0x01, 0x18, 0x00, 0x06, 0xE6, 0x48, 0x02,
+    ];
+
+    #[test]
+    fn config_from_bytes() {
+        let cfg = match UserConfig::from_bytes(&STORED_DEFAULT, &mut SoftwareCrc32) {
+            Ok(cfg) => cfg,
+            Err(err) => panic!("config {}", err.as_str()),
+        };
+        assert_eq!(cfg.mac_address, [0x02, 0x00, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(cfg.ip_address, [10, 1, 1, 10]);
+        assert_eq!(cfg.ip_gateway, [10, 1, 1, 1]);
+        assert_eq!(cfg.ip_prefix, 24);
+        assert_eq!(cfg.flags, 0);
+        assert_eq!(cfg.to_bytes(), STORED_DEFAULT);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut cfg = DEFAULT_CONFIG;
+        cfg.mac_address = [0x02, 0xAB, 0xCD, 0xEF, 0x12, 0x34];
+        cfg.ip_address = [192, 168, 5, 20];
+        cfg.ip_gateway = [192, 168, 5, 1];
+        cfg.ip_prefix = 16;
+        cfg.flags = CONFIG_FLAG_LOCKED;
+        let mut bytes = cfg.to_bytes();
+        UserConfig::seal(&mut bytes, &mut SoftwareCrc32);
+        let decoded = match UserConfig::from_bytes(&bytes, &mut SoftwareCrc32) {
+            Ok(decoded) => decoded,
+            Err(err) => panic!("config {}", err.as_str()),
+        };
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.flags, CONFIG_FLAG_LOCKED);
+        assert_eq!(bytes[20..], [0x61, 0xE8, 0xC8, 0x3F]);
+    }
+
+    #[test]
+    fn config_from_bytes_errors() {
+        let check = |bytes: &[u8; CONFIG_SIZE], expected| {
+            match UserConfig::from_bytes(bytes, &mut SoftwareCrc32) {
+                Ok(_) => panic!("invalid config accepted"),
+                Err(err) => assert!(err == expected, "got {}", err.as_str()),
+            }
+        };
+        check(&[0xFF; CONFIG_SIZE], ConfigError::Blank);
+        check(&[0x00; CONFIG_SIZE], ConfigError::BadMagic);
+        let mut bytes = STORED_DEFAULT;
+        bytes[0] ^= 1;
+        check(&bytes, ConfigError::BadMagic);
+        for idx in 4..CONFIG_SIZE {
+            let mut bytes = STORED_DEFAULT;
+            bytes[idx] ^= 0x10;
+            check(&bytes, ConfigError::BadChecksum);
+        }
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn key_block_round_trip() {
+        let mut block = KeyBlock { key: [0; KEY_LEN] };
+        for (idx, byte) in block.key.iter_mut().enumerate() {
+            *byte = idx as u8 * 7;
+        }
+        let bytes = block.to_bytes(&mut SoftwareCrc32);
+        assert_eq!(bytes[..4], KEY_MAGIC.to_le_bytes());
+        assert_eq!(bytes[4..KEY_BLOCK_SIZE - 4], block.key);
+        match KeyBlock::from_bytes(&bytes, &mut SoftwareCrc32) {
+            Some(decoded) => assert_eq!(decoded.key, block.key),
+            None => panic!("stored key rejected"),
+        }
+        for idx in 0..KEY_BLOCK_SIZE {
+            let mut corrupt = bytes;
+            corrupt[idx] ^= 0x01;
+            assert!(KeyBlock::from_bytes(&corrupt, &mut SoftwareCrc32).is_none(), "byte {}", idx);
+        }
+        assert!(KeyBlock::from_bytes(&[0xFF; KEY_BLOCK_SIZE], &mut SoftwareCrc32).is_none());
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn read_refuses_key() {
+        let key_end = AUTH_KEY_ADDRESS + KEY_BLOCK_SIZE as u32;
+        assert!(validate_read(AUTH_KEY_ADDRESS - 4, 4) == Ok(()));
+        assert!(validate_read(AUTH_KEY_ADDRESS - 4, 8) == Err(Error::InvalidAddress));
+        assert!(validate_read(key_end - 4, 4) == Err(Error::InvalidAddress));
+        assert!(validate_read(key_end, 4) == Ok(()));
+        assert!(validate_read(AUTH_KEY_ADDRESS, 0) == Ok(()));
+    }
+
+    /// A power cut after erasing the configuration sector, before the key is written back,
+    /// leaves authentication on with no key, so every tag is refused.
+    #[cfg(feature = "auth")]
+    #[test]
+    fn lost_key_fails_closed() {
+        let _device = fake::lock();
+        assert!(!::auth::enabled());
+        assert!(KeyBlock::store(Some([0x11; KEY_LEN]), &mut SoftwareCrc32) == Ok(()));
+        assert!(key_expected());
+
+        // Storing a config or a new key keeps the marker
+        assert!(DEFAULT_CONFIG.store(&mut SoftwareCrc32) == Ok(()));
+        assert!(KeyBlock::store(Some([0x22; KEY_LEN]), &mut SoftwareCrc32) == Ok(()));
+        assert!(key_expected());
+
+        fake::erase_sector(sector_index(FLASH_CONFIG).unwrap());
+        assert!(KeyBlock::get(&mut SoftwareCrc32).is_none());
+        assert!(::auth::enabled());
+        let nonce = ::auth::new_nonce().unwrap();
+        let tag = ::sha256::hmac(&[0x22; KEY_LEN], &[&nonce, &0u32.to_le_bytes()]);
+        assert!(!::auth::verify(0, &[], &tag));
+    }
+
+    /// Removing the key turns authentication off, and a key stored before the marker
+    /// existed gets it on the next store.
+    #[cfg(feature = "auth")]
+    #[test]
+    fn key_marker_follows_key() {
+        let _device = fake::lock();
+        assert!(KeyBlock::store(Some([0x11; KEY_LEN]), &mut SoftwareCrc32) == Ok(()));
+        assert!(KeyBlock::store(None, &mut SoftwareCrc32) == Ok(()));
+        assert!(!key_expected());
+        assert!(!::auth::enabled());
+
+        let block = KeyBlock { key: [0x33; KEY_LEN] };
+        assert!(block.program(&mut SoftwareCrc32) == Ok(()));
+        assert!(!key_expected());
+        assert!(DEFAULT_CONFIG.store(&mut SoftwareCrc32) == Ok(()));
+        assert!(key_expected());
+        assert!(KeyBlock::get(&mut SoftwareCrc32).map(|block| block.key) == Some([0x33; KEY_LEN]));
+    }
+
+    /// Once every bit of the marker is used, storing a key is refused before anything
+    /// is erased, but the last key can still be removed.
+    #[cfg(feature = "auth")]
+    #[test]
+    fn key_marker_exhausted() {
+        let _device = fake::lock();
+        for _ in 0..AUTH_MARKER_WORDS * 16 {
+            assert!(KeyBlock::store(Some([0x11; KEY_LEN]), &mut SoftwareCrc32) == Ok(()));
+            assert!(KeyBlock::store(None, &mut SoftwareCrc32) == Ok(()));
+        }
+        assert!(DEFAULT_CONFIG.store(&mut SoftwareCrc32) == Ok(()));
+        assert!(KeyBlock::store(Some([0x11; KEY_LEN]), &mut SoftwareCrc32) == Err(Error::WriteError));
+        assert!(KeyBlock::get(&mut SoftwareCrc32).is_none());
+        assert!(UserConfig::get(&mut SoftwareCrc32).is_ok());
+        assert!(!::auth::enabled());
+    }
+
+    /// A word which already holds other data is refused, and nothing else is written.
+    #[cfg(feature = "write-guard")]
+    #[test]
+    fn write_guard_refuses_programmed_word() {
+        let _device = fake::lock();
+        assert!(write(FLASH_USER + 4, 4, &0x1234_5678u32.to_le_bytes()) == Ok(()));
+
+        let data = [0xAA; 8];
+        assert!(write(FLASH_USER, 8, &data) == Err(Error::WriteError));
+        assert_eq!(fake::read_word(FLASH_USER), 0xFFFF_FFFF);
+        assert_eq!(fake::read_word(FLASH_USER + 4), 0x1234_5678);
+        match take_error_detail() {
+            Some(detail) => assert_eq!(detail.address, FLASH_USER + 4),
+            None => panic!("no error detail"),
+        }
+
+        // Writing the value a word already holds is harmless, so is allowed
+        let mut data = [0xAA; 8];
+        data[4..].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        assert!(write(FLASH_USER, 8, &data) == Ok(()));
+        assert_eq!(fake::memory(FLASH_USER, 8), &data[..]);
+    }
+
+    /// Without the guard, writing over a programmed word stores the AND of both.
+    #[cfg(not(feature = "write-guard"))]
+    #[test]
+    fn write_over_programmed_word() {
+        let _device = fake::lock();
+        assert!(write(FLASH_USER, 4, &0x1234_5678u32.to_le_bytes()) == Ok(()));
+        assert!(write(FLASH_USER, 4, &0x0F0F_0F0Fu32.to_le_bytes()) == Ok(()));
+        assert_eq!(fake::read_word(FLASH_USER), 0x0204_0608);
+    }
+
+    /// A block decompressing to fewer bytes than the length given writes nothing.
+    #[cfg(feature = "compressed-write")]
+    #[test]
+    fn write_compressed_short_block() {
+        let _device = fake::lock();
+        let block = [0x40, 1, 2, 3, 4];
+        let crc = SoftwareCrc32.compute(&[0x0403_0201, 0]);
+        assert!(write_compressed(FLASH_USER, 8, crc, &block) == Err(Error::DecompressionError));
+        assert!(fake::memory(FLASH_USER, 8).iter().all(|&byte| byte == 0xFF));
+
+        let crc = SoftwareCrc32.compute(&[0x0403_0201]);
+        assert!(write_compressed(FLASH_USER, 4, crc, &block) == Ok(()));
+        assert_eq!(fake::read_word(FLASH_USER), 0x0403_0201);
+    }
+}
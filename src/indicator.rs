@@ -0,0 +1,43 @@
+//! Hooks for board specific indications of bootloader activity.
+//!
+//! Implement `Indicator` in config.rs to drive LEDs, buzzers, relays etc.
+//! All methods default to doing nothing, so only the events of interest need implementing.
+use ::Error;
+
+/// Why the bootloader is running instead of the user firmware.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EnterCause {
+    /// No valid user firmware was found.
+    NoUserCode,
+    /// `should_enter_bootloader` asked for the bootloader to run.
+    Requested,
+}
+
+pub trait Indicator {
+    /// Called once GPIOs are configured, at the start of bootloader mode.
+    fn entered_bootloader(&self, _cause: EnterCause) {}
+
+    /// Called when the ethernet link comes up.
+    fn link_up(&self) {}
+
+    /// Called when waiting for the ethernet link to come up.
+    fn link_down(&self) {}
+
+    /// Called every LINK_POLL_MS while the link has still not come up, with the ms
+    /// waited since the network started. Useful for blinking a warning, as without user
+    /// firmware to fall back to the board otherwise appears dead until a cable is connected.
+    fn link_waiting(&self, _ms: u32) {}
+
+    /// Called when a command is received, with its command code.
+    fn command_started(&self, _cmd: u32) {}
+
+    /// Called when the status of a command is sent.
+    fn command_finished(&self, _status: Error) {}
+
+    /// Called with true when flash is unlocked for erasing or writing,
+    /// and false when it is locked again.
+    fn flash_busy(&self, _busy: bool) {}
+
+    /// Called just before resetting to boot the user firmware.
+    fn about_to_boot(&self) {}
+}
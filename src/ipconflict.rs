@@ -0,0 +1,113 @@
+//! Detection of other hosts using the bootloader's IP address, with the ip-conflict feature.
+//!
+//! Once the link is up, ARP probes for our own address are sent as described in RFC 5227,
+//! and every ARP packet received is checked for another MAC address claiming or probing
+//! for it. smoltcp handles ARP itself and its raw sockets only carry IP packets, so the
+//! probes are sent and received frames inspected directly through the ethernet device.
+use core::cell::Cell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+use smoltcp::phy::{Device, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr};
+use smoltcp::wire::{ArpOperation, ArpPacket, ArpRepr, Ipv4Address};
+
+use ::ethernet::EthernetDevice;
+use ::config::{ARP_PROBE_COUNT, ARP_PROBE_INTERVAL_MS};
+
+/// Our MAC and IP address, set by `set_addresses`.
+static OWN: Mutex<Cell<Option<(EthernetAddress, Ipv4Address)>>> = Mutex::new(Cell::new(None));
+
+/// MAC address of the first other host found using our IP address.
+static CONFLICT: Mutex<Cell<Option<EthernetAddress>>> = Mutex::new(Cell::new(None));
+
+/// Number of probes sent so far, and the time in ms the next is due.
+static PROBES: Mutex<Cell<(u32, i64)>> = Mutex::new(Cell::new((0, 0)));
+
+/// Length of an ARP probe frame: an ethernet header and an ARP packet for IPv4.
+const PROBE_LEN: usize = 14 + 28;
+
+/// Set the addresses to check for, whenever either changes.
+pub fn set_addresses(mac: EthernetAddress, ip: Ipv4Address) {
+    ::interrupt_free(|cs| OWN.borrow(cs).set(Some((mac, ip))));
+}
+
+/// MAC address of another host found using our IP address, if any.
+pub fn conflict() -> Option<EthernetAddress> {
+    ::interrupt_free(|cs| CONFLICT.borrow(cs).get())
+}
+
+/// Send the next ARP probe if one is due. Call on every poll once the link is up.
+pub fn poll(eth_dev: &mut EthernetDevice, time_ms: i64) {
+    let own = ::interrupt_free(|cs| OWN.borrow(cs).get());
+    let (mac, ip) = match own {
+        Some(own) => own,
+        None => return,
+    };
+    let (sent, due) = ::interrupt_free(|cs| PROBES.borrow(cs).get());
+    if sent >= ARP_PROBE_COUNT || time_ms < due {
+        return;
+    }
+    if let Some(token) = eth_dev.transmit() {
+        token.consume(Instant::from_millis(time_ms), PROBE_LEN, |buf| {
+            write_probe(buf, mac, ip);
+            Ok(())
+        }).ok();
+        let next = (sent + 1, time_ms + ARP_PROBE_INTERVAL_MS as i64);
+        ::interrupt_free(|cs| PROBES.borrow(cs).set(next));
+    }
+}
+
+/// Write an ARP probe asking who has `ip`, from `mac` with no sender IP address.
+fn write_probe(buf: &mut [u8], mac: EthernetAddress, ip: Ipv4Address) {
+    let mut frame = EthernetFrame::new_unchecked(buf);
+    EthernetRepr {
+        src_addr: mac,
+        dst_addr: EthernetAddress::BROADCAST,
+        ethertype: EthernetProtocol::Arp,
+    }.emit(&mut frame);
+    let mut packet = ArpPacket::new_unchecked(frame.payload_mut());
+    ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr: mac,
+        source_protocol_addr: Ipv4Address::UNSPECIFIED,
+        target_hardware_addr: EthernetAddress([0; 6]),
+        target_protocol_addr: ip,
+    }.emit(&mut packet);
+}
+
+/// Check a received frame for another host sending ARP from our IP address,
+/// or probing for it.
+pub fn inspect(buf: &[u8]) {
+    let own = ::interrupt_free(|cs| OWN.borrow(cs).get());
+    let (mac, ip) = match own {
+        Some(own) => own,
+        None => return,
+    };
+    let frame = match EthernetFrame::new_checked(buf) {
+        Ok(frame) if frame.ethertype() == EthernetProtocol::Arp => frame,
+        _ => return,
+    };
+    let repr = ArpPacket::new_checked(frame.payload()).and_then(|p| ArpRepr::parse(&p));
+    if let Ok(ArpRepr::EthernetIpv4 { source_hardware_addr, source_protocol_addr,
+                                      target_protocol_addr, operation, .. }) = repr
+    {
+        let claims = source_protocol_addr == ip;
+        let probes = operation == ArpOperation::Request
+                     && source_protocol_addr == Ipv4Address::UNSPECIFIED
+                     && target_protocol_addr == ip;
+        if source_hardware_addr != mac && (claims || probes) {
+            let first = ::interrupt_free(|cs| {
+                let conflict = CONFLICT.borrow(cs);
+                let first = conflict.get().is_none();
+                if first {
+                    conflict.set(Some(source_hardware_addr));
+                }
+                first
+            });
+            if first {
+                print!(" IP address conflict detected.\n");
+            }
+        }
+    }
+}
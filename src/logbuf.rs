@@ -0,0 +1,47 @@
+//! Copy of everything printed by the bootloader, kept in RAM so it can be fetched
+//! with the get log command when no debugger is attached to show the semihosting output.
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+
+use ::config::LOG_BUFFER_SIZE;
+
+struct Ring {
+    buf: [u8; LOG_BUFFER_SIZE],
+    /// Index the next byte is written to, which is also the oldest byte once full.
+    next: usize,
+    full: bool,
+}
+
+static LOG: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring {
+    buf: [0u8; LOG_BUFFER_SIZE], next: 0, full: false,
+}));
+
+/// Append `bytes` to the log, overwriting the oldest output once it is full.
+///
+/// Safe to call from both the main thread and the SysTick handler.
+pub fn write(bytes: &[u8]) {
+    ::interrupt_free(|cs| {
+        let mut ring = LOG.borrow(cs).borrow_mut();
+        for &byte in bytes {
+            let next = ring.next;
+            ring.buf[next] = byte;
+            ring.next = (next + 1) % LOG_BUFFER_SIZE;
+            if ring.next == 0 {
+                ring.full = true;
+            }
+        }
+    });
+}
+
+/// Call `f` with the log contents as two slices, which together hold the output
+/// in order with the oldest first.
+pub fn with_contents<F: FnOnce(&[u8], &[u8])>(f: F) {
+    ::interrupt_free(|cs| {
+        let ring = LOG.borrow(cs).borrow();
+        if ring.full {
+            f(&ring.buf[ring.next..], &ring.buf[..ring.next]);
+        } else {
+            f(&ring.buf[..ring.next], &[]);
+        }
+    });
+}
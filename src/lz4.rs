@@ -0,0 +1,145 @@
+//! Decoder for the LZ4 block format, used by the compressed write command.
+//!
+//! Only single blocks are supported, without the LZ4 frame header, and the output
+//! is bounded by the destination slice, so no memory beyond it is needed.
+
+/// Decompress the LZ4 block `src` into `dst`.
+///
+/// Returns the number of bytes written to `dst`, or None if `src` is malformed
+/// or would decompress to more than `dst.len()` bytes.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut s = 0;
+    let mut d = 0;
+    loop {
+        let token = *src.get(s)?;
+        s += 1;
+
+        // Copy literals
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(src, &mut s)?;
+        }
+        let end = s.checked_add(literals)?;
+        dst.get_mut(d..d + literals)?.copy_from_slice(src.get(s..end)?);
+        s = end;
+        d += literals;
+
+        // The final sequence has literals only
+        if s == src.len() {
+            return Some(d);
+        }
+
+        // Copy match from earlier output, which may overlap the bytes being written
+        let offset = u16::from_le_bytes([*src.get(s)?, *src.get(s + 1)?]) as usize;
+        s += 2;
+        if offset == 0 || offset > d {
+            return None;
+        }
+        let mut length = (token & 0x0F) as usize + 4;
+        if token & 0x0F == 15 {
+            length += read_length(src, &mut s)?;
+        }
+        if length > dst.len() - d {
+            return None;
+        }
+        for idx in d..(d + length) {
+            dst[idx] = dst[idx - offset];
+        }
+        d += length;
+    }
+}
+
+/// Read the extra bytes of a literal or match length, each adding up to 255.
+fn read_length(src: &[u8], s: &mut usize) -> Option<usize> {
+    let mut length = 0;
+    loop {
+        let byte = *src.get(*s)?;
+        *s += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_only() {
+        let mut dst = [0u8; 8];
+        assert_eq!(decompress(&[0x50, 1, 2, 3, 4, 5], &mut dst), Some(5));
+        assert_eq!(&dst[..5], &[1, 2, 3, 4, 5]);
+
+        // 15 or more literals need an extra length byte
+        let mut src = vec![0xF0, 5];
+        src.extend(0..20);
+        let mut dst = [0u8; 20];
+        assert_eq!(decompress(&src, &mut dst), Some(20));
+        assert_eq!(&dst[..], &src[2..]);
+    }
+
+    /// A match with offset 1 repeats the last byte, reading bytes it has just written.
+    #[test]
+    fn overlapping_match() {
+        let src = [0x1F, 0xAB, 0x01, 0x00, 3, 0x10, 0xCD];
+        let mut dst = [0u8; 24];
+        assert_eq!(decompress(&src, &mut dst), Some(24));
+        assert!(dst[..23].iter().all(|&byte| byte == 0xAB));
+        assert_eq!(dst[23], 0xCD);
+    }
+
+    /// Offsets must point back into the output written so far.
+    #[test]
+    fn bad_offsets() {
+        let mut dst = [0u8; 32];
+        assert_eq!(decompress(&[0x20, 1, 2, 0x00, 0x00, 0x10, 3], &mut dst), None);
+        assert_eq!(decompress(&[0x20, 1, 2, 0x03, 0x00, 0x10, 3], &mut dst), None);
+        assert_eq!(decompress(&[0x00, 0x01, 0x00, 0x10, 3], &mut dst), None);
+        assert_eq!(decompress(&[0x20, 1, 2, 0x02, 0x00, 0x10, 3], &mut dst), Some(7));
+    }
+
+    /// Input ending part way through a token, offset or length is refused.
+    #[test]
+    fn truncated() {
+        let mut dst = [0u8; 64];
+        // No token
+        assert_eq!(decompress(&[], &mut dst), None);
+        // Fewer literals than the token says
+        assert_eq!(decompress(&[0x30, 1, 2], &mut dst), None);
+        // Half an offset
+        assert_eq!(decompress(&[0x10, 1, 0x01], &mut dst), None);
+        // Missing literal and match length bytes
+        assert_eq!(decompress(&[0xF0], &mut dst), None);
+        assert_eq!(decompress(&[0xF0, 255], &mut dst), None);
+        assert_eq!(decompress(&[0x1F, 1, 0x01, 0x00], &mut dst), None);
+        // No final literals sequence after a match
+        assert_eq!(decompress(&[0x10, 1, 0x01, 0x00], &mut dst), None);
+    }
+
+    /// Output which wouldn't fit in `dst` is refused, from literals or a match.
+    #[test]
+    fn output_past_dst() {
+        let mut dst = [0u8; 4];
+        assert_eq!(decompress(&[0x50, 1, 2, 3, 4, 5], &mut dst), None);
+        assert_eq!(decompress(&[0x10, 1, 0x01, 0x00, 0x00], &mut dst), None);
+        let mut dst = [0u8; 5];
+        assert_eq!(decompress(&[0x10, 1, 0x01, 0x00, 0x00], &mut dst), Some(5));
+    }
+
+    /// A block from blethrs.py `lz4_compress`, with long literal and match lengths.
+    #[test]
+    fn client_block() {
+        let src = [
+            0xFF, 0x06, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
+            0x0C, 0x0D, 0x0E, 0x0F, 0x10, 0x11, 0x12, 0x13, 0xFF, 0x01, 0x00, 0xFF, 0x19, 0x0B,
+            0x40, 0x01, 0x50, 0x0F, 0x10, 0x11, 0x12, 0x13,
+        ];
+        let expected: Vec<u8> = (0..20).chain(core::iter::repeat(0xFF).take(300)).chain(0..20)
+                                       .collect();
+        let mut dst = [0u8; 340];
+        assert_eq!(decompress(&src, &mut dst), Some(340));
+        assert_eq!(&dst[..], &expected[..]);
+    }
+}
@@ -1,56 +1,98 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// The test harness replaces main, leaving most of the firmware unused in test builds
+#![cfg_attr(test, allow(dead_code))]
 
+#[cfg(test)]
+extern crate core;
 extern crate cortex_m;
 extern crate cortex_m_rt;
 extern crate cortex_m_semihosting;
+#[cfg(not(test))]
 extern crate panic_halt;
 extern crate stm32f4;
 extern crate smoltcp;
 extern crate ufmt;
 
+#[cfg(not(test))]
 use cortex_m_rt::{entry, exception};
 use stm32f4::stm32f407;
 
 
+/// Status codes sent as the first word of every response.
+///
+/// The values are part of the wire protocol and must match the `errors` table in blethrs.py,
+/// so new variants must only ever be added at the end with the next unused value.
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Error {
-    Success,
-    InvalidAddress,
-    LengthNotMultiple4,
-    LengthTooLong,
-    DataLengthIncorrect,
-    EraseError,
-    WriteError,
-    FlashError,
-    NetworkError,
-    InternalError,
+    Success = 0,
+    InvalidAddress = 1,
+    LengthNotMultiple4 = 2,
+    LengthTooLong = 3,
+    DataLengthIncorrect = 4,
+    EraseError = 5,
+    WriteError = 6,
+    FlashError = 7,
+    NetworkError = 8,
+    InternalError = 9,
+    UnknownCommand = 10,
+    DecompressionError = 11,
+    WriteProtected = 12,
+    Locked = 13,
+    Unauthorized = 14,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-/// Try to print over semihosting if a debugger is available
+/// Print to the log buffer, and over semihosting if a debugger is available
 #[macro_export]
 macro_rules! print {
     ($($arg:expr),*) => ({
-        if unsafe { (*cortex_m::peripheral::DCB::ptr()).dhcsr.read() & 1 == 1 } {
-            match cortex_m_semihosting::hio::hstdout() {
-                Ok(mut stdout) => {
-                    $(
-                        stdout.write_all($arg.as_bytes()).ok();
-                    )*
-                },
-                Err(_) => ()
+        let debugger = !cfg!(test) &&
+                       unsafe { (*cortex_m::peripheral::DCB::ptr()).dhcsr.read() & 1 == 1 };
+        let mut stdout = if debugger { cortex_m_semihosting::hio::hstdout().ok() } else { None };
+        $(
+            let bytes = $arg.as_bytes();
+            ::logbuf::write(bytes);
+            if let Some(ref mut stdout) = stdout {
+                stdout.write_all(bytes).ok();
             }
-        }
+        )*
     })
 }
 
 mod config;
 mod ethernet;
 mod network;
+mod wire;
 mod flash;
 mod bootload;
+mod indicator;
+mod cpuload;
+mod diagnostics;
+mod logbuf;
+mod meminfo;
+mod startup;
+mod selftest;
+#[cfg(feature = "compressed-write")]
+mod lz4;
+#[cfg(feature = "backup-sram")]
+mod bkpsram;
+#[cfg(feature = "ip-conflict")]
+mod ipconflict;
+#[cfg(feature = "auth")]
+mod auth;
+#[cfg(feature = "auth")]
+mod sha256;
+#[cfg(test)]
+mod test_rng;
+// The handoff reader for user firmware, built here only to test it against the bootloader
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../app/handoff.rs"]
+mod app_handoff;
+
+use indicator::{Indicator, EnterCause};
 
 // Pull in build information (from `built` crate)
 mod build_info {
@@ -88,9 +130,9 @@ fn rcc_init(peripherals: &mut stm32f407::Peripherals) {
     rcc.cr.write(|w| w.hsion().set_bit());
     rcc.cfgr.write(|w| unsafe { w.bits(0) });
 
-    // Configure PLL: 16MHz /8 *168 /2, source HSI
+    // Configure PLL: 16MHz /8 *168 /2, source HSI, with the 48MHz clock for the RNG at /7
     rcc.pllcfgr.write(|w| unsafe {
-        w.pllq().bits(4)
+        w.pllq().bits(7)
          .pllsrc().hsi()
          .pllp().div2()
          .plln().bits(168)
@@ -145,18 +187,30 @@ fn systick_init(syst: &mut stm32f407::SYST) {
     syst.enable_counter();
 }
 
-#[entry]
+#[cfg_attr(not(test), entry)]
 fn main() -> ! {
+    // If user code jumped straight back into the bootloader, the clocks, interrupts and
+    // vector table it set up are all still active. Reset cleanly into the bootloader instead.
+    if bootload::reentered() {
+        bootload::reset_to_bootloader();
+    }
+
     let mut peripherals = stm32f407::Peripherals::take().unwrap();
     let mut core_peripherals = stm32f407::CorePeripherals::take().unwrap();
+    startup::init(&mut core_peripherals.DCB, &mut core_peripherals.DWT);
+    bootload::save_reset_flags(&peripherals.RCC);
 
     // Jump to user code if it exists and hasn't asked us to run
-    match flash::valid_user_code() {
-        Some(address) => if !config::should_enter_bootloader(&mut peripherals) {
-            bootload::bootload(&mut core_peripherals.SCB, address);
+    let cause = match flash::valid_user_code() {
+        Some(address) => {
+            if !config::should_enter_bootloader(&mut peripherals) {
+                bootload::bootload(&mut core_peripherals.SCB, address);
+            }
+            EnterCause::Requested
         },
-        None => (),
-    }
+        None => EnterCause::NoUserCode,
+    };
+    startup::mark(startup::Milestone::EntryDecision);
 
     print!("\n|-=-=-=-=-=-=-=-=-= blethrs =-=-=-=-=-=-=-=-=-\n");
     print!("| Version ", build_info::PKG_VERSION, " ", build_info::GIT_VERSION.unwrap(), "\n");
@@ -165,20 +219,34 @@ fn main() -> ! {
     print!("| ", build_info::RUSTC_VERSION, "\n");
     print!("|-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-\n\n");
 
+    if !config::user_region_valid(config::FLASH_CONFIG, flash::active_user_address()) {
+        print!(" User firmware region overlaps the bootloader or configuration, not booting it.\n\n");
+    }
+
     print!(  " Initialising clocks...               ");
     rcc_init(&mut peripherals);
+    startup::clock_changed(cpuload::CORE_HZ);
+    startup::mark(startup::Milestone::ClocksUp);
     print!("OK\n");
 
+    // Only now the bootloader is staying and the clocks are fast, so this doesn't delay
+    // booting the user firmware.
+    meminfo::paint();
+
     print!(  " Initialising GPIOs...                ");
     config::configure_gpio(&mut peripherals);
+    config::INDICATOR.entered_bootloader(cause);
     print!("OK\n");
 
     print!(  " Reading configuration...             ");
-    let cfg = match flash::UserConfig::get(&mut peripherals.CRC) {
-        Some(cfg) => { print!("OK\n"); cfg },
-        None => {
-            print!("Err\nCouldn't read configuration, using default.\n");
-            flash::DEFAULT_CONFIG
+    if !flash::hardware_crc_available() {
+        print!("CRC peripheral not clocked, using software CRC... ");
+    }
+    let (cfg, cfg_err) = match flash::UserConfig::get(&mut peripherals.CRC) {
+        Ok(cfg) => { print!("OK\n"); (cfg, None) },
+        Err(err) => {
+            print!("Err\nConfiguration ", err.as_str(), ", using default.\n");
+            (flash::DEFAULT_CONFIG, Some(err))
         },
     };
 
@@ -191,24 +259,30 @@ fn main() -> ! {
     ethdev.init(&mut peripherals.RCC, mac_addr.clone());
     print!("OK\n");
 
-    print!(  " Waiting for link...                  ");
-    ethdev.block_until_link();
-    print!("OK\n");
+    // Move flash peripheral into flash module
+    flash::init(peripherals.FLASH);
+
+    #[cfg(feature = "backup-sram")]
+    bkpsram::init(&mut peripherals.RCC, &mut peripherals.PWR);
 
+    #[cfg(feature = "auth")]
+    auth::init(&mut peripherals.RCC, peripherals.RNG);
+
+    // Set up the network without waiting for the link, which is polled for from the systick,
+    // so the socket is listening as soon as the link comes up.
+    config::INDICATOR.link_down();
     print!(  " Initialising network...              ");
     let ip_addr = smoltcp::wire::Ipv4Address::from_bytes(&cfg.ip_address);
     let ip_cidr = smoltcp::wire::Ipv4Cidr::new(ip_addr, cfg.ip_prefix);
     let cidr = smoltcp::wire::IpCidr::Ipv4(ip_cidr);
-    network::init(ethdev, mac_addr.clone(), cidr);
+    network::init(ethdev, mac_addr.clone(), cidr, cfg_err);
     print!("OK\n");
+    startup::mark(startup::Milestone::InterfaceReady);
 
-    // Move flash peripheral into flash module
-    flash::init(peripherals.FLASH);
-
-    // Turn on STATUS LED
-    print!(" Ready.\n\n");
+    print!(" Ready, waiting for link.\n\n");
 
-    // Begin periodic tasks via systick
+    // Begin periodic tasks via systick, measuring the time they take
+    cpuload::init();
     systick_init(&mut core_peripherals.SYST);
 
     loop {
@@ -218,35 +292,120 @@ fn main() -> ! {
 
 static mut SYSTICK_TICKS: u32 = 0;
 static mut SYSTICK_RESET_AT: Option<u32> = None;
+static mut SYSTICK_RESET_TO_BOOTLOADER: bool = false;
 
+#[cfg(not(test))]
 #[exception]
 fn SysTick() {
-    let ticks = unsafe { core::ptr::read_volatile(&SYSTICK_TICKS) + 1 };
-    unsafe { core::ptr::write_volatile(&mut SYSTICK_TICKS, ticks) };
+    let start = cpuload::handler_start();
+    let ticks = unsafe { core::ptr::read_volatile(core::ptr::addr_of!(SYSTICK_TICKS)) + 1 };
+    unsafe { core::ptr::write_volatile(core::ptr::addr_of_mut!(SYSTICK_TICKS), ticks) };
     network::poll(ticks as i64);
-    match unsafe { core::ptr::read_volatile(&SYSTICK_RESET_AT) } {
+    match unsafe { core::ptr::read_volatile(core::ptr::addr_of!(SYSTICK_RESET_AT)) } {
         Some(reset_time) => if ticks >= reset_time {
             print!("Performing scheduled reset\n");
+            if unsafe { core::ptr::read_volatile(core::ptr::addr_of!(SYSTICK_RESET_TO_BOOTLOADER)) } {
+                bootload::reset_to_bootloader();
+            }
+            config::INDICATOR.about_to_boot();
             bootload::reset_bootload();
         },
         None => (),
     }
+    cpuload::handler_done(start);
+}
+
+/// Milliseconds since the systick was started.
+pub fn uptime_ms() -> u32 {
+    unsafe { core::ptr::read_volatile(core::ptr::addr_of!(SYSTICK_TICKS)) }
+}
+
+/// The three words of the device unique ID, lowest address first.
+#[cfg(not(test))]
+pub fn unique_id() -> [u32; 3] {
+    let mut id = [0u32; 3];
+    for (idx, word) in id.iter_mut().enumerate() {
+        let address = config::UID_ADDRESS + 4 * idx as u32;
+        *word = unsafe { core::ptr::read_volatile(address as *const u32) };
+    }
+    id
+}
+
+/// A made up unique ID for host tests.
+#[cfg(test)]
+pub fn unique_id() -> [u32; 3] {
+    [0x0033_0021, 0x3436_5104, 0x3330_3237]
+}
+
+/// Run `f` with interrupts disabled, as `cortex_m::interrupt::free`.
+#[cfg(not(test))]
+pub fn interrupt_free<F, R>(f: F) -> R
+    where F: FnOnce(&cortex_m::interrupt::CriticalSection) -> R
+{
+    cortex_m::interrupt::free(f)
+}
+
+/// Host tests can't disable interrupts, and instead take `flash::fake::lock` before
+/// touching anything shared.
+#[cfg(test)]
+pub fn interrupt_free<F, R>(f: F) -> R
+    where F: FnOnce(&cortex_m::interrupt::CriticalSection) -> R
+{
+    f(unsafe { &cortex_m::interrupt::CriticalSection::new() })
 }
 
 /// Reset after some ms delay.
 pub fn schedule_reset(delay: u32) {
-    cortex_m::interrupt::free(|_| unsafe {
-        let ticks = core::ptr::read_volatile(&SYSTICK_TICKS) + delay;
-        core::ptr::write_volatile(&mut SYSTICK_RESET_AT, Some(ticks));
+    interrupt_free(|_| unsafe {
+        let ticks = core::ptr::read_volatile(core::ptr::addr_of!(SYSTICK_TICKS)) + delay;
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(SYSTICK_RESET_AT), Some(ticks));
     });
 }
 
+/// Reset after some ms delay, and stay in the bootloader rather than boot the user firmware.
+pub fn schedule_reset_to_bootloader(delay: u32) {
+    unsafe { core::ptr::write_volatile(core::ptr::addr_of_mut!(SYSTICK_RESET_TO_BOOTLOADER), true) };
+    schedule_reset(delay);
+}
+
+#[cfg(not(test))]
 #[exception]
 fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
     panic!("HardFault at {:#?}", ef);
 }
 
+#[cfg(not(test))]
 #[exception]
 fn DefaultHandler(irqn: i16) {
     panic!("Unhandled exception (IRQn = {})", irqn);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    /// The status codes sent on the wire, which blethrs.py decodes with its `errors` table.
+    #[test]
+    fn error_wire_values() {
+        let table = [
+            (Error::Success, 0),
+            (Error::InvalidAddress, 1),
+            (Error::LengthNotMultiple4, 2),
+            (Error::LengthTooLong, 3),
+            (Error::DataLengthIncorrect, 4),
+            (Error::EraseError, 5),
+            (Error::WriteError, 6),
+            (Error::FlashError, 7),
+            (Error::NetworkError, 8),
+            (Error::InternalError, 9),
+            (Error::UnknownCommand, 10),
+            (Error::DecompressionError, 11),
+            (Error::WriteProtected, 12),
+            (Error::Locked, 13),
+            (Error::Unauthorized, 14),
+        ];
+        for &(err, value) in table.iter() {
+            assert_eq!(err as u32, value);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! RAM use figures for the mem info command.
+//!
+//! The RAM between the end of the statics and the stack is painted with a known value at
+//! startup, so the deepest the stack has reached is found later from the first word which
+//! has been overwritten.
+use core;
+use cortex_m;
+
+#[cfg(not(test))]
+extern "C" {
+    /// Start of the statics in RAM, from the cortex-m-rt linker script.
+    static __sdata: u32;
+    /// End of the statics in RAM.
+    static __sheap: u32;
+    /// Initial stack pointer, just past the end of RAM.
+    static _stack_start: u32;
+}
+
+/// Value painted over unused RAM.
+const PAINT: u32 = 0x5AC4_A11D;
+
+/// Space left unpainted below the stack pointer, for the stack used while painting.
+const PAINT_MARGIN: u32 = 256;
+
+#[cfg(not(test))]
+fn statics_start() -> u32 {
+    core::ptr::addr_of!(__sdata) as u32
+}
+
+#[cfg(not(test))]
+fn statics_end() -> u32 {
+    core::ptr::addr_of!(__sheap) as u32
+}
+
+#[cfg(not(test))]
+fn stack_top() -> u32 {
+    core::ptr::addr_of!(_stack_start) as u32
+}
+
+// Host tests have no linker script, so see no RAM at all.
+#[cfg(test)]
+fn statics_start() -> u32 { 0 }
+#[cfg(test)]
+fn statics_end() -> u32 { 0 }
+#[cfg(test)]
+fn stack_top() -> u32 { 0 }
+
+/// Paint the unused RAM below the stack. Call once, early in startup.
+pub fn paint() {
+    let end = cortex_m::register::msp::read() - PAINT_MARGIN;
+    let mut address = statics_end();
+    while address < end {
+        unsafe { core::ptr::write_volatile(address as *mut u32, PAINT) };
+        address += 4;
+    }
+}
+
+/// Address of the lowest word of RAM the stack has overwritten.
+fn stack_low_water() -> u32 {
+    let mut address = statics_end();
+    while address < stack_top() &&
+          unsafe { core::ptr::read_volatile(address as *const u32) } == PAINT
+    {
+        address += 4;
+    }
+    address
+}
+
+/// Bytes of RAM taken by statics, including all buffers.
+pub fn statics_bytes() -> u32 {
+    statics_end() - statics_start()
+}
+
+/// Most bytes of stack used since startup.
+pub fn stack_peak_bytes() -> u32 {
+    stack_top() - stack_low_water()
+}
+
+/// Bytes between the statics and the deepest point the stack has reached,
+/// which have never been used.
+pub fn free_bytes() -> u32 {
+    stack_low_water() - statics_end()
+}
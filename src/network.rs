@@ -2,13 +2,37 @@ use smoltcp;
 use smoltcp::time::Instant;
 use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
 use smoltcp::iface::{Neighbor, NeighborCache, EthernetInterface, EthernetInterfaceBuilder};
-use smoltcp::socket::{SocketSet, SocketSetItem, SocketHandle, TcpSocket, TcpSocketBuffer};
+use smoltcp::socket::{SocketSet, SocketSetItem, SocketHandle, TcpSocket, TcpSocketBuffer, TcpState};
 
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, Ordering};
 use cortex_m;
+use cortex_m::interrupt::Mutex;
 
-use ::flash;
+use ::flash::{self, CrcCompute};
+#[cfg(feature = "backup-sram")]
+use ::bkpsram;
+#[cfg(feature = "ip-conflict")]
+use ::ipconflict;
+#[cfg(feature = "auth")]
+use ::auth;
+#[cfg(feature = "auth")]
+use ::sha256;
+use ::bootload;
+use ::cpuload;
+use ::diagnostics;
+use ::meminfo;
+use ::ethernet;
+use ::logbuf;
+use ::startup::{self, Milestone};
+use ::selftest;
+use ::config::{INDICATOR, CUSTOM_HANDLER};
+use ::indicator::Indicator;
 use ::build_info;
 use ::Error;
+use ::wire::{Socket, recv_u32, send_u32, read_adr_len};
+#[cfg(any(feature = "emergency-port", feature = "auth", feature = "trace"))]
+use ::wire;
 use ethernet::EthernetDevice;
 
 const CMD_INFO: u32 = 0;
@@ -16,24 +40,79 @@ const CMD_READ: u32 = 1;
 const CMD_ERASE: u32 = 2;
 const CMD_WRITE: u32 = 3;
 const CMD_BOOT: u32 = 4;
+const CMD_LAYOUT: u32 = 5;
+const CMD_TRIAL_BOOT: u32 = 6;
+const CMD_SELF_TEST: u32 = 9;
+const CMD_STATUS: u32 = 10;
+#[cfg(feature = "compressed-write")]
+const CMD_WRITE_COMPRESSED: u32 = 11;
+const CMD_SET_MAC: u32 = 12;
+const CMD_GET_LOG: u32 = 13;
+const CMD_SET_CONFIG: u32 = 14;
+const CMD_DIAGNOSTICS: u32 = 15;
+const CMD_MEM_INFO: u32 = 16;
+const CMD_FIRST_WORD: u32 = 17;
+const CMD_ECHO: u32 = 18;
+const CMD_GET_CONFIG: u32 = 19;
+const CMD_REBOOT: u32 = 20;
+const CMD_UNLOCK: u32 = 21;
+#[cfg(feature = "auth")]
+const CMD_AUTH_NONCE: u32 = 22;
+#[cfg(feature = "auth")]
+const CMD_SET_KEY: u32 = 23;
 
-use ::config::TCP_PORT;
+/// Largest payload the echo command returns.
+const ECHO_MAX: usize = 1024;
+#[cfg(feature = "backup-sram")]
+const CMD_READ_MEM: u32 = 7;
+#[cfg(feature = "backup-sram")]
+const CMD_WRITE_MEM: u32 = 8;
 
-/// Read an address and length from the socket
-fn read_adr_len(socket: &mut TcpSocket) -> (u32, usize) {
-    let mut adr = [0u8; 4];
-    let mut len = [0u8; 4];
-    socket.recv_slice(&mut adr[..]).ok();
-    socket.recv_slice(&mut len[..]).ok();
-    let adr = u32::from_le_bytes(adr);
-    let len = u32::from_le_bytes(len);
-    (adr, len as usize)
+/// Command codes from CMD_CUSTOM_BASE upwards are reserved for CustomHandler,
+/// and will never be used by built in commands.
+pub const CMD_CUSTOM_BASE: u32 = 0x8000_0000;
+
+/// Handler for integrator specific commands, registered as CUSTOM_HANDLER in config.rs.
+pub trait CustomHandler {
+    /// Called with each received command code of CMD_CUSTOM_BASE or higher.
+    ///
+    /// The command code has already been read from `socket`. If the command is recognised,
+    /// read any arguments, send a status with `send_status` followed by any response data,
+    /// and return true. Return false to have UnknownCommand sent instead.
+    fn handle(&self, cmd: u32, socket: &mut TcpSocket) -> bool;
 }
 
+use ::config::LOG_BUFFER_SIZE;
+use ::config::{TCP_PORT, ERASE_VERIFY_CHUNK, NEIGHBOR_CACHE_SIZE, LISTEN_RETRY_LIMIT};
+use ::config::{LINK_POLL_MS, LINK_WAIT_LOG_MS};
+#[cfg(feature = "emergency-port")]
+use ::config::EMERGENCY_TCP_PORT;
+use ::config::{FLASH_SECTOR_ADDRESSES, FLASH_SECTOR_ERASE_MS, FLASH_CONFIG, FLASH_USER, FLASH_END};
+
 /// Send a status word back at the start of a response
-fn send_status(socket: &mut TcpSocket, status: ::Error) {
-    let resp = (status as u32).to_le_bytes();
-    socket.send_slice(&resp).unwrap();
+///
+/// Send errors are ignored, as they only occur if the client has already gone away.
+pub fn send_status<S: Socket>(socket: &mut S, status: ::Error) {
+    send_u32(socket, status as u32);
+    #[cfg(feature = "trace")]
+    {
+        let mut buf = [0u8; 10];
+        print!(" -> status ", digits_str(u32_to_dec(status as u32, &mut buf)), "\n");
+    }
+    INDICATOR.command_finished(status);
+}
+
+/// Send the context of a failed erase or write after its error status, if there is any.
+///
+/// The detail is the failing address, the index of its sector or flash::SECTOR_NONE,
+/// the flash status register, and the nWRP option bits.
+fn send_error_detail<S: Socket>(socket: &mut S) {
+    if let Some(detail) = flash::take_error_detail() {
+        send_u32(socket, detail.address);
+        send_u32(socket, detail.sector);
+        send_u32(socket, detail.sr);
+        send_u32(socket, detail.nwrp);
+    }
 }
 
 /// Read device unique ID, return as array of 24 ASCII hex digits
@@ -42,9 +121,10 @@ pub fn get_hex_id() -> [u8; 24] {
         48, 49, 50, 51, 52, 53, 54, 55, 56, 57,
         65, 66, 67, 68, 69, 70,
     ];
-    let id1: [u8; 4] = unsafe { *(0x1FFF_7A10 as *const u32) }.to_le_bytes();
-    let id2: [u8; 4] = unsafe { *(0x1FFF_7A14 as *const u32) }.to_le_bytes();
-    let id3: [u8; 4] = unsafe { *(0x1FFF_7A18 as *const u32) }.to_le_bytes();
+    let uid = ::unique_id();
+    let id1: [u8; 4] = uid[0].to_le_bytes();
+    let id2: [u8; 4] = uid[1].to_le_bytes();
+    let id3: [u8; 4] = uid[2].to_le_bytes();
     let id = [
         id3[3], id3[2], id3[1], id3[0],
         id2[3], id2[2], id2[1], id2[0],
@@ -60,8 +140,98 @@ pub fn get_hex_id() -> [u8; 24] {
     out
 }
 
+/// Format `v` as ASCII decimal digits in `buf`, returning the used part of `buf`.
+fn u32_to_dec(mut v: u32, buf: &mut [u8; 10]) -> &[u8] {
+    let mut idx = buf.len();
+    loop {
+        idx -= 1;
+        buf[idx] = b'0' + (v % 10) as u8;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    &buf[idx..]
+}
+
+/// Format `v` as 8 ASCII hex digits in `buf`.
+fn u32_to_hex(v: u32, buf: &mut [u8; 8]) -> &[u8] {
+    for (idx, digit) in buf.iter_mut().enumerate() {
+        let nibble = ((v >> (28 - 4 * idx)) & 0xF) as u8;
+        *digit = if nibble < 10 { b'0' + nibble } else { b'A' + nibble - 10 };
+    }
+    &buf[..]
+}
+
+/// Format `mac` as colon separated ASCII hex digits in `buf`.
+#[cfg(feature = "ip-conflict")]
+fn mac_to_hex(mac: EthernetAddress, buf: &mut [u8; 17]) -> &[u8] {
+    for (idx, byte) in mac.0.iter().enumerate() {
+        for (nib, &nibble) in [byte >> 4, byte & 0xF].iter().enumerate() {
+            buf[3 * idx + nib] = if nibble < 10 { b'0' + nibble } else { b'A' + nibble - 10 };
+        }
+        if idx < 5 {
+            buf[3 * idx + 2] = b':';
+        }
+    }
+    &buf[..]
+}
+
+/// Printable form of formatted digits, for the print macro.
+fn digits_str(digits: &[u8]) -> &str {
+    core::str::from_utf8(digits).unwrap_or("?")
+}
+
+/// Log a received command's name and code, with its address and length for commands
+/// which have them.
+/// The status is appended by `send_status`.
+#[cfg(feature = "trace")]
+fn trace_command<S: Socket>(socket: &mut S, cmd: u32) {
+    let (name, has_adr_len) = match cmd {
+        CMD_INFO => ("INFO", false),
+        CMD_READ => ("READ", true),
+        CMD_ERASE => ("ERASE", true),
+        CMD_WRITE => ("WRITE", true),
+        CMD_BOOT => ("BOOT", false),
+        CMD_LAYOUT => ("LAYOUT", false),
+        CMD_TRIAL_BOOT => ("TRIAL_BOOT", false),
+        CMD_SELF_TEST => ("SELF_TEST", false),
+        CMD_STATUS => ("STATUS", false),
+        CMD_SET_MAC => ("SET_MAC", false),
+        CMD_GET_LOG => ("GET_LOG", false),
+        CMD_SET_CONFIG => ("SET_CONFIG", false),
+        CMD_DIAGNOSTICS => ("DIAGNOSTICS", false),
+        CMD_MEM_INFO => ("MEM_INFO", false),
+        CMD_FIRST_WORD => ("FIRST_WORD", false),
+        CMD_ECHO => ("ECHO", false),
+        CMD_GET_CONFIG => ("GET_CONFIG", false),
+        CMD_REBOOT => ("REBOOT", false),
+        CMD_UNLOCK => ("UNLOCK", false),
+        #[cfg(feature = "auth")]
+        CMD_AUTH_NONCE => ("AUTH_NONCE", false),
+        #[cfg(feature = "auth")]
+        CMD_SET_KEY => ("SET_KEY", false),
+        #[cfg(feature = "compressed-write")]
+        CMD_WRITE_COMPRESSED => ("WRITE_COMPRESSED", true),
+        #[cfg(feature = "backup-sram")]
+        CMD_READ_MEM => ("READ_MEM", true),
+        #[cfg(feature = "backup-sram")]
+        CMD_WRITE_MEM => ("WRITE_MEM", true),
+        _ => ("UNKNOWN", false),
+    };
+    let mut hex = [0u8; 8];
+    print!(name, " 0x", digits_str(u32_to_hex(cmd, &mut hex)));
+    if !has_adr_len {
+        return;
+    }
+    let (adr, len) = wire::peek_adr_len(socket);
+    let mut dec = [0u8; 10];
+    print!(" adr=0x", digits_str(u32_to_hex(adr, &mut hex)),
+           " len=", digits_str(u32_to_dec(len as u32, &mut dec)));
+}
+
 /// Respond to the information request command with our build information.
-fn cmd_info(socket: &mut TcpSocket) {
+fn cmd_info<S: Socket>(socket: &mut S, cfg_err: Option<flash::ConfigError>) {
 
     send_status(socket, Error::Success);
 
@@ -75,42 +245,831 @@ fn cmd_info(socket: &mut TcpSocket) {
     socket.send_slice(build_info::RUSTC_VERSION.as_bytes()).ok();
     socket.send_slice("\r\nMCU ID: ".as_bytes()).ok();
     socket.send_slice(&get_hex_id()).ok();
+    socket.send_slice("\r\nConfig: ".as_bytes()).ok();
+    match cfg_err {
+        None => socket.send_slice("OK".as_bytes()).ok(),
+        Some(err) => socket.send_slice(err.as_str().as_bytes()).ok(),
+    };
+    socket.send_slice("\r\nBoots: 0x".as_bytes()).ok();
+    let mut hex = [0u8; 8];
+    socket.send_slice(u32_to_hex(flash::active_user_address(), &mut hex)).ok();
+    match flash::valid_user_code() {
+        Some(_) => socket.send_slice(" (valid)".as_bytes()).ok(),
+        None => socket.send_slice(" (no valid firmware)".as_bytes()).ok(),
+    };
+    #[cfg(feature = "ip-conflict")]
+    {
+        socket.send_slice("\r\nIP conflict: ".as_bytes()).ok();
+        match ipconflict::conflict() {
+            Some(mac) => socket.send_slice(mac_to_hex(mac, &mut [0u8; 17])).ok(),
+            None => socket.send_slice("none".as_bytes()).ok(),
+        };
+    }
+    socket.send_slice("\r\nCPU: ".as_bytes()).ok();
+    let mut buf = [0u8; 10];
+    socket.send_slice(u32_to_dec(cpuload::utilisation_percent(), &mut buf)).ok();
+    socket.send_slice("%\r\nStartup: ".as_bytes()).ok();
+    let milestones = [(Milestone::EntryDecision, "entry "), (Milestone::ClocksUp, ", clocks "),
+                      (Milestone::InterfaceReady, ", network "), (Milestone::LinkUp, ", link ")];
+    for &(milestone, label) in milestones.iter() {
+        socket.send_slice(label.as_bytes()).ok();
+        match startup::ms(milestone) {
+            startup::NOT_REACHED => socket.send_slice("pending".as_bytes()).ok(),
+            ms => { socket.send_slice(u32_to_dec(ms, &mut buf)).ok(); socket.send_slice("ms".as_bytes()).ok() },
+        };
+    }
     socket.send_slice("\r\n".as_bytes()).ok();
 }
 
-fn cmd_read(socket: &mut TcpSocket) {
+/// Respond to the layout request with the flash memory map.
+///
+/// Sends the config address, user address, final flash address and number of sectors,
+/// followed by the start address, typical and maximum erase time in ms of each sector,
+/// and finally the address of the user firmware that will be booted.
+fn cmd_layout<S: Socket>(socket: &mut S) {
+    send_status(socket, Error::Success);
+
+    send_u32(socket, FLASH_CONFIG);
+    send_u32(socket, FLASH_USER);
+    send_u32(socket, FLASH_END);
+    send_u32(socket, FLASH_SECTOR_ADDRESSES.len() as u32);
+    for (adr, (typ, max)) in FLASH_SECTOR_ADDRESSES.iter().zip(FLASH_SECTOR_ERASE_MS.iter()) {
+        send_u32(socket, *adr);
+        send_u32(socket, *typ);
+        send_u32(socket, *max);
+    }
+    send_u32(socket, flash::active_user_address());
+}
+
+fn cmd_read<S: Socket>(socket: &mut S) {
     let (adr, len) = read_adr_len(socket);
     match flash::read(adr, len) {
         Ok(data) => {
             send_status(socket, Error::Success);
-            socket.send_slice(data).unwrap();
+            socket.send_slice(data).ok();
         },
         Err(err) => send_status(socket, err),
     };
 }
 
-fn cmd_erase(socket: &mut TcpSocket) {
+/// An erase whose status hasn't been sent yet, because it is still being blank-checked.
+#[derive(Clone, Copy)]
+struct PendingErase {
+    /// Range still to be blank-checked.
+    range: (u32, u32),
+    sectors: u32,
+    ms: u32,
+}
+
+/// Erase the requested range, returning the number of sectors erased and the
+/// milliseconds taken, measured with the cycle counter as the systick doesn't
+/// advance while a command runs.
+fn erase_timed(adr: u32, len: usize) -> ::Result<((u32, u32), u32, u32)> {
+    let start = cpuload::cycle_count();
+    let range = flash::erase(adr, len)?;
+    let ms = cpuload::cycle_count().wrapping_sub(start) / (cpuload::CORE_HZ / 1000);
+    let sectors = if range.1 > range.0 {
+        match (flash::sector_index(range.0), flash::sector_index(range.1 - 1)) {
+            (Some(first), Some(last)) => (last - first + 1) as u32,
+            _ => 0,
+        }
+    } else {
+        0
+    };
+    Ok((range, sectors, ms))
+}
+
+/// Send a successful erase's status, followed by the number of sectors erased
+/// and the milliseconds the erase took.
+fn send_erase_success<S: Socket>(socket: &mut S, sectors: u32, ms: u32) {
+    send_status(socket, Error::Success);
+    send_u32(socket, sectors);
+    send_u32(socket, ms);
+}
+
+/// Erase the requested sectors.
+///
+/// On success, returns the erased sectors still to be blank-checked, which is then done
+/// by `cmd_erase_verify` over subsequent polls before the status is sent.
+#[cfg(feature = "erase-verify")]
+fn cmd_erase<S: Socket>(socket: &mut S) -> Option<PendingErase> {
     let (adr, len) = read_adr_len(socket);
-    match flash::erase(adr, len) {
-        Ok(()) => send_status(socket, Error::Success),
-        Err(err) => send_status(socket, err),
+    match erase_timed(adr, len) {
+        Ok((range, sectors, ms)) => Some(PendingErase { range, sectors, ms }),
+        Err(err) => { send_status(socket, err); send_error_detail(socket); None },
     }
 }
 
-fn cmd_write(socket: &mut TcpSocket) {
+/// Erase the requested sectors, trusting the flash controller's report of success.
+#[cfg(not(feature = "erase-verify"))]
+fn cmd_erase<S: Socket>(socket: &mut S) -> Option<PendingErase> {
+    let (adr, len) = read_adr_len(socket);
+    match erase_timed(adr, len) {
+        Ok((_, sectors, ms)) => send_erase_success(socket, sectors, ms),
+        Err(err) => { send_status(socket, err); send_error_detail(socket); },
+    }
+    None
+}
+
+/// Blank-check the next chunk of a previous erase.
+///
+/// If a word is not blank, EraseError is sent followed by the word's address and sector,
+/// a zero status register and the nWRP option bits.
+///
+/// Returns the erase with the range still to be checked, or None once the check has
+/// finished and the status has been sent.
+fn cmd_erase_verify<S: Socket>(socket: &mut S, pending: PendingErase) -> Option<PendingErase> {
+    let (start, end) = pending.range;
+
+    // Give up if the client has gone away in the meantime
+    if !socket.may_send() {
+        return None;
+    }
+
+    let len = core::cmp::min(ERASE_VERIFY_CHUNK as u32, end - start);
+    match flash::first_non_blank(start, len as usize) {
+        Some(adr) => {
+            send_status(socket, Error::EraseError);
+            send_u32(socket, adr);
+            send_u32(socket, flash::sector_index(adr).map_or(flash::SECTOR_NONE, |idx| idx as u32));
+            send_u32(socket, 0);
+            send_u32(socket, flash::write_protection());
+            None
+        },
+        None if start + len >= end => {
+            send_erase_success(socket, pending.sectors, pending.ms);
+            None
+        },
+        None => Some(PendingErase { range: (start + len, end), ..pending }),
+    }
+}
+
+/// Write the received data to flash.
+///
+/// The status is only sent after `flash::write` returns, by which point programming has
+/// finished and flash is locked, so a Success status guarantees the data is committed.
+fn cmd_write<S: Socket>(socket: &mut S) {
     let (adr, len) = read_adr_len(socket);
     match socket.recv(|buf| (buf.len(), flash::write(adr, len, buf))) {
+        Ok(Ok(())) => send_status(socket, Error::Success),
+        Ok(Err(err)) => { send_status(socket, err); send_error_detail(socket); },
+        Err(_) => send_status(socket, Error::NetworkError),
+    }
+}
+
+/// Decompress the received LZ4 block and write it to flash.
+///
+/// After the address and decompressed length, the command carries the CRC of the
+/// decompressed data and the length of the compressed data which follows.
+#[cfg(feature = "compressed-write")]
+fn cmd_write_compressed<S: Socket>(socket: &mut S) {
+    let (adr, len) = read_adr_len(socket);
+    let crc = recv_u32(socket);
+    let compressed_len = recv_u32(socket) as usize;
+    let result = socket.recv(|buf| {
+        let result = if buf.len() == compressed_len {
+            flash::write_compressed(adr, len, crc, buf)
+        } else {
+            Err(Error::DataLengthIncorrect)
+        };
+        (buf.len(), result)
+    });
+    match result {
+        Ok(Ok(())) => send_status(socket, Error::Success),
+        Ok(Err(err)) => { send_status(socket, err); send_error_detail(socket); },
+        Err(_) => send_status(socket, Error::NetworkError),
+    }
+}
+
+/// MAC address stored by the set MAC command, to be used once its connection has closed.
+static PENDING_MAC: Mutex<Cell<Option<EthernetAddress>>> = Mutex::new(Cell::new(None));
+
+/// Store a new MAC address in the configuration, keeping its other settings.
+///
+/// The address must be unicast and locally administered. It is used from when this
+/// connection closes, so the response still reaches the client at the old address.
+fn cmd_set_mac<S: Socket>(socket: &mut S) {
+    let mut mac = [0u8; 6];
+    if socket.recv_slice(&mut mac[..]) != Ok(6) {
+        send_status(socket, Error::DataLengthIncorrect);
+        return;
+    }
+    let mac = EthernetAddress(mac);
+    if !mac.is_unicast() || !mac.is_local() {
+        send_status(socket, Error::InvalidAddress);
+        return;
+    }
+
+    let mut cfg = flash::UserConfig::get(&mut flash::SoftwareCrc32)
+                                     .unwrap_or(flash::DEFAULT_CONFIG);
+    cfg.mac_address = mac.0;
+    match cfg.store(&mut flash::SoftwareCrc32) {
+        Ok(()) => {
+            ::interrupt_free(|cs| PENDING_MAC.borrow(cs).set(Some(mac)));
+            send_status(socket, Error::Success);
+        },
+        Err(err) => { send_status(socket, err); send_error_detail(socket); },
+    }
+}
+
+/// Run the self test and send its report: the failure bitmask, bootloader CRC,
+/// config state, PHY ID registers 2 and 3 in one word, and flash size in kB.
+fn cmd_self_test(socket: &mut TcpSocket, eth_dev: &mut EthernetDevice) {
+    let report = selftest::run(eth_dev);
+    send_status(socket, Error::Success);
+    send_u32(socket, report.failures);
+    send_u32(socket, report.bootloader_crc);
+    send_u32(socket, report.config);
+    send_u32(socket, (report.phy_id.0 as u32) << 16 | report.phy_id.1 as u32);
+    send_u32(socket, report.flash_size_kb);
+}
+
+/// Version of the status structure. Fields are only ever appended, increasing the version.
+const STATUS_VERSION: u32 = 3;
+/// Length in bytes of the status fields following the version and length words.
+const STATUS_LENGTH: u32 = 88;
+
+/// Send a snapshot of the device status as a binary structure.
+///
+/// The structure is the version and the length in bytes of the fields which follow,
+/// then in version 1:
+/// * package version, 16 bytes, zero padded
+/// * MCU unique ID, 3 words
+/// * RCC_CSR reset flags at startup
+/// * uptime in ms
+/// * config state: 0 valid, 1 blank, 2 bad magic, 3 bad checksum
+/// * MAC address, 6 bytes, then IP prefix length and a padding byte
+/// * IP address, 4 bytes
+/// * config address, user firmware address and final flash address
+/// * CPU utilisation percentage
+///
+/// and in version 2:
+/// * number of times a socket has failed to listen
+///
+/// and in version 3, ms from reset to each startup milestone, or 0xFFFFFFFF if not reached:
+/// * deciding to stay in the bootloader
+/// * clocks up
+/// * network interface ready
+/// * link up
+fn cmd_status(socket: &mut TcpSocket, cfg_err: Option<flash::ConfigError>,
+              eth_iface: &mut Interface)
+{
+    send_status(socket, Error::Success);
+    send_u32(socket, STATUS_VERSION);
+    send_u32(socket, STATUS_LENGTH);
+
+    let mut version = [0u8; 16];
+    let pkg_version = build_info::PKG_VERSION.as_bytes();
+    let len = core::cmp::min(pkg_version.len(), version.len());
+    version[..len].copy_from_slice(&pkg_version[..len]);
+    socket.send_slice(&version).ok();
+
+    for word in ::unique_id().iter() {
+        send_u32(socket, *word);
+    }
+    send_u32(socket, bootload::reset_flags());
+    send_u32(socket, ::uptime_ms());
+    send_u32(socket, match cfg_err {
+        None => 0,
+        Some(flash::ConfigError::Blank) => 1,
+        Some(flash::ConfigError::BadMagic) => 2,
+        Some(flash::ConfigError::BadChecksum) => 3,
+    });
+
+    socket.send_slice(eth_iface.ethernet_addr().as_bytes()).ok();
+    match eth_iface.ip_addrs().first() {
+        Some(IpCidr::Ipv4(cidr)) => {
+            socket.send_slice(&[cidr.prefix_len(), 0]).ok();
+            socket.send_slice(cidr.address().as_bytes()).ok();
+        },
+        _ => { socket.send_slice(&[0u8; 6]).ok(); },
+    }
+
+    send_u32(socket, FLASH_CONFIG);
+    send_u32(socket, FLASH_USER);
+    send_u32(socket, FLASH_END);
+    send_u32(socket, cpuload::utilisation_percent());
+    send_u32(socket, diagnostics::LISTEN_FAILURES.load(Ordering::Relaxed));
+    send_u32(socket, startup::ms(Milestone::EntryDecision));
+    send_u32(socket, startup::ms(Milestone::ClocksUp));
+    send_u32(socket, startup::ms(Milestone::InterfaceReady));
+    send_u32(socket, startup::ms(Milestone::LinkUp));
+}
+
+#[cfg(feature = "backup-sram")]
+fn cmd_read_mem<S: Socket>(socket: &mut S) {
+    let (adr, len) = read_adr_len(socket);
+    match bkpsram::read(adr, len) {
+        Ok(data) => {
+            send_status(socket, Error::Success);
+            socket.send_slice(data).ok();
+        },
+        Err(err) => send_status(socket, err),
+    };
+}
+
+#[cfg(feature = "backup-sram")]
+fn cmd_write_mem<S: Socket>(socket: &mut S) {
+    let (adr, len) = read_adr_len(socket);
+    match socket.recv(|buf| (buf.len(), bkpsram::write(adr, len, buf))) {
         Ok(Ok(())) => send_status(socket, Error::Success),
         Ok(Err(err)) => send_status(socket, err),
         Err(_) => send_status(socket, Error::NetworkError),
     }
 }
 
-fn cmd_boot(socket: &mut TcpSocket) {
+fn cmd_boot<S: Socket>(socket: &mut S) {
     send_status(socket, Error::Success);
     ::schedule_reset(50);
 }
 
+/// Set by the unlock command, until the next reset or a configuration which locks flashing.
+static UNLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if the stored configuration locks flashing and it hasn't been unlocked.
+///
+/// This only guards against accidentally reflashing a deployed device, as anyone who
+/// can reach it can read its MCU ID and so the unlock token.
+fn flash_locked() -> bool {
+    !UNLOCKED.load(Ordering::Relaxed) &&
+        flash::UserConfig::get(&mut flash::SoftwareCrc32)
+            .map_or(false, |cfg| cfg.flags & flash::CONFIG_FLAG_LOCKED != 0)
+}
+
+/// Returns true for commands refused while flashing is locked.
+fn modifies_flash(cmd: u32) -> bool {
+    match cmd {
+        CMD_ERASE | CMD_WRITE | CMD_FIRST_WORD | CMD_SET_MAC | CMD_SET_CONFIG => true,
+        #[cfg(feature = "compressed-write")]
+        CMD_WRITE_COMPRESSED => true,
+        _ => false,
+    }
+}
+
+/// Returns true for commands which need a tag once a key is stored, with the auth feature.
+#[cfg(feature = "auth")]
+fn requires_auth(cmd: u32) -> bool {
+    match cmd {
+        CMD_ERASE | CMD_WRITE | CMD_BOOT | CMD_TRIAL_BOOT | CMD_SET_MAC | CMD_SET_CONFIG |
+        CMD_FIRST_WORD | CMD_REBOOT | CMD_UNLOCK | CMD_SET_KEY => true,
+        #[cfg(feature = "compressed-write")]
+        CMD_WRITE_COMPRESSED => true,
+        #[cfg(feature = "backup-sram")]
+        CMD_WRITE_MEM => true,
+        _ => false,
+    }
+}
+
+/// Returns true if `cmd` is a raw erase or write touching the configuration sector,
+/// whose first word has been received.
+///
+/// Once a key is stored these are refused, as erasing the sector would remove the key
+/// with it. The configuration and key are changed with the set config and set key
+/// commands instead, which keep the key.
+#[cfg(feature = "auth")]
+fn touches_config<S: Socket>(socket: &mut S, cmd: u32) -> bool {
+    match cmd {
+        CMD_ERASE | CMD_WRITE => (),
+        #[cfg(feature = "compressed-write")]
+        CMD_WRITE_COMPRESSED => (),
+        _ => return false,
+    }
+    let (adr, len) = wire::peek_adr_len(socket);
+    len > 0 && adr < FLASH_USER && adr as u64 + len as u64 > FLASH_CONFIG as u64
+}
+
+/// Check the tag ending the command `cmd`, whose first word has been received.
+///
+/// The rest of the command is left to be received as usual, through `Untagged` so the
+/// tag is hidden, and so the whole command must already be in the socket buffer, which
+/// starts afresh with each connection.
+#[cfg(feature = "auth")]
+fn authenticated<S: Socket>(socket: &mut S, cmd: u32) -> bool {
+    let queued = socket.recv_queue();
+    let rest = socket.peek(queued).unwrap_or(&[]);
+    if rest.len() != queued || queued < sha256::DIGEST_LEN {
+        return auth::verify(cmd, &[], &[]);
+    }
+    let (body, tag) = rest.split_at(queued - sha256::DIGEST_LEN);
+    auth::verify(cmd, body, tag)
+}
+
+/// A socket whose received data stops before the tag ending an authenticated command,
+/// so that its handler sees only the command itself.
+#[cfg(feature = "auth")]
+struct Untagged<'s, S: Socket + 's> {
+    socket: &'s mut S,
+    /// Bytes of the command not yet received.
+    body: usize,
+}
+
+#[cfg(feature = "auth")]
+impl<'s, S: Socket> Untagged<'s, S> {
+    /// Wrap `socket`, which must hold the rest of a command whose tag has been checked.
+    fn new(socket: &'s mut S) -> Untagged<'s, S> {
+        let body = socket.recv_queue().saturating_sub(sha256::DIGEST_LEN);
+        Untagged { socket, body }
+    }
+
+    /// Discard anything the handler left unread, and the tag.
+    fn finish(self) {
+        while self.socket.recv_queue() > 0 {
+            match self.socket.recv(|buf| (buf.len(), buf.len())) {
+                Ok(n) if n > 0 => (),
+                _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "auth")]
+impl<'s, S: Socket> Socket for Untagged<'s, S> {
+    fn recv<R, F>(&mut self, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> (usize, R)
+    {
+        let body = self.body;
+        let (n, result) = self.socket.recv(|buf| {
+            let len = core::cmp::min(buf.len(), body);
+            let (n, result) = f(&mut buf[..len]);
+            (n, (n, result))
+        })?;
+        self.body -= n;
+        Ok(result)
+    }
+
+    fn recv_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        let len = core::cmp::min(data.len(), self.body);
+        let n = self.socket.recv_slice(&mut data[..len])?;
+        self.body -= n;
+        Ok(n)
+    }
+
+    fn peek(&mut self, size: usize) -> smoltcp::Result<&[u8]> {
+        self.socket.peek(core::cmp::min(size, self.body))
+    }
+
+    fn peek_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        let len = core::cmp::min(data.len(), self.body);
+        self.socket.peek_slice(&mut data[..len])
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> smoltcp::Result<usize> {
+        self.socket.send_slice(data)
+    }
+
+    fn recv_queue(&self) -> usize {
+        core::cmp::min(self.socket.recv_queue(), self.body)
+    }
+
+    fn send_queue(&self) -> usize {
+        self.socket.send_queue()
+    }
+
+    fn recv_capacity(&self) -> usize {
+        self.socket.recv_capacity()
+    }
+
+    fn send_capacity(&self) -> usize {
+        self.socket.send_capacity()
+    }
+
+    fn may_send(&self) -> bool {
+        self.socket.may_send()
+    }
+}
+
+/// Send a new nonce for authenticating the next command.
+#[cfg(feature = "auth")]
+fn cmd_auth_nonce<S: Socket>(socket: &mut S) {
+    match auth::new_nonce() {
+        Some(nonce) => {
+            send_status(socket, Error::Success);
+            socket.send_slice(&nonce).ok();
+        },
+        None => send_status(socket, Error::InternalError),
+    }
+}
+
+/// Store the key for authenticating commands, or remove it if the key is all zeros.
+#[cfg(feature = "auth")]
+fn cmd_set_key<S: Socket>(socket: &mut S) {
+    let mut key = [0u8; flash::KEY_LEN];
+    if socket.recv_slice(&mut key[..]) != Ok(flash::KEY_LEN) {
+        send_status(socket, Error::DataLengthIncorrect);
+        return;
+    }
+    let key = if key.iter().all(|&byte| byte == 0) { None } else { Some(key) };
+    match flash::KeyBlock::store(key, &mut flash::SoftwareCrc32) {
+        Ok(()) => send_status(socket, Error::Success),
+        Err(err) => { send_status(socket, err); send_error_detail(socket); },
+    }
+}
+
+/// Allow flash to be modified until the next reset, even if the configuration locks it.
+///
+/// The token must be the CRC of the device's unique ID, as computed by CrcCompute.
+fn cmd_unlock<S: Socket>(socket: &mut S) {
+    let token = recv_u32(socket);
+    if token == flash::SoftwareCrc32.compute(&::unique_id()) {
+        UNLOCKED.store(true, Ordering::Relaxed);
+        send_status(socket, Error::Success);
+    } else {
+        send_status(socket, Error::Locked);
+    }
+}
+
+/// Reset back into the bootloader, for example to use a new configuration.
+fn cmd_reboot<S: Socket>(socket: &mut S) {
+    send_status(socket, Error::Success);
+    ::schedule_reset_to_bootloader(50);
+}
+
+/// Store a new configuration, sent as a UserConfig without its final checksum word.
+///
+/// It is always stored at FLASH_CONFIG, so the client doesn't need to know the address,
+/// and the magic value and checksum are set by the device. It is used after the next reset,
+/// except that a lock on flashing takes effect at once.
+fn cmd_set_config<S: Socket>(socket: &mut S) {
+    let mut bytes = [0u8; flash::CONFIG_SIZE];
+    let len = flash::CONFIG_SIZE - 4;
+    if socket.recv_slice(&mut bytes[..len]) != Ok(len) {
+        send_status(socket, Error::DataLengthIncorrect);
+        return;
+    }
+    flash::UserConfig::seal(&mut bytes, &mut flash::SoftwareCrc32);
+    let cfg = match flash::UserConfig::from_bytes(&bytes, &mut flash::SoftwareCrc32) {
+        Ok(cfg) => cfg,
+        Err(_) => { send_status(socket, Error::InternalError); return; },
+    };
+    match cfg.store(&mut flash::SoftwareCrc32) {
+        Ok(()) => {
+            if cfg.flags & flash::CONFIG_FLAG_LOCKED != 0 {
+                UNLOCKED.store(false, Ordering::Relaxed);
+            }
+            send_status(socket, Error::Success);
+        },
+        Err(err) => { send_status(socket, err); send_error_detail(socket); },
+    }
+}
+
+/// Send the error counters, and clear them if the word following the command is non-zero.
+///
+/// The response is the structure version and the number of counters, followed by the
+/// counters in the order given in `diagnostics::COUNTERS`.
+fn cmd_diagnostics(socket: &mut TcpSocket, eth_dev: &mut EthernetDevice) {
+    let reset = recv_u32(socket) != 0;
+    eth_dev.count_missed_frames();
+    send_status(socket, Error::Success);
+    send_u32(socket, diagnostics::VERSION);
+    send_u32(socket, diagnostics::COUNTERS.len() as u32);
+    diagnostics::read(reset, |value| send_u32(socket, value));
+}
+
+/// Version of the mem info structure. Fields are only ever appended, increasing the version.
+const MEM_INFO_VERSION: u32 = 1;
+/// Number of words in the mem info structure following the version and count.
+const MEM_INFO_WORDS: u32 = 9;
+
+/// Send RAM use figures, as the structure version and number of words which follow:
+/// * bytes of RAM taken by statics
+/// * most bytes of stack used since startup
+/// * bytes never used between the statics and the stack
+/// * bytes of ethernet descriptors and buffers
+/// * bytes of log buffer
+/// * TCP receive buffer capacity and bytes queued, before this command was read
+/// * TCP send buffer capacity and bytes queued, before this response
+fn cmd_mem_info<S: Socket>(socket: &mut S) {
+    let rx_queued = socket.recv_queue() as u32;
+    let tx_queued = socket.send_queue() as u32;
+    send_status(socket, Error::Success);
+    send_u32(socket, MEM_INFO_VERSION);
+    send_u32(socket, MEM_INFO_WORDS);
+    send_u32(socket, meminfo::statics_bytes());
+    send_u32(socket, meminfo::stack_peak_bytes());
+    send_u32(socket, meminfo::free_bytes());
+    send_u32(socket, ethernet::BUFFER_BYTES as u32);
+    send_u32(socket, LOG_BUFFER_SIZE as u32);
+    send_u32(socket, socket.recv_capacity() as u32);
+    send_u32(socket, rx_queued);
+    send_u32(socket, socket.send_capacity() as u32);
+    send_u32(socket, tx_queued);
+}
+
+/// Send the bootloader's log output, as its length in bytes followed by the text.
+fn cmd_get_log<S: Socket>(socket: &mut S) {
+    // The status is sent first, as the indicator called by send_status may itself print.
+    send_status(socket, Error::Success);
+    logbuf::with_contents(|older, newer| {
+        send_u32(socket, (older.len() + newer.len()) as u32);
+        socket.send_slice(older).ok();
+        socket.send_slice(newer).ok();
+    });
+}
+
+/// Send the stored configuration as it is in flash, after its length,
+/// whether or not it is valid.
+fn cmd_get_config<S: Socket>(socket: &mut S) {
+    let bytes = flash::read_config_bytes();
+    send_status(socket, Error::Success);
+    send_u32(socket, bytes.len() as u32);
+    socket.send_slice(bytes).ok();
+}
+
+/// Send back the received payload, which follows its length.
+///
+/// The payload is taken from a single receive, as for the write command, so a payload
+/// which is split over several segments by the network gives DataLengthIncorrect.
+/// This lets the client find the largest write which arrives intact.
+fn cmd_echo<S: Socket>(socket: &mut S) {
+    let len = recv_u32(socket) as usize;
+    if len > ECHO_MAX {
+        send_status(socket, Error::LengthTooLong);
+        return;
+    }
+    let mut buf = [0u8; ECHO_MAX];
+    match socket.recv(|data| {
+        let n = core::cmp::min(data.len(), ECHO_MAX);
+        buf[..n].copy_from_slice(&data[..n]);
+        (data.len(), data.len())
+    }) {
+        Ok(n) if n == len => {
+            send_status(socket, Error::Success);
+            socket.send_slice(&buf[..len]).ok();
+        },
+        Ok(_) => send_status(socket, Error::DataLengthIncorrect),
+        Err(_) => send_status(socket, Error::NetworkError),
+    }
+}
+
+/// Operations of the first word command.
+const FIRST_WORD_DEFER: u32 = 0;
+const FIRST_WORD_COMMIT: u32 = 1;
+const FIRST_WORD_CANCEL: u32 = 2;
+
+/// Control holding back the first word of the user firmware while it is written.
+///
+/// Defer replies with the address of the word which will be held back. Commit is
+/// followed by the expected value of the word, and programs it.
+fn cmd_first_word<S: Socket>(socket: &mut S) {
+    match recv_u32(socket) {
+        FIRST_WORD_DEFER => {
+            flash::defer_first_word();
+            send_status(socket, Error::Success);
+            send_u32(socket, FLASH_USER);
+        },
+        FIRST_WORD_COMMIT => match flash::commit_first_word(recv_u32(socket)) {
+            Ok(()) => send_status(socket, Error::Success),
+            Err(err) => { send_status(socket, err); send_error_detail(socket); },
+        },
+        FIRST_WORD_CANCEL => {
+            flash::cancel_first_word();
+            send_status(socket, Error::Success);
+        },
+        _ => send_status(socket, Error::UnknownCommand),
+    }
+}
+
+fn cmd_trial_boot<S: Socket>(socket: &mut S) {
+    bootload::set_trial_flag();
+    send_status(socket, Error::Success);
+    ::schedule_reset(50);
+}
+
+/// Read a command word from the socket and check it may run.
+///
+/// Returns the command, or None if it was refused, in which case its status has been sent.
+fn receive_command<S: Socket>(socket: &mut S) -> Option<u32> {
+    let cmd = recv_u32(socket);
+    #[cfg(feature = "trace")]
+    trace_command(socket, cmd);
+    INDICATOR.command_started(cmd);
+    #[cfg(feature = "auth")]
+    {
+        if requires_auth(cmd) && auth::enabled() {
+            if !authenticated(socket, cmd) {
+                send_status(socket, Error::Unauthorized);
+                return None;
+            }
+            if touches_config(socket, cmd) {
+                send_status(socket, Error::InvalidAddress);
+                return None;
+            }
+        }
+    }
+    if modifies_flash(cmd) && flash_locked() {
+        send_status(socket, Error::Locked);
+        return None;
+    }
+    Some(cmd)
+}
+
+/// Run the command `cmd`, which has passed `receive_command`, for those commands
+/// which only need the socket.
+///
+/// With the auth feature, the handler doesn't see the tag ending the command,
+/// which is discarded afterwards.
+///
+/// Returns any erase which still needs to be blank-checked.
+fn run_command<S: Socket>(socket: &mut S, cmd: u32, cfg_err: Option<flash::ConfigError>)
+    -> Option<PendingErase>
+{
+    #[cfg(feature = "auth")]
+    {
+        if requires_auth(cmd) && auth::enabled() {
+            let mut untagged = Untagged::new(socket);
+            let pending = run_handler(&mut untagged, cmd, cfg_err);
+            untagged.finish();
+            return pending;
+        }
+    }
+    run_handler(socket, cmd, cfg_err)
+}
+
+/// Run the handler for `cmd`, as `run_command`.
+fn run_handler<S: Socket>(socket: &mut S, cmd: u32, cfg_err: Option<flash::ConfigError>)
+    -> Option<PendingErase>
+{
+    match cmd {
+        CMD_INFO => cmd_info(socket, cfg_err),
+        CMD_READ => cmd_read(socket),
+        CMD_ERASE => return cmd_erase(socket),
+        CMD_WRITE => cmd_write(socket),
+        CMD_BOOT => cmd_boot(socket),
+        CMD_LAYOUT => cmd_layout(socket),
+        CMD_TRIAL_BOOT => cmd_trial_boot(socket),
+        CMD_SET_MAC => cmd_set_mac(socket),
+        CMD_GET_LOG => cmd_get_log(socket),
+        CMD_SET_CONFIG => cmd_set_config(socket),
+        CMD_MEM_INFO => cmd_mem_info(socket),
+        CMD_FIRST_WORD => cmd_first_word(socket),
+        CMD_ECHO => cmd_echo(socket),
+        CMD_GET_CONFIG => cmd_get_config(socket),
+        CMD_REBOOT => cmd_reboot(socket),
+        CMD_UNLOCK => cmd_unlock(socket),
+        #[cfg(feature = "auth")]
+        CMD_AUTH_NONCE => cmd_auth_nonce(socket),
+        #[cfg(feature = "auth")]
+        CMD_SET_KEY => cmd_set_key(socket),
+        #[cfg(feature = "compressed-write")]
+        CMD_WRITE_COMPRESSED => cmd_write_compressed(socket),
+        #[cfg(feature = "backup-sram")]
+        CMD_READ_MEM => cmd_read_mem(socket),
+        #[cfg(feature = "backup-sram")]
+        CMD_WRITE_MEM => cmd_write_mem(socket),
+        _ => send_status(socket, Error::UnknownCommand),
+    };
+    None
+}
+
+/// Read a command word from the socket and run the corresponding command.
+///
+/// Returns any erase which still needs to be blank-checked,
+/// in which case the socket must be kept open until that completes.
+fn dispatch_command(socket: &mut TcpSocket, cfg_err: Option<flash::ConfigError>,
+                    eth_iface: &mut Interface) -> Option<PendingErase>
+{
+    let cmd = receive_command(socket)?;
+    match cmd {
+        CMD_SELF_TEST => cmd_self_test(socket, eth_iface.device_mut()),
+        CMD_STATUS => cmd_status(socket, cfg_err, eth_iface),
+        CMD_DIAGNOSTICS => cmd_diagnostics(socket, eth_iface.device_mut()),
+        _ if cmd >= CMD_CUSTOM_BASE => match CUSTOM_HANDLER {
+            Some(handler) if handler.handle(cmd, socket) => (),
+            _ => send_status(socket, Error::UnknownCommand),
+        },
+        _ => return run_command(socket, cmd, cfg_err),
+    };
+    None
+}
+
+/// Returns true if the command starting with `hdr` may run on the emergency port.
+///
+/// Only the commands needed to identify the device, store a new configuration and
+/// reset back into the bootloader to use it are accepted, along with fetching a nonce
+/// to sign the configuration with the auth feature.
+#[cfg(feature = "emergency-port")]
+fn emergency_allowed(hdr: wire::Header) -> bool {
+    match hdr.cmd {
+        CMD_INFO | CMD_REBOOT | CMD_SET_CONFIG => true,
+        #[cfg(feature = "auth")]
+        CMD_AUTH_NONCE => true,
+        _ => false,
+    }
+}
+
+/// Handle a command received on the emergency port, if `emergency_allowed`.
+#[cfg(feature = "emergency-port")]
+fn dispatch_emergency_command(socket: &mut TcpSocket, cfg_err: Option<flash::ConfigError>,
+                              eth_iface: &mut Interface) -> Option<PendingErase>
+{
+    if emergency_allowed(wire::peek_header(socket)) {
+        dispatch_command(socket, cfg_err, eth_iface)
+    } else {
+        send_status(socket, Error::UnknownCommand);
+        None
+    }
+}
+
 // Stores the underlying data buffers. If these were included in Network,
 // they couldn't live in BSS and therefore take up a load of flash space.
 struct NetworkBuffers {
@@ -123,57 +1082,202 @@ static mut NETWORK_BUFFERS: NetworkBuffers = NetworkBuffers {
     tcp_rx_buf: [0u8; 1536],
 };
 
-// Stores all the smoltcp required structs.
-pub struct Network<'a> {
-    neighbor_cache_storage: [Option<(IpAddress, Neighbor)>; 16],
+// The emergency socket has its own small buffers, so it keeps working
+// even if the main socket's are full of a stuck transfer.
+#[cfg(feature = "emergency-port")]
+struct EmergencyBuffers {
+    tcp_tx_buf: [u8; 512],
+    tcp_rx_buf: [u8; 512],
+}
+
+#[cfg(feature = "emergency-port")]
+static mut EMERGENCY_BUFFERS: EmergencyBuffers = EmergencyBuffers {
+    tcp_tx_buf: [0u8; 512],
+    tcp_rx_buf: [0u8; 512],
+};
+
+#[cfg(feature = "emergency-port")]
+const NUM_SOCKETS: usize = 2;
+#[cfg(not(feature = "emergency-port"))]
+const NUM_SOCKETS: usize = 1;
+
+// Stores the device and other storage borrowed by the smoltcp structs in Network.
+struct NetworkStorage {
+    device: Option<EthernetDevice>,
+    neighbor_cache: [Option<(IpAddress, Neighbor)>; NEIGHBOR_CACHE_SIZE],
     ip_addr: Option<[IpCidr; 1]>,
-    eth_iface: Option<EthernetInterface<'a, 'a, 'a, EthernetDevice>>,
-    sockets_storage: [Option<SocketSetItem<'a, 'a>>; 1],
-    sockets: Option<SocketSet<'a, 'a, 'a>>,
-    tcp_handle: Option<SocketHandle>,
-    initialised: bool,
+    sockets: [Option<SocketSetItem<'static, 'static>>; NUM_SOCKETS],
 }
 
-static mut NETWORK: Network = Network {
-    neighbor_cache_storage: [None; 16],
+static mut NETWORK_STORAGE: NetworkStorage = NetworkStorage {
+    device: None,
+    neighbor_cache: [None; NEIGHBOR_CACHE_SIZE],
     ip_addr: None,
-    eth_iface: None,
-    sockets_storage: [None],
-    sockets: None,
-    tcp_handle: None,
-    initialised: false,
+    #[cfg(not(feature = "emergency-port"))]
+    sockets: [None],
+    #[cfg(feature = "emergency-port")]
+    sockets: [None, None],
 };
 
+// Stores all the smoltcp required structs.
+// The interface borrows the device from NETWORK_STORAGE, so it can be rebuilt over it.
+type Interface = EthernetInterface<'static, 'static, 'static, &'static mut EthernetDevice>;
+
+pub struct Network {
+    eth_iface: Interface,
+    sockets: SocketSet<'static, 'static, 'static>,
+    tcp_handle: SocketHandle,
+    erase_verify: Option<PendingErase>,
+    #[cfg(feature = "emergency-port")]
+    emergency_handle: SocketHandle,
+    #[cfg(feature = "emergency-port")]
+    emergency_erase_verify: Option<PendingErase>,
+    cfg_err: Option<flash::ConfigError>,
+    /// Number of consecutive polls on which a socket failed to listen.
+    listen_retries: u32,
+    /// Set once the ethernet link has been established.
+    link_up: bool,
+}
+
+static NETWORK: Mutex<RefCell<Option<Network>>> = Mutex::new(RefCell::new(None));
+
 /// Initialise the static NETWORK.
 ///
-/// Sets up the required EthernetInterface and sockets.
+/// Moves `eth_dev` into this module, and sets up the required EthernetInterface
+/// and sockets over it.
+/// `cfg_err` is the reason the stored configuration couldn't be used, if any,
+/// which is reported in response to the info command.
 ///
-/// Do not call more than once or this function will panic.
-pub fn init<'a>(eth_dev: EthernetDevice, mac_addr: EthernetAddress, ip_addr: IpCidr) {
-    // Unsafe required for access to NETWORK.
-    // NETWORK.initialised guards against calling twice.
-    unsafe {
-        cortex_m::interrupt::free(|_| {
-            if NETWORK.initialised {
-                panic!("NETWORK already initialised");
-            }
-            NETWORK.initialised = true;
-        });
+/// May be called again, which drops the existing network and its device first.
+pub fn init(eth_dev: EthernetDevice, mac_addr: EthernetAddress, ip_addr: IpCidr,
+            cfg_err: Option<flash::ConfigError>)
+{
+    #[cfg(feature = "ip-conflict")]
+    {
+        if let IpAddress::Ipv4(ip) = ip_addr.address() {
+            ipconflict::set_addresses(mac_addr, ip);
+        }
+    }
 
-        let neighbor_cache = NeighborCache::new(&mut NETWORK.neighbor_cache_storage.as_mut()[..]);
+    ::interrupt_free(|cs| {
+        let mut network = NETWORK.borrow(cs).borrow_mut();
 
-        NETWORK.ip_addr = Some([ip_addr]);
-        NETWORK.eth_iface = Some(EthernetInterfaceBuilder::new(eth_dev)
-                                .ethernet_addr(mac_addr)
-                                .neighbor_cache(neighbor_cache)
-                                .ip_addrs(&mut NETWORK.ip_addr.as_mut().unwrap()[..])
-                                .finalize());
+        // Drop the old network before its storage is reused
+        *network = None;
+        unsafe { (*core::ptr::addr_of_mut!(NETWORK_STORAGE)).device = Some(eth_dev) };
+        *network = Some(unsafe { build(mac_addr, ip_addr, cfg_err) });
+    });
+}
+
+/// Rebuild the interface and sockets of `network` over the same device, keeping its
+/// addresses, configuration error and link state. Open connections are dropped.
+fn rebuild(network: &mut Option<Network>) {
+    let old = match network.take() {
+        Some(old) => old,
+        None => return,
+    };
+    let mac_addr = old.eth_iface.ethernet_addr();
+    let ip_addr = old.eth_iface.ip_addrs()[0];
+    let (cfg_err, link_up) = (old.cfg_err, old.link_up);
+    drop(old);
+
+    let mut new = unsafe { build(mac_addr, ip_addr, cfg_err) };
+    new.link_up = link_up;
+    *network = Some(new);
+}
 
-        NETWORK.sockets = Some(SocketSet::new(&mut NETWORK.sockets_storage.as_mut()[..]));
-        let tcp_rx_buf = TcpSocketBuffer::new(&mut NETWORK_BUFFERS.tcp_rx_buf.as_mut()[..]);
-        let tcp_tx_buf = TcpSocketBuffer::new(&mut NETWORK_BUFFERS.tcp_tx_buf.as_mut()[..]);
-        let tcp_socket = TcpSocket::new(tcp_rx_buf, tcp_tx_buf);
-        NETWORK.tcp_handle = Some(NETWORK.sockets.as_mut().unwrap().add(tcp_socket));
+/// Build a Network over the device in NETWORK_STORAGE, which must have been set.
+///
+/// Unsafe as it hands out references to NETWORK_STORAGE and the socket buffers:
+/// the caller must have dropped any Network built before, which held the only others.
+unsafe fn build(mac_addr: EthernetAddress, ip_addr: IpCidr,
+                cfg_err: Option<flash::ConfigError>) -> Network
+{
+    let storage = &mut *core::ptr::addr_of_mut!(NETWORK_STORAGE);
+    let buffers = &mut *core::ptr::addr_of_mut!(NETWORK_BUFFERS);
+
+    // Clear anything left by an earlier Network, which smoltcp would otherwise reuse
+    storage.neighbor_cache.iter_mut().for_each(|entry| *entry = None);
+    storage.sockets.iter_mut().for_each(|item| *item = None);
+
+    let neighbor_cache = NeighborCache::new(&mut storage.neighbor_cache[..]);
+
+    storage.ip_addr = Some([ip_addr]);
+    let eth_iface = EthernetInterfaceBuilder::new(storage.device.as_mut().unwrap())
+                    .ethernet_addr(mac_addr)
+                    .neighbor_cache(neighbor_cache)
+                    .ip_addrs(&mut storage.ip_addr.as_mut().unwrap()[..])
+                    .finalize();
+
+    let mut sockets = SocketSet::new(&mut storage.sockets[..]);
+    let tcp_rx_buf = TcpSocketBuffer::new(&mut buffers.tcp_rx_buf[..]);
+    let tcp_tx_buf = TcpSocketBuffer::new(&mut buffers.tcp_tx_buf[..]);
+    let tcp_socket = TcpSocket::new(tcp_rx_buf, tcp_tx_buf);
+    let tcp_handle = sockets.add(tcp_socket);
+
+    #[cfg(feature = "emergency-port")]
+    let emergency_handle = {
+        let buffers = &mut *core::ptr::addr_of_mut!(EMERGENCY_BUFFERS);
+        let tcp_rx_buf = TcpSocketBuffer::new(&mut buffers.tcp_rx_buf[..]);
+        let tcp_tx_buf = TcpSocketBuffer::new(&mut buffers.tcp_tx_buf[..]);
+        sockets.add(TcpSocket::new(tcp_rx_buf, tcp_tx_buf))
+    };
+
+    Network {
+        eth_iface, sockets, tcp_handle, erase_verify: None,
+        #[cfg(feature = "emergency-port")]
+        emergency_handle,
+        #[cfg(feature = "emergency-port")]
+        emergency_erase_verify: None,
+        cfg_err,
+        listen_retries: 0,
+        link_up: false,
+    }
+}
+
+/// Service one TCP socket, listening on `port` and running commands with `dispatch`.
+///
+/// `erase_verify` holds any erase still being blank-checked on this socket.
+///
+/// Returns false if the socket could not be put back into the listening state, in which case
+/// it has been aborted so that listening can be retried on the next poll.
+fn poll_socket<F>(socket: &mut TcpSocket, port: u16, erase_verify: &mut Option<PendingErase>,
+                  dispatch: F) -> bool
+    where F: FnOnce(&mut TcpSocket) -> Option<PendingErase>
+{
+    if !socket.is_open() && socket.listen(port).is_err() {
+        socket.abort();
+        *erase_verify = None;
+        diagnostics::count(&diagnostics::LISTEN_FAILURES);
+        return false;
+    }
+    if let Some(pending) = *erase_verify {
+        // An erase is still being blank-checked, continue with the next chunk
+        *erase_verify = cmd_erase_verify(socket, pending);
+        if erase_verify.is_none() {
+            socket.close();
+        }
+    } else {
+        if !socket.may_recv() && socket.may_send() {
+            socket.close();
+        }
+        if socket.can_recv() {
+            *erase_verify = dispatch(socket);
+            if erase_verify.is_none() {
+                socket.close();
+            }
+        }
+    }
+    true
+}
+
+/// Report that the link is still down `ms` after the systick started,
+/// logging every LINK_WAIT_LOG_MS so the bootloader doesn't appear dead.
+fn link_waiting(ms: u32) {
+    INDICATOR.link_waiting(ms);
+    if ms > 0 && ms % LINK_WAIT_LOG_MS == 0 {
+        let mut buf = [0u8; 10];
+        print!(" Still waiting for link after ", digits_str(u32_to_dec(ms / 1000, &mut buf)), "s.\n");
     }
 }
 
@@ -181,47 +1285,338 @@ pub fn init<'a>(eth_dev: EthernetDevice, mac_addr: EthernetAddress, ip_addr: IpC
 ///
 /// Arrange for this function to be called frequently.
 pub fn poll(time_ms: i64) {
-    // Unsafe required to access static mut NETWORK.
-    // Since the entire poll is run in an interrupt-free context no
-    // other access to NETWORK can occur.
-    cortex_m::interrupt::free(|_| unsafe {
+    // The entire poll is run in an interrupt-free context,
+    // so no other access to NETWORK can occur.
+    ::interrupt_free(|cs| {
+        let mut slot = NETWORK.borrow(cs).borrow_mut();
+
         // Bail out early if NETWORK is not initialised.
-        if !NETWORK.initialised {
-            return;
-        }
+        let network = match slot.as_mut() {
+            Some(network) => network,
+            None => return,
+        };
 
-        let sockets = NETWORK.sockets.as_mut().unwrap();
+        // Check for the link until it first comes up. The socket is already listening.
+        if !network.link_up && time_ms as u32 % LINK_POLL_MS == 0 {
+            if network.eth_iface.device_mut().link_established() {
+                network.link_up = true;
+                startup::mark_uptime(Milestone::LinkUp);
+                INDICATOR.link_up();
+            } else {
+                link_waiting(time_ms as u32);
+            }
+        }
 
-        // Handle TCP
+        #[cfg(feature = "ip-conflict")]
         {
-            let mut socket = sockets.get::<TcpSocket>(NETWORK.tcp_handle.unwrap());
-            if !socket.is_open() {
-                socket.listen(TCP_PORT).unwrap();
+            if network.link_up {
+                ipconflict::poll(network.eth_iface.device_mut(), time_ms);
             }
-            if !socket.may_recv() && socket.may_send() {
-                socket.close();
-            }
-            if socket.can_recv() {
-                let mut cmd = [0u8; 4];
-                socket.recv_slice(&mut cmd[..]).ok();
-                let cmd = u32::from_le_bytes(cmd);
-                match cmd {
-                   CMD_INFO  => cmd_info(&mut socket),
-                   CMD_READ => cmd_read(&mut socket),
-                   CMD_ERASE => cmd_erase(&mut socket),
-                   CMD_WRITE => cmd_write(&mut socket),
-                   CMD_BOOT => cmd_boot(&mut socket),
-                    _ => (),
-                };
-                socket.close();
+        }
+
+        // Handle TCP
+        let listening = {
+            let cfg_err = network.cfg_err;
+            let eth_iface = &mut network.eth_iface;
+            let mut socket = network.sockets.get::<TcpSocket>(network.tcp_handle);
+            poll_socket(&mut socket, TCP_PORT, &mut network.erase_verify,
+                        |socket| dispatch_command(socket, cfg_err, eth_iface))
+        };
+
+        #[cfg(feature = "emergency-port")]
+        let listening = listening & {
+            let cfg_err = network.cfg_err;
+            let eth_iface = &mut network.eth_iface;
+            let mut socket = network.sockets.get::<TcpSocket>(network.emergency_handle);
+            poll_socket(&mut socket, EMERGENCY_TCP_PORT, &mut network.emergency_erase_verify,
+                        |socket| dispatch_emergency_command(socket, cfg_err, eth_iface))
+        };
+
+        // Switch to a new MAC address once the command that set it has finished
+        if network.sockets.get::<TcpSocket>(network.tcp_handle).state() == TcpState::Listen {
+            if let Some(mac) = PENDING_MAC.borrow(cs).take() {
+                network.eth_iface.device_mut().set_mac(mac);
+                network.eth_iface.set_ethernet_addr(mac);
+                #[cfg(feature = "ip-conflict")]
+                {
+                    if let Some(IpAddress::Ipv4(ip)) = network.eth_iface.ip_addrs().first()
+                                                             .map(|cidr| cidr.address()) {
+                        ipconflict::set_addresses(mac, ip);
+                    }
+                }
             }
         }
 
+        // If the sockets can't be made to listen again, rebuild the network stack
+        // over the same device, and poll it afresh next time.
+        network.listen_retries = if listening { 0 } else { network.listen_retries + 1 };
+        if network.listen_retries >= LISTEN_RETRY_LIMIT {
+            rebuild(&mut slot);
+            return;
+        }
+
         // Poll smoltcp
         let timestamp = Instant::from_millis(time_ms);
-        match NETWORK.eth_iface.as_mut().unwrap().poll(sockets, timestamp) {
+        match network.eth_iface.poll(&mut network.sockets, timestamp) {
             Ok(_) | Err(smoltcp::Error::Exhausted) => (),
-            Err(_) => (),
+            // Frames for other protocols or hosts are normal, and truncated
+            // frames are already counted as dropped.
+            Err(smoltcp::Error::Unrecognized) | Err(smoltcp::Error::Truncated) => (),
+            Err(_) => diagnostics::count(&diagnostics::POLL_ERRORS),
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::flash::fake;
+    use ::test_rng::Rng;
+    use ::wire::{self, FakeSocket};
+
+    /// Commands which touch RAM or peripherals the host doesn't have.
+    fn needs_hardware(cmd: u32) -> bool {
+        match cmd {
+            CMD_TRIAL_BOOT => true,
+            #[cfg(feature = "backup-sram")]
+            CMD_READ_MEM | CMD_WRITE_MEM => true,
+            _ => false,
+        }
+    }
+
+    /// Run the command in `rx` as `poll_socket` would, including any blank-check of an erase.
+    fn run(rx: &[u8]) -> FakeSocket {
+        let mut socket = FakeSocket::new(rx);
+        let mut pending = receive_command(&mut socket)
+                          .and_then(|cmd| run_command(&mut socket, cmd, None));
+        while let Some(erase) = pending {
+            pending = cmd_erase_verify(&mut socket, erase);
+        }
+        socket
+    }
+
+    /// Every response starts with a defined status, and a successful read, write or erase
+    /// covered only what the protocol allows.
+    fn check(rx: &[u8], socket: &FakeSocket) {
+        let status = socket.status().expect("no status sent");
+        assert!(status <= Error::Unauthorized as u32, "status {} for {:02X?}", status, rx);
+        if status != Error::Success as u32 {
+            return;
+        }
+        let hdr = wire::decode_header(rx);
+        let end = hdr.adr as u64 + hdr.len as u64;
+        match hdr.cmd {
+            CMD_READ => {
+                assert!(hdr.adr >= FLASH_SECTOR_ADDRESSES[0] && end <= FLASH_END as u64 + 1);
+                assert_eq!(socket.tx.len(), 4 + hdr.len as usize);
+            },
+            CMD_WRITE | CMD_ERASE =>
+                assert!(hdr.adr >= FLASH_CONFIG && end <= FLASH_END as u64 + 1, "{:02X?}", rx),
+            _ => (),
+        }
+    }
+
+    /// Commands known to the firmware, and some which aren't.
+    const COMMANDS: &[u32] = &[
+        CMD_INFO, CMD_READ, CMD_ERASE, CMD_WRITE, CMD_BOOT, CMD_LAYOUT, CMD_SET_MAC,
+        CMD_GET_LOG, CMD_SET_CONFIG, CMD_MEM_INFO, CMD_FIRST_WORD, CMD_ECHO, CMD_GET_CONFIG, CMD_REBOOT,
+        CMD_UNLOCK, 22, 23, 11, 7, 8, 24, CMD_CUSTOM_BASE, 0xFFFF_FFFF,
+    ];
+
+    fn pick(rng: &mut Rng, values: &[u32]) -> u32 {
+        values[rng.below(values.len() as u32) as usize]
+    }
+
+    /// A command word, address, length and payload, each either near a boundary
+    /// the firmware checks or entirely random.
+    fn structured_command(rng: &mut Rng) -> Vec<u8> {
+        let cmd = pick(rng, COMMANDS);
+        let last_sector = FLASH_SECTOR_ADDRESSES[FLASH_SECTOR_ADDRESSES.len() - 1];
+        let adr = match rng.below(4) {
+            0 => rng.next_u32(),
+            1 if cmd == CMD_UNLOCK => flash::SoftwareCrc32.compute(&::unique_id()),
+            _ => pick(rng, &[FLASH_SECTOR_ADDRESSES[0], FLASH_CONFIG, FLASH_USER - 4,
+                             FLASH_USER, last_sector, FLASH_END - 3, FLASH_END + 1])
+                 .wrapping_add(pick(rng, &[0, 0, 1, 4, 0xFFFF_FFFC])),
+        };
+        let len = match rng.below(4) {
+            0 => rng.next_u32(),
+            _ => pick(rng, &[0, 4, 8, 6, 1020, 1024, 1028, FLASH_USER - FLASH_CONFIG]),
+        };
+        let payload = match rng.below(3) {
+            0 => rng.bytes(64),
+            _ => (0..core::cmp::min(len, 1100)).map(|_| rng.next_u32() as u8).collect(),
+        };
+        let mut rx = Vec::new();
+        rx.extend_from_slice(&cmd.to_le_bytes());
+        rx.extend_from_slice(&adr.to_le_bytes());
+        rx.extend_from_slice(&len.to_le_bytes());
+        rx.extend_from_slice(&payload);
+        rx
+    }
+
+    /// Feed sequences of commands, some valid and most malformed, each sequence starting
+    /// from erased flash. No command may panic, every one must send a status, and the
+    /// bootloader's own sectors must never change.
+    #[test]
+    fn command_fuzz() {
+        let _device = fake::lock();
+        let mut rng = Rng::new(0xB1E7);
+        for _ in 0..200 {
+            fake::reset();
+            for iteration in 0..20 {
+                let rx = if iteration % 4 == 3 { rng.bytes(40) } else { structured_command(&mut rng) };
+                if needs_hardware(wire::decode_header(&rx).cmd) {
+                    continue;
+                }
+                let socket = run(&rx);
+                check(&rx, &socket);
+            }
+            // Only the key marker at the end of the bootloader's flash may change
+            #[cfg(not(feature = "auth"))]
+            let end = FLASH_CONFIG;
+            #[cfg(feature = "auth")]
+            let end = ::config::AUTH_MARKER_ADDRESS;
+            let bootloader = fake::memory(FLASH_SECTOR_ADDRESSES[0],
+                                          (end - FLASH_SECTOR_ADDRESSES[0]) as usize);
+            assert!(bootloader.iter().all(|&byte| byte == 0xFF));
+        }
+    }
+
+    #[test]
+    fn write_then_read_back() {
+        let _device = fake::lock();
+        let data: Vec<u8> = (0..16).collect();
+        let write = command(&[CMD_WRITE, FLASH_USER + 0x100, 16], &data);
+        assert_eq!(run(&write).status(), Some(Error::Success as u32));
+
+        let socket = run(&command(&[CMD_READ, FLASH_USER + 0x100, 16], &[]));
+        assert_eq!(socket.status(), Some(Error::Success as u32));
+        assert_eq!(&socket.tx[4..], &data[..]);
+    }
+
+    fn command(words: &[u32], payload: &[u8]) -> Vec<u8> {
+        let mut rx: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+        rx.extend_from_slice(payload);
+        rx
+    }
+
+    /// Get a nonce and sign `rx` with it, as blethrs.py does.
+    #[cfg(feature = "auth")]
+    fn sign(key: &[u8], rx: &[u8]) -> Vec<u8> {
+        let socket = run(&command(&[CMD_AUTH_NONCE], &[]));
+        assert_eq!(socket.status(), Some(Error::Success as u32));
+        let nonce = &socket.tx[4..4 + auth::NONCE_LEN];
+        let mut signed = rx.to_vec();
+        signed.extend_from_slice(&sha256::hmac(key, &[nonce, rx]));
+        signed
+    }
+
+    /// A signed write stores exactly its data, not the tag after it, and can't be replayed.
+    #[cfg(feature = "auth")]
+    #[test]
+    fn signed_write() {
+        let _device = fake::lock();
+        let key = [0x6Bu8; flash::KEY_LEN];
+        assert_eq!(run(&command(&[CMD_SET_KEY], &key)).status(), Some(Error::Success as u32));
+
+        let data: Vec<u8> = (1..=8).collect();
+        let write = command(&[CMD_WRITE, FLASH_USER, 8], &data);
+        assert_eq!(run(&write).status(), Some(Error::Unauthorized as u32));
+
+        let signed = sign(&key, &write);
+        let socket = run(&signed);
+        assert_eq!(socket.status(), Some(Error::Success as u32));
+        assert!(socket.rx.is_empty());
+        assert_eq!(fake::memory(FLASH_USER, 8), &data[..]);
+        assert!(fake::memory(FLASH_USER + 8, sha256::DIGEST_LEN).iter().all(|&byte| byte == 0xFF));
+
+        assert_eq!(run(&signed).status(), Some(Error::Unauthorized as u32));
+    }
+
+    /// A signed command with fixed length arguments must still be exactly that long.
+    #[cfg(feature = "auth")]
+    #[test]
+    fn signed_set_config_length() {
+        let _device = fake::lock();
+        let key = [0x6Bu8; flash::KEY_LEN];
+        assert_eq!(run(&command(&[CMD_SET_KEY], &key)).status(), Some(Error::Success as u32));
+
+        let short = command(&[CMD_SET_CONFIG], &flash::DEFAULT_CONFIG.to_bytes()[..19]);
+        let socket = run(&sign(&key, &short));
+        assert_eq!(socket.status(), Some(Error::DataLengthIncorrect as u32));
+
+        let config = command(&[CMD_SET_CONFIG], &flash::DEFAULT_CONFIG.to_bytes()[..20]);
+        assert_eq!(run(&sign(&key, &config)).status(), Some(Error::Success as u32));
+        assert!(flash::KeyBlock::get(&mut flash::SoftwareCrc32).is_some());
+    }
+
+    /// Once a key is stored, even signed raw erases and writes may not touch the
+    /// configuration sector, as they would remove the key.
+    #[cfg(feature = "auth")]
+    #[test]
+    fn signed_raw_config_change() {
+        let _device = fake::lock();
+        let key = [0x6Bu8; flash::KEY_LEN];
+        assert_eq!(run(&command(&[CMD_SET_KEY], &key)).status(), Some(Error::Success as u32));
+
+        let refused = [
+            command(&[CMD_ERASE, FLASH_CONFIG, 24], &[]),
+            command(&[CMD_ERASE, FLASH_USER - 4, 8], &[]),
+            command(&[CMD_WRITE, FLASH_CONFIG + 0x200, 4], &[0; 4]),
+        ];
+        for rx in refused.iter() {
+            let socket = run(&sign(&key, rx));
+            assert_eq!(socket.status(), Some(Error::InvalidAddress as u32), "{:02X?}", rx);
+        }
+        assert!(flash::KeyBlock::get(&mut flash::SoftwareCrc32).is_some());
+        assert!(fake::memory(FLASH_CONFIG + 0x200, 4).iter().all(|&byte| byte == 0xFF));
+
+        let erase = command(&[CMD_ERASE, FLASH_USER, 4], &[]);
+        assert_eq!(run(&sign(&key, &erase)).status(), Some(Error::Success as u32));
+    }
+
+    /// Zero length reads, writes and erases succeed without touching flash.
+    #[test]
+    fn zero_length() {
+        let _device = fake::lock();
+        for &adr in &[FLASH_CONFIG, FLASH_USER, FLASH_END + 1] {
+            for &cmd in &[CMD_READ, CMD_WRITE, CMD_ERASE] {
+                let socket = run(&command(&[cmd, adr, 0], &[]));
+                assert_eq!(socket.status(), Some(Error::Success as u32), "{} at {:08X}", cmd, adr);
+            }
+        }
+        let user = fake::memory(FLASH_CONFIG, (FLASH_END + 1 - FLASH_CONFIG) as usize);
+        assert!(user.iter().all(|&byte| byte == 0xFF));
+    }
+
+    /// Only info, reboot, set config and, with auth, the nonce may use the emergency port,
+    /// whatever address and length follow them.
+    #[cfg(feature = "emergency-port")]
+    #[test]
+    fn emergency_allowed_fuzz() {
+        let allowed: &[u32] = &[
+            CMD_INFO, CMD_REBOOT, CMD_SET_CONFIG,
+            #[cfg(feature = "auth")]
+            CMD_AUTH_NONCE,
+        ];
+        let mut rng = Rng::new(0xE4);
+        for _ in 0..100_000 {
+            let hdr = wire::Header {
+                cmd: match rng.below(2) {
+                    0 => rng.next_u32(),
+                    _ => rng.below(32),
+                },
+                adr: match rng.below(2) {
+                    0 => rng.next_u32(),
+                    _ => FLASH_CONFIG.wrapping_add(rng.below(0x8000)).wrapping_sub(0x4000),
+                },
+                len: match rng.below(2) {
+                    0 => rng.next_u32(),
+                    _ => rng.below(0x8000),
+                },
+            };
+            assert_eq!(emergency_allowed(hdr), allowed.contains(&hdr.cmd), "{:?}", hdr);
+        }
+    }
+}
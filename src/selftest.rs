@@ -0,0 +1,112 @@
+//! Non-destructive checks of the board's health, run by the self test command.
+use core;
+
+use ::flash::{ConfigError, CrcCompute, SoftwareCrc32, UserConfig};
+use ::ethernet::EthernetDevice;
+use ::config::{FLASH_SECTOR_ADDRESSES, FLASH_END};
+#[cfg(not(feature = "auth"))]
+use ::config::FLASH_CONFIG;
+#[cfg(feature = "auth")]
+use ::config::AUTH_MARKER_ADDRESS;
+
+/// Failure bits in `Report::failures`.
+pub const FAIL_CONFIG: u32 = 1 << 0;
+pub const FAIL_RAM: u32 = 1 << 1;
+pub const FAIL_PHY: u32 = 1 << 2;
+pub const FAIL_FLASH_SIZE: u32 = 1 << 3;
+pub const FAIL_UID: u32 = 1 << 4;
+
+/// Address of the flash size in kB, programmed at the factory.
+const FLASH_SIZE_ADDRESS: u32 = 0x1FFF_7A22;
+
+pub struct Report {
+    /// Bitmask of FAIL_* for each check that failed.
+    pub failures: u32,
+    /// CRC of the bootloader's flash, from the start of flash up to the config sector.
+    pub bootloader_crc: u32,
+    /// 0 if the config is valid, 1 if blank, 2 if it has a bad magic, 3 if a bad checksum.
+    pub config: u32,
+    /// PHY identifier registers 2 and 3.
+    pub phy_id: (u16, u16),
+    /// Flash size in kB reported by the device.
+    pub flash_size_kb: u32,
+}
+
+/// Scratch RAM for the walking ones test.
+static mut SCRATCH: [u32; 16] = [0; 16];
+
+/// Run all checks. Nothing in flash is modified.
+pub fn run(eth_dev: &mut EthernetDevice) -> Report {
+    let mut failures = 0;
+
+    let config = match UserConfig::get(&mut SoftwareCrc32) {
+        Ok(_) => 0,
+        // A blank config is a normal state for a new board, so isn't a failure.
+        Err(ConfigError::Blank) => 1,
+        Err(ConfigError::BadMagic) => { failures |= FAIL_CONFIG; 2 },
+        Err(ConfigError::BadChecksum) => { failures |= FAIL_CONFIG; 3 },
+    };
+
+    if !walking_ones() {
+        failures |= FAIL_RAM;
+    }
+
+    // With no PHY responding, the pulled up MDIO line reads as all ones.
+    let phy_id = eth_dev.phy_id();
+    if phy_id.0 == 0xFFFF || phy_id == (0, 0) {
+        failures |= FAIL_PHY;
+    }
+
+    let flash_size_kb = unsafe {
+        core::ptr::read_volatile(FLASH_SIZE_ADDRESS as *const u16)
+    } as u32;
+    if flash_size_kb != (FLASH_END + 1 - FLASH_SECTOR_ADDRESSES[0]) / 1024 {
+        failures |= FAIL_FLASH_SIZE;
+    }
+
+    if !uid_valid() {
+        failures |= FAIL_UID;
+    }
+
+    Report { failures, bootloader_crc: bootloader_crc(), config, phy_id, flash_size_kb }
+}
+
+/// CRC of the bootloader's own flash, for comparison with a known build.
+///
+/// With the auth feature, the key marker at the end is left out, as it changes.
+fn bootloader_crc() -> u32 {
+    let start = FLASH_SECTOR_ADDRESSES[0];
+    #[cfg(not(feature = "auth"))]
+    let end = FLASH_CONFIG;
+    #[cfg(feature = "auth")]
+    let end = AUTH_MARKER_ADDRESS;
+    let words = ((end - start) / 4) as usize;
+    let data = unsafe { core::slice::from_raw_parts(start as *const u32, words) };
+    SoftwareCrc32.compute(data)
+}
+
+/// Write and read back each single bit in turn over the scratch RAM.
+fn walking_ones() -> bool {
+    let base = core::ptr::addr_of_mut!(SCRATCH) as *mut u32;
+    for idx in 0..16 {
+        for bit in 0..32 {
+            let word = unsafe { base.add(idx) };
+            unsafe { core::ptr::write_volatile(word, 1 << bit) };
+            if unsafe { core::ptr::read_volatile(word) } != 1 << bit {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Check the unique ID is neither all zeros nor all ones, as it would be if never programmed.
+fn uid_valid() -> bool {
+    let mut all_zero = true;
+    let mut all_ones = true;
+    for &word in ::unique_id().iter() {
+        all_zero &= word == 0;
+        all_ones &= word == 0xFFFF_FFFF;
+    }
+    !(all_zero || all_ones)
+}
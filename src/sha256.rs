@@ -0,0 +1,186 @@
+//! SHA-256 and HMAC-SHA-256, used to authenticate commands with the auth feature.
+//!
+//! Data is hashed incrementally, so a message made of several parts, such as a nonce and
+//! a command still in the socket buffer, needn't be copied together first.
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Length in bytes of a SHA-256 digest, and so of an HMAC tag.
+pub const DIGEST_LEN: usize = 32;
+
+const BLOCK_LEN: usize = 64;
+
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK_LEN],
+    /// Total bytes hashed so far.
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 { state: H0, block: [0; BLOCK_LEN], length: 0 }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let used = (self.length % BLOCK_LEN as u64) as usize;
+            let take = core::cmp::min(BLOCK_LEN - used, data.len());
+            self.block[used..used + take].copy_from_slice(&data[..take]);
+            self.length += take as u64;
+            data = &data[take..];
+            if used + take == BLOCK_LEN {
+                let block = self.block;
+                self.compress(&block);
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; DIGEST_LEN] {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.length % BLOCK_LEN as u64 != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0u8; DIGEST_LEN];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; BLOCK_LEN]) {
+        let mut w = [0u32; 64];
+        for (idx, chunk) in block.chunks(4).enumerate() {
+            w[idx] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for idx in 16..64 {
+            let s0 = w[idx - 15].rotate_right(7) ^ w[idx - 15].rotate_right(18) ^ (w[idx - 15] >> 3);
+            let s1 = w[idx - 2].rotate_right(17) ^ w[idx - 2].rotate_right(19) ^ (w[idx - 2] >> 10);
+            w[idx] = w[idx - 16].wrapping_add(s0).wrapping_add(w[idx - 7]).wrapping_add(s1);
+        }
+
+        let mut v = self.state;
+        for idx in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[idx]).wrapping_add(w[idx]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+        for (state, word) in self.state.iter_mut().zip(v.iter()) {
+            *state = state.wrapping_add(*word);
+        }
+    }
+}
+
+/// HMAC-SHA-256 of the concatenation of `parts`, with a key of at most one block.
+pub fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_LEN] {
+    let mut ipad = [0x36u8; BLOCK_LEN];
+    let mut opad = [0x5cu8; BLOCK_LEN];
+    for (idx, byte) in key.iter().take(BLOCK_LEN).enumerate() {
+        ipad[idx] ^= byte;
+        opad[idx] ^= byte;
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&ipad);
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(&opad);
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+/// Compare two tags in time independent of where they differ.
+pub fn tags_equal(a: &[u8; DIGEST_LEN], b: &[u8]) -> bool {
+    if b.len() != DIGEST_LEN {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).unwrap())
+                                  .collect()
+    }
+
+    fn digest(parts: &[&[u8]]) -> [u8; DIGEST_LEN] {
+        let mut sha = Sha256::new();
+        for part in parts {
+            sha.update(part);
+        }
+        sha.finish()
+    }
+
+    const MSG_448: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+
+    /// The one and two block examples from FIPS 180-2.
+    #[test]
+    fn fips_180_2_vectors() {
+        assert_eq!(digest(&[b"abc"]).to_vec(),
+                   unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+        assert_eq!(digest(&[MSG_448]).to_vec(),
+                   unhex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"));
+    }
+
+    /// Splitting a message across `update` calls, on and either side of block boundaries,
+    /// doesn't change its digest.
+    #[test]
+    fn split_updates() {
+        let msg: Vec<u8> = MSG_448.iter().chain(MSG_448.iter()).cloned().collect();
+        let whole = digest(&[&msg]);
+        for split in 0..=msg.len() {
+            assert_eq!(digest(&[&msg[..split], &msg[split..]]), whole, "split at {}", split);
+        }
+        assert_eq!(digest(&[&msg[..63], &msg[63..64], &[], &msg[64..65], &msg[65..]]), whole);
+
+        // The third FIPS 180-2 example, a million 'a's, in uneven chunks
+        let a = [b'a'; 129];
+        let mut sha = Sha256::new();
+        let mut left = 1_000_000;
+        for &take in [64, 1, 63, 128, 129, 65].iter().cycle() {
+            let take = core::cmp::min(take, left);
+            sha.update(&a[..take]);
+            left -= take;
+            if left == 0 {
+                break;
+            }
+        }
+        assert_eq!(sha.finish().to_vec(),
+                   unhex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"));
+    }
+
+    /// Test cases 1 and 2 from RFC 4231.
+    #[test]
+    fn rfc_4231_hmac() {
+        assert_eq!(hmac(&[0x0b; 20], &[b"Hi There"]).to_vec(),
+                   unhex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"));
+        let tag = unhex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hmac(b"Jefe", &[b"what do ya want for nothing?"]).to_vec(), tag);
+        assert_eq!(hmac(b"Jefe", &[b"what do ya", b" want ", b"for nothing?"]).to_vec(), tag);
+        assert!(tags_equal(&hmac(b"Jefe", &[b"what do ya want for nothing?"]), &tag));
+    }
+}
@@ -0,0 +1,81 @@
+//! Times from reset to each stage of startup, reported by the info and status commands.
+//!
+//! Milestones before the systick starts are timed with the DWT cycle counter, which runs
+//! at whichever core clock is active, so the clock is tracked as it changes. Later
+//! milestones, such as the link coming up, may be many seconds later and so are timed
+//! from the systick instead, as the cycle counter wraps after about 25s at full speed.
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+use stm32f407;
+
+/// Clock the core runs from out of reset.
+const HSI_HZ: u32 = 16_000_000;
+
+/// Time reported for a milestone which hasn't been reached yet.
+pub const NOT_REACHED: u32 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy)]
+pub enum Milestone {
+    /// Decided to stay in the bootloader rather than boot the user firmware.
+    EntryDecision = 0,
+    /// Core clock switched to the PLL.
+    ClocksUp = 1,
+    /// Network interface set up and the TCP socket about to start listening.
+    InterfaceReady = 2,
+    /// Ethernet link established.
+    LinkUp = 3,
+}
+
+/// Milliseconds from reset to each milestone, indexed by `Milestone`.
+static MILESTONES: [AtomicU32; 4] = [
+    AtomicU32::new(NOT_REACHED), AtomicU32::new(NOT_REACHED),
+    AtomicU32::new(NOT_REACHED), AtomicU32::new(NOT_REACHED),
+];
+
+/// Microseconds from reset up to the cycle count in LAST_CYCLES.
+static ELAPSED_US: AtomicU32 = AtomicU32::new(0);
+static LAST_CYCLES: AtomicU32 = AtomicU32::new(0);
+static CLOCK_HZ: AtomicU32 = AtomicU32::new(HSI_HZ);
+
+/// Start the cycle counter. Call as early as possible after reset; time before this
+/// is not counted.
+pub fn init(dcb: &mut stm32f407::DCB, dwt: &mut stm32f407::DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+    LAST_CYCLES.store(DWT::get_cycle_count(), Ordering::Relaxed);
+}
+
+/// Microseconds since reset, counting cycles at the current clock since the last update.
+fn update_elapsed() -> u32 {
+    let now = DWT::get_cycle_count();
+    let cycles = now.wrapping_sub(LAST_CYCLES.load(Ordering::Relaxed));
+    let elapsed = ELAPSED_US.load(Ordering::Relaxed)
+                  + cycles / (CLOCK_HZ.load(Ordering::Relaxed) / 1_000_000);
+    ELAPSED_US.store(elapsed, Ordering::Relaxed);
+    LAST_CYCLES.store(now, Ordering::Relaxed);
+    elapsed
+}
+
+/// Record that the core clock has just changed to `hz`.
+pub fn clock_changed(hz: u32) {
+    update_elapsed();
+    CLOCK_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Record reaching a milestone before the systick has started.
+pub fn mark(milestone: Milestone) {
+    MILESTONES[milestone as usize].store(update_elapsed() / 1000, Ordering::Relaxed);
+}
+
+/// Record reaching a milestone after the systick has started, which happens just
+/// after `Milestone::InterfaceReady`.
+pub fn mark_uptime(milestone: Milestone) {
+    let started = MILESTONES[Milestone::InterfaceReady as usize].load(Ordering::Relaxed);
+    MILESTONES[milestone as usize].store(started + ::uptime_ms(), Ordering::Relaxed);
+}
+
+/// Milliseconds from reset to `milestone`, or NOT_REACHED.
+pub fn ms(milestone: Milestone) -> u32 {
+    MILESTONES[milestone as usize].load(Ordering::Relaxed)
+}
+
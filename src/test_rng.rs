@@ -0,0 +1,29 @@
+//! Deterministic pseudo-random numbers for the randomised host tests, so that any
+//! failure can be reproduced by running the test again.
+
+/// Xorshift generator.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    /// A number below `n`, which must not be zero.
+    pub fn below(&mut self, n: u32) -> u32 {
+        self.next_u32() % n
+    }
+
+    /// Up to `max_len` random bytes.
+    pub fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.below(max_len as u32 + 1) as usize;
+        (0..len).map(|_| self.next_u32() as u8).collect()
+    }
+}
@@ -0,0 +1,247 @@
+//! Framing of the command protocol, separate from the smoltcp socket it runs over.
+//!
+//! All words in the protocol are little-endian u32s, and are only framed by these helpers.
+//! Command handlers use the `Socket` trait rather than `TcpSocket` directly, so that host
+//! tests can run them against a fake socket.
+
+// Decoding a header without receiving it is only needed by the emergency port
+#![cfg_attr(not(feature = "emergency-port"), allow(dead_code))]
+
+use smoltcp;
+use smoltcp::socket::TcpSocket;
+
+/// The parts of a TCP socket used by the command handlers.
+///
+/// The methods behave as the `TcpSocket` methods of the same names.
+pub trait Socket {
+    fn recv<R, F>(&mut self, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> (usize, R);
+    fn recv_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize>;
+    // Only needed to check the tags of authenticated commands
+    #[cfg_attr(not(feature = "auth"), allow(dead_code))]
+    fn peek(&mut self, size: usize) -> smoltcp::Result<&[u8]>;
+    fn peek_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize>;
+    fn send_slice(&mut self, data: &[u8]) -> smoltcp::Result<usize>;
+    fn recv_queue(&self) -> usize;
+    fn send_queue(&self) -> usize;
+    fn recv_capacity(&self) -> usize;
+    fn send_capacity(&self) -> usize;
+    fn may_send(&self) -> bool;
+}
+
+impl<'a> Socket for TcpSocket<'a> {
+    fn recv<R, F>(&mut self, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> (usize, R)
+    {
+        TcpSocket::recv(self, f)
+    }
+
+    fn recv_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        TcpSocket::recv_slice(self, data)
+    }
+
+    fn peek(&mut self, size: usize) -> smoltcp::Result<&[u8]> {
+        TcpSocket::peek(self, size)
+    }
+
+    fn peek_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        TcpSocket::peek_slice(self, data)
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> smoltcp::Result<usize> {
+        TcpSocket::send_slice(self, data)
+    }
+
+    fn recv_queue(&self) -> usize {
+        TcpSocket::recv_queue(self)
+    }
+
+    fn send_queue(&self) -> usize {
+        TcpSocket::send_queue(self)
+    }
+
+    fn recv_capacity(&self) -> usize {
+        TcpSocket::recv_capacity(self)
+    }
+
+    fn send_capacity(&self) -> usize {
+        TcpSocket::send_capacity(self)
+    }
+
+    fn may_send(&self) -> bool {
+        TcpSocket::may_send(self)
+    }
+}
+
+/// Read a word from the socket. Missing bytes read as zero.
+pub fn recv_u32<S: Socket>(socket: &mut S) -> u32 {
+    let mut word = [0u8; 4];
+    socket.recv_slice(&mut word[..]).ok();
+    u32::from_le_bytes(word)
+}
+
+/// Send a word on the socket
+pub fn send_u32<S: Socket>(socket: &mut S, word: u32) {
+    socket.send_slice(&word.to_le_bytes()).ok();
+}
+
+/// Read an address and length from the socket
+pub fn read_adr_len<S: Socket>(socket: &mut S) -> (u32, usize) {
+    let adr = recv_u32(socket);
+    let len = recv_u32(socket);
+    (adr, len as usize)
+}
+
+/// Peek the address and length following a received command word, without receiving them.
+#[cfg_attr(not(any(feature = "auth", feature = "trace")), allow(dead_code))]
+pub fn peek_adr_len<S: Socket>(socket: &mut S) -> (u32, usize) {
+    let mut hdr = [0u8; HEADER_LEN];
+    socket.peek_slice(&mut hdr[4..]).ok();
+    let hdr = decode_header(&hdr);
+    (hdr.adr, hdr.len as usize)
+}
+
+/// Length in bytes of a command header.
+pub const HEADER_LEN: usize = 12;
+
+/// The first three words of a command: its code, and the address and length
+/// for the commands which have them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub cmd: u32,
+    pub adr: u32,
+    pub len: u32,
+}
+
+/// Decode the header at the start of `bytes`.
+///
+/// Words missing from a short command decode as zero, as they do when received with
+/// `recv_u32`, and anything after the header is ignored.
+pub fn decode_header(bytes: &[u8]) -> Header {
+    let mut hdr = [0u8; HEADER_LEN];
+    let n = core::cmp::min(bytes.len(), HEADER_LEN);
+    hdr[..n].copy_from_slice(&bytes[..n]);
+    let word = |idx: usize| {
+        u32::from_le_bytes([hdr[idx], hdr[idx + 1], hdr[idx + 2], hdr[idx + 3]])
+    };
+    Header { cmd: word(0), adr: word(4), len: word(8) }
+}
+
+/// Decode the header of the command waiting in `socket`, without receiving it.
+pub fn peek_header<S: Socket>(socket: &mut S) -> Header {
+    let mut hdr = [0u8; HEADER_LEN];
+    let n = socket.peek_slice(&mut hdr[..]).unwrap_or(0);
+    decode_header(&hdr[..n])
+}
+
+/// A socket for host tests, which receives the bytes it was created with
+/// and collects the bytes sent.
+#[cfg(test)]
+pub struct FakeSocket {
+    pub rx: Vec<u8>,
+    pub tx: Vec<u8>,
+}
+
+#[cfg(test)]
+impl FakeSocket {
+    /// Same as the firmware's TCP buffers.
+    const CAPACITY: usize = 1536;
+
+    pub fn new(rx: &[u8]) -> FakeSocket {
+        FakeSocket { rx: rx.to_vec(), tx: Vec::new() }
+    }
+
+    /// The first word sent, which is the status of the command.
+    pub fn status(&self) -> Option<u32> {
+        if self.tx.len() < 4 {
+            return None;
+        }
+        Some(decode_header(&self.tx).cmd)
+    }
+}
+
+#[cfg(test)]
+impl Socket for FakeSocket {
+    fn recv<R, F>(&mut self, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> (usize, R)
+    {
+        let (n, result) = f(&mut self.rx[..]);
+        self.rx.drain(..n);
+        Ok(result)
+    }
+
+    fn recv_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        let n = self.peek_slice(data)?;
+        self.rx.drain(..n);
+        Ok(n)
+    }
+
+    fn peek(&mut self, size: usize) -> smoltcp::Result<&[u8]> {
+        let n = core::cmp::min(size, self.rx.len());
+        Ok(&self.rx[..n])
+    }
+
+    fn peek_slice(&mut self, data: &mut [u8]) -> smoltcp::Result<usize> {
+        let n = core::cmp::min(data.len(), self.rx.len());
+        data[..n].copy_from_slice(&self.rx[..n]);
+        Ok(n)
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> smoltcp::Result<usize> {
+        self.tx.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn recv_queue(&self) -> usize {
+        self.rx.len()
+    }
+
+    fn send_queue(&self) -> usize {
+        self.tx.len()
+    }
+
+    fn recv_capacity(&self) -> usize {
+        FakeSocket::CAPACITY
+    }
+
+    fn send_capacity(&self) -> usize {
+        FakeSocket::CAPACITY
+    }
+
+    fn may_send(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::test_rng::Rng;
+
+    #[test]
+    fn decode_header_words() {
+        let bytes = [3, 0, 0, 0, 0x00, 0x00, 0x01, 0x08, 0x00, 0x04, 0, 0, 0xAA];
+        assert_eq!(decode_header(&bytes), Header { cmd: 3, adr: 0x0801_0000, len: 1024 });
+    }
+
+    #[test]
+    fn decode_header_short() {
+        assert_eq!(decode_header(&[]), Header { cmd: 0, adr: 0, len: 0 });
+        assert_eq!(decode_header(&[1, 2]), Header { cmd: 0x0201, adr: 0, len: 0 });
+        assert_eq!(decode_header(&[1, 0, 0, 0, 5, 0, 0]), Header { cmd: 1, adr: 5, len: 0 });
+    }
+
+    /// Any bytes at all decode without panicking, to the words `recv_u32` would read.
+    #[test]
+    fn decode_header_fuzz() {
+        let mut rng = Rng::new(0x1182);
+        for _ in 0..10_000 {
+            let bytes = rng.bytes(24);
+            let hdr = decode_header(&bytes);
+            let mut socket = FakeSocket::new(&bytes);
+            assert_eq!(peek_header(&mut socket), hdr);
+            assert_eq!(recv_u32(&mut socket), hdr.cmd);
+            assert_eq!(read_adr_len(&mut socket), (hdr.adr, hdr.len as usize));
+        }
+    }
+}
@@ -0,0 +1,189 @@
+#!/usr/bin/env python3
+"""
+Tests of blethrs.py which don't need a device, run with:
+
+    python3 -m unittest test_blethrs
+"""
+import contextlib
+import io
+import os
+import re
+import socket
+import struct
+import threading
+import time
+import unittest
+
+import blethrs
+
+SECTORS = [(0x08000000, 500, 1000), (0x08004000, 500, 1000),
+           (0x08008000, 500, 1000), (0x0800C000, 500, 1000),
+           (0x08010000, 1100, 2200)] + \
+          [(0x08020000 + 0x20000 * idx, 2000, 4000) for idx in range(7)]
+
+LAYOUT = {"config": 0x0800C000, "user": 0x08010000, "end": 0x080FFFFF,
+          "sectors": SECTORS, "active": 0x08010000}
+
+USER_BYTES = LAYOUT['end'] + 1 - LAYOUT['user']
+
+
+class FakeTransport:
+    """Records each command sent, and replies to each with a success status."""
+    def __init__(self):
+        self.commands = []
+
+    def exchange(self, hostname, port, command, timeout):
+        self.commands.append(struct.unpack("<I", command[:4])[0])
+        return struct.pack("<I", 0)
+
+
+class TransportTestCase(unittest.TestCase):
+    def setUp(self):
+        self.saved_transport = blethrs.transport
+        self.transport = blethrs.transport = FakeTransport()
+
+    def tearDown(self):
+        blethrs.transport = self.saved_transport
+
+    def write_file(self, address, data):
+        blethrs.write_file("device", 7777, 1024, address, data, LAYOUT)
+
+
+class ImageFitsTests(TransportTestCase):
+    def test_exactly_fills_region(self):
+        blethrs.check_image_fits(LAYOUT, LAYOUT['user'], USER_BYTES)
+
+    def test_one_byte_too_large(self):
+        with self.assertRaisesRegex(blethrs.ImageError, "overflows by 1 byte"):
+            blethrs.check_image_fits(LAYOUT, LAYOUT['user'], USER_BYTES + 1)
+
+    def test_too_large_refused_before_erase(self):
+        with self.assertRaisesRegex(blethrs.ImageError, "too large"):
+            self.write_file(LAYOUT['user'], b"\x00" * (USER_BYTES + 1))
+        self.assertEqual(self.transport.commands, [])
+
+    def test_invalid_address_refused_before_erase(self):
+        for address in (LAYOUT['user'] + 2, LAYOUT['config'] - 4):
+            with self.assertRaisesRegex(blethrs.ImageError, "invalid address"):
+                self.write_file(address, b"\x00" * 16)
+        self.assertEqual(self.transport.commands, [])
+
+
+class EmptyImageTests(TransportTestCase):
+    def test_empty_file_refused_before_erase(self):
+        with self.assertRaisesRegex(blethrs.ImageError, "empty"):
+            self.write_file(LAYOUT['user'], b"")
+        self.assertEqual(self.transport.commands, [])
+
+    def test_empty_segments_refused_before_erase(self):
+        with self.assertRaisesRegex(blethrs.ImageError, "empty"):
+            blethrs.write_segments("device", 7777, 1024,
+                                   [(LAYOUT['user'], b"")], LAYOUT)
+        self.assertEqual(self.transport.commands, [])
+
+
+class EchoTransport(FakeTransport):
+    """Replies with a success status followed by the rest of the command."""
+    def __init__(self, status=0):
+        super().__init__()
+        self.status = status
+
+    def exchange(self, hostname, port, command, timeout):
+        super().exchange(hostname, port, command, timeout)
+        return struct.pack("<I", self.status) + command[4:]
+
+
+class CaptureReplayTests(TransportTestCase):
+    def setUp(self):
+        super().setUp()
+        self.transport = blethrs.transport = EchoTransport()
+        self.saved_capture = blethrs.capture
+
+    def tearDown(self):
+        blethrs.capture = self.saved_capture
+        super().tearDown()
+
+    def capture(self, commands, hash_payloads=False):
+        f = io.BytesIO()
+        blethrs.capture = blethrs.CaptureLog(f, hash_payloads)
+        for command in commands:
+            blethrs.interact("device", 7777, command)
+        blethrs.capture = None
+        return f.getvalue()
+
+    def replay(self, data):
+        with contextlib.redirect_stdout(io.StringIO()):
+            return blethrs.replay("device", 7777, io.BytesIO(data))
+
+    def test_capture_read_replay(self):
+        commands = [struct.pack("<I", 0),
+                    struct.pack("<III", 1, LAYOUT['user'], 16) + bytes(16)]
+        data = self.capture(commands)
+        records = list(blethrs.read_capture(io.BytesIO(data)))
+        self.assertEqual(len(records), 2)
+        for record, command in zip(records, commands):
+            _, outcome, hashed, port, tx, rx = record
+            self.assertEqual((outcome, hashed, port), (0, 0, 7777))
+            self.assertEqual(tx, command)
+            self.assertEqual(rx, struct.pack("<I", 0) + command[4:])
+
+        self.transport.commands = []
+        self.assertEqual(self.replay(data), 0)
+        self.assertEqual(self.transport.commands, [0, 1])
+
+        # A device answering differently is reported for every record
+        self.transport.status = 1
+        self.assertEqual(self.replay(data), 2)
+
+    def test_hashed_capture_not_replayed(self):
+        data = self.capture([struct.pack("<III", 3, LAYOUT['user'], 64) +
+                             bytes(64)], hash_payloads=True)
+        (_, _, hashed, _, tx, _), = blethrs.read_capture(io.BytesIO(data))
+        self.assertTrue(hashed)
+        self.assertEqual(len(tx), 12 + 32)
+        self.transport.commands = []
+        with self.assertRaisesRegex(ValueError, "hashed"):
+            self.replay(data)
+        self.assertEqual(self.transport.commands, [])
+
+
+class TcpTransportTests(unittest.TestCase):
+    def test_reads_until_closed(self):
+        """A response split over several segments is read in full."""
+        server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        server.bind(("127.0.0.1", 0))
+        server.listen(1)
+        response = struct.pack("<I", 0) + bytes(range(256)) * 12
+
+        def serve():
+            conn, _ = server.accept()
+            conn.recv(64)
+            for idx in range(0, len(response), 1000):
+                conn.sendall(response[idx:idx + 1000])
+                time.sleep(0.01)
+            conn.close()
+
+        thread = threading.Thread(target=serve)
+        thread.start()
+        try:
+            data = blethrs.TcpTransport().exchange(
+                "127.0.0.1", server.getsockname()[1], struct.pack("<I", 0), 2)
+        finally:
+            thread.join()
+            server.close()
+        self.assertEqual(data, response)
+
+
+class ErrorTableTests(unittest.TestCase):
+    def test_matches_firmware(self):
+        """Every status the firmware can send has an entry, and no others."""
+        main_rs = os.path.join(os.path.dirname(__file__), "src", "main.rs")
+        with open(main_rs) as f:
+            enum = re.search(r"pub enum Error \{(.*?)\}", f.read(), re.S).group(1)
+        values = sorted(int(value) for value in re.findall(r"= (\d+),", enum))
+        self.assertEqual(values, list(range(len(values))))
+        self.assertEqual(sorted(blethrs.errors), values)
+
+
+if __name__ == "__main__":
+    unittest.main()